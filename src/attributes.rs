@@ -0,0 +1,58 @@
+use std::fs;
+use std::path::Path;
+
+use crate::config::wildmatch;
+use crate::repository::git_path;
+
+/// What `.gitattributes` says about diffing the file at `path`: `Some(true)`
+/// if it's binary (`binary` or `-diff`), `Some(false)` if it's text (`diff`
+/// or `diff=<driver>`), and `None` if it doesn't say. Deeper directories'
+/// files take precedence, and `.git/info/attributes` over them all.
+pub fn binary_override(root: &str, path: &str) -> Option<bool> {
+    let worktree = Path::new(root);
+    let mut files = vec![(String::new(), worktree.join(".gitattributes"))];
+    for (i, _) in path.match_indices('/') {
+        files.push((
+            format!("{}/", &path[..i]),
+            worktree.join(&path[..i]).join(".gitattributes"),
+        ));
+    }
+    files.push((String::new(), git_path(root, "info/attributes")));
+
+    let mut binary = None;
+    for (dir, file) in files {
+        let content = match fs::read_to_string(file) {
+            Ok(content) => content,
+            Err(_) => continue,
+        };
+        let relative = &path[dir.len()..];
+        for line in content.lines().map(str::trim) {
+            let mut parts = line.split_whitespace();
+            let pattern = match parts.next() {
+                Some(pattern) if !pattern.starts_with('#') => pattern,
+                _ => continue,
+            };
+            // A pattern without a slash matches the name in any directory.
+            let matched = match pattern.trim_start_matches('/') {
+                p if pattern.contains('/') => wildmatch(p.as_bytes(), relative.as_bytes()),
+                p => {
+                    let name = relative.rsplit('/').next().unwrap_or(relative);
+                    wildmatch(p.as_bytes(), name.as_bytes())
+                }
+            };
+            if !matched {
+                continue;
+            }
+            for attribute in parts {
+                match attribute {
+                    "binary" | "-diff" => binary = Some(true),
+                    "diff" => binary = Some(false),
+                    "!diff" => binary = None,
+                    a if a.starts_with("diff=") => binary = Some(false),
+                    _ => {}
+                }
+            }
+        }
+    }
+    binary
+}
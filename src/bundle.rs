@@ -0,0 +1,84 @@
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use crate::git_error::{GitError, GitResult};
+use crate::refs;
+use crate::remote::Ref;
+
+const SIGNATURE: &str = "# v2 git bundle";
+
+/// The header of a bundle file: the refs it carries and the commits the
+/// receiving repository must already have for its pack to be complete.
+#[derive(Debug)]
+pub struct Bundle {
+    pub prerequisites: Vec<String>,
+    pub refs: Vec<Ref>,
+}
+
+/// Writes a bundle of `refs` around a pack holding everything they need
+/// beyond the `prerequisites`.
+pub fn write(path: &Path, prerequisites: &[String], refs: &[Ref], pack: &[u8]) -> GitResult<()> {
+    let mut content = format!("{}\n", SIGNATURE).into_bytes();
+    for sha in prerequisites {
+        content.extend_from_slice(format!("-{}\n", sha).as_bytes());
+    }
+    for r in refs {
+        content.extend_from_slice(format!("{} {}\n", r.sha, r.name).as_bytes());
+    }
+    content.push(b'\n');
+    content.extend_from_slice(pack);
+    fs::write(path, content)?;
+    Ok(())
+}
+
+/// Reads a bundle's header, returning it along with a reader positioned at
+/// the start of its pack.
+pub fn read(path: &Path) -> GitResult<(Bundle, BufReader<File>)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if line.trim_end() != SIGNATURE {
+        return Err(GitError(format!(
+            "{} is not a v2 git bundle",
+            path.display()
+        )));
+    }
+
+    let mut bundle = Bundle {
+        prerequisites: Vec::new(),
+        refs: Vec::new(),
+    };
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(GitError(format!("{} ends before its pack", path.display())));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok((bundle, reader));
+        }
+        // Prerequisites may carry a comment (the commit's subject) after the SHA.
+        if let Some(prerequisite) = line.strip_prefix('-') {
+            let sha = prerequisite.split(' ').next().unwrap_or("");
+            bundle.prerequisites.push(sha.to_owned());
+            continue;
+        }
+        let mut parts = line.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some(sha), Some(name)) if refs::check_ref_format(name).is_ok() => {
+                bundle.refs.push(Ref {
+                    sha: sha.to_owned(),
+                    name: name.to_owned(),
+                })
+            }
+            _ => {
+                return Err(GitError(format!(
+                    "Bad ref line in bundle {}: {}",
+                    path.display(),
+                    line
+                )))
+            }
+        }
+    }
+}
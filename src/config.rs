@@ -0,0 +1,650 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::git_error::{GitError, GitResult};
+use crate::repository::git_path;
+use crate::{lockfile, refs};
+
+/// A single `key = value` line, along with the section it appeared in.
+#[derive(Debug, Clone)]
+struct Entry {
+    section: String,
+    subsection: Option<String>,
+    key: String,
+    value: String,
+    /// The line as it was read, written back as it is unless the entry
+    /// changes. New entries have none.
+    raw: Option<String>,
+}
+
+/// A line of a config file.
+#[derive(Debug)]
+enum Line {
+    Entry(Entry),
+    Header {
+        section: String,
+        subsection: Option<String>,
+        raw: String,
+    },
+    /// A comment or blank line.
+    Other(String),
+}
+
+/// The contents of a git config file. Section and key names are matched
+/// case-insensitively, subsection names case-sensitively, as git does.
+/// Comments and formatting are kept when the file is written back, apart
+/// from the lines of the keys that changed.
+#[derive(Debug, Default)]
+pub struct Config {
+    lines: Vec<Line>,
+}
+
+impl Config {
+    /// Reads `.git/config`, treating a missing file as an empty config.
+    pub fn read(root: &str) -> GitResult<Config> {
+        match fs::read_to_string(git_path(root, "config")) {
+            Ok(content) => Config::parse(&content),
+            Err(_) => Ok(Config::default()),
+        }
+    }
+
+    /// Reads the config in effect for a repository: the system-wide file,
+    /// then the user's global ones, then `.git/config`, with later values
+    /// winning. Use `read` instead for a config that's going to be written
+    /// back.
+    pub fn load(root: &str) -> GitResult<Config> {
+        let mut files = Vec::new();
+        if env::var_os("GIT_CONFIG_NOSYSTEM").is_none() {
+            files.push(
+                env::var_os("GIT_CONFIG_SYSTEM")
+                    .map_or_else(|| PathBuf::from("/etc/gitconfig"), PathBuf::from),
+            );
+        }
+        match env::var_os("GIT_CONFIG_GLOBAL") {
+            Some(global) => files.push(PathBuf::from(global)),
+            None => {
+                let home = home_dir();
+                let xdg = env::var_os("XDG_CONFIG_HOME")
+                    .map(PathBuf::from)
+                    .or_else(|| home.as_ref().map(|home| home.join(".config")));
+                files.extend(xdg.map(|xdg| xdg.join("git/config")));
+                files.extend(home.map(|home| home.join(".gitconfig")));
+            }
+        }
+        files.push(git_path(root, "config"));
+
+        let mut entries = Vec::new();
+        for file in files {
+            read_file(&file, root, 0, &mut entries)?;
+        }
+        Ok(Config {
+            lines: entries.into_iter().map(Line::Entry).collect(),
+        })
+    }
+
+    pub fn parse(content: &str) -> GitResult<Config> {
+        let mut lines = Vec::new();
+        let mut section: Option<(String, Option<String>)> = None;
+
+        for (n, raw) in content.lines().enumerate() {
+            let line = raw.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                lines.push(Line::Other(raw.to_owned()));
+                continue;
+            }
+            if line.starts_with('[') {
+                let end = line
+                    .find(']')
+                    .ok_or_else(|| GitError(format!("Bad config section on line {}", n + 1)))?;
+                let (name, subsection) = parse_section_header(&line[1..end]);
+                lines.push(Line::Header {
+                    section: name.clone(),
+                    subsection: subsection.clone(),
+                    raw: raw.to_owned(),
+                });
+                section = Some((name, subsection));
+                continue;
+            }
+
+            let (name, subsection) = section.clone().ok_or_else(|| {
+                GitError(format!("Config key outside of a section on line {}", n + 1))
+            })?;
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim().to_lowercase();
+            let value = match parts.next() {
+                Some(value) => parse_value(value),
+                // A bare key is a boolean set to true.
+                None => "true".to_owned(),
+            };
+            lines.push(Line::Entry(Entry {
+                section: name,
+                subsection,
+                key,
+                value,
+                raw: Some(raw.to_owned()),
+            }));
+        }
+        Ok(Config { lines })
+    }
+
+    /// Writes the config to `.git/config`, through `config.lock` so that a
+    /// concurrent writer can't interleave and readers never see half a file.
+    pub fn write(&self, root: &str) -> GitResult<()> {
+        lockfile::write(
+            &git_path(root, "config"),
+            "config file",
+            self.to_string().as_bytes(),
+        )
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.lines.iter().filter_map(|line| match line {
+            Line::Entry(entry) => Some(entry),
+            _ => None,
+        })
+    }
+
+    /// Returns the last value set for the key, which is the one that wins.
+    pub fn get(&self, section: &str, subsection: Option<&str>, key: &str) -> Option<&str> {
+        self.get_all(section, subsection, key).pop()
+    }
+
+    /// Reads a path, where a leading `~/` stands for the home directory.
+    pub fn get_path(&self, section: &str, subsection: Option<&str>, key: &str) -> Option<PathBuf> {
+        let value = self.get(section, subsection, key)?;
+        match value.strip_prefix("~/") {
+            Some(rest) => home_dir().map(|home| home.join(rest)),
+            None => Some(PathBuf::from(value)),
+        }
+    }
+
+    /// Reads a boolean the way git does, accepting `yes`/`no`, `on`/`off`
+    /// and `1`/`0` as well as `true`/`false`.
+    pub fn get_bool(
+        &self,
+        section: &str,
+        subsection: Option<&str>,
+        key: &str,
+    ) -> GitResult<Option<bool>> {
+        match self.get(section, subsection, key) {
+            None => Ok(None),
+            Some(value) => match value.to_lowercase().as_str() {
+                "true" | "yes" | "on" | "1" => Ok(Some(true)),
+                "false" | "no" | "off" | "0" | "" => Ok(Some(false)),
+                _ => Err(GitError(format!(
+                    "Bad boolean config value '{}' for {}.{}",
+                    value, section, key
+                ))),
+            },
+        }
+    }
+
+    /// Returns every value of a multi-valued key, in file order.
+    pub fn get_all(&self, section: &str, subsection: Option<&str>, key: &str) -> Vec<&str> {
+        self.entries()
+            .filter(|e| e.matches(section, subsection, key))
+            .map(|e| e.value.as_str())
+            .collect()
+    }
+
+    /// Returns the keys set in a section, with their values, in file order.
+    pub fn section(&self, section: &str, subsection: Option<&str>) -> Vec<(&str, &str)> {
+        self.entries()
+            .filter(|e| {
+                e.section.eq_ignore_ascii_case(section) && e.subsection.as_deref() == subsection
+            })
+            .map(|e| (e.key.as_str(), e.value.as_str()))
+            .collect()
+    }
+
+    /// Sets the key to a single value, replacing any existing values. The
+    /// first of them is replaced where it is.
+    pub fn set(&mut self, section: &str, subsection: Option<&str>, key: &str, value: &str) {
+        let first = self
+            .lines
+            .iter()
+            .position(|line| matches!(line, Line::Entry(e) if e.matches(section, subsection, key)));
+        match first {
+            Some(first) => {
+                let mut seen = false;
+                self.lines.retain(|line| match line {
+                    Line::Entry(e) if e.matches(section, subsection, key) => {
+                        !std::mem::replace(&mut seen, true)
+                    }
+                    _ => true,
+                });
+                self.lines[first] = Line::Entry(new_entry(section, subsection, key, value));
+            }
+            None => self.add(section, subsection, key, value),
+        }
+    }
+
+    /// Adds another value for the key, keeping the existing ones.
+    pub fn add(&mut self, section: &str, subsection: Option<&str>, key: &str, value: &str) {
+        let entry = new_entry(section, subsection, key, value);
+        // Keep the new entry next to the rest of its section, or under its
+        // header if it has no keys yet.
+        let in_section = |line: &Line| match line {
+            Line::Entry(e) => e.section == entry.section && e.subsection == entry.subsection,
+            _ => false,
+        };
+        let is_header = |line: &Line| match line {
+            Line::Header {
+                section,
+                subsection: sub,
+                ..
+            } => *section == entry.section && *sub == entry.subsection,
+            _ => false,
+        };
+        match self
+            .lines
+            .iter()
+            .rposition(in_section)
+            .or_else(|| self.lines.iter().rposition(is_header))
+        {
+            Some(i) => self.lines.insert(i + 1, Line::Entry(entry)),
+            None => {
+                self.lines.push(Line::Header {
+                    raw: section_header(&entry.section, entry.subsection.as_deref()),
+                    section: entry.section.clone(),
+                    subsection: entry.subsection.clone(),
+                });
+                self.lines.push(Line::Entry(entry));
+            }
+        }
+    }
+}
+
+fn new_entry(section: &str, subsection: Option<&str>, key: &str, value: &str) -> Entry {
+    Entry {
+        section: section.to_lowercase(),
+        subsection: subsection.map(|s| s.to_owned()),
+        key: key.to_lowercase(),
+        value: value.to_owned(),
+        raw: None,
+    }
+}
+
+fn section_header(section: &str, subsection: Option<&str>) -> String {
+    match subsection {
+        Some(subsection) => format!(
+            "[{} \"{}\"]",
+            section,
+            subsection.replace('\\', "\\\\").replace('"', "\\\"")
+        ),
+        None => format!("[{}]", section),
+    }
+}
+
+/// Git gives up on includes nested deeper than this, which also stops
+/// include cycles.
+const MAX_INCLUDE_DEPTH: usize = 10;
+
+/// Appends the entries of a config file to `entries`, with those of the
+/// files it includes in place of the `path` keys including them. Like git,
+/// files that aren't there are skipped.
+fn read_file(file: &Path, root: &str, depth: usize, entries: &mut Vec<Entry>) -> GitResult<()> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(GitError(format!(
+            "Exceeded maximum include depth ({}) while including {}",
+            MAX_INCLUDE_DEPTH,
+            file.display()
+        )));
+    }
+    let content = match fs::read_to_string(file) {
+        Ok(content) => content,
+        Err(_) => return Ok(()),
+    };
+    let config =
+        Config::parse(&content).map_err(|e| GitError(format!("{} in {}", e.0, file.display())))?;
+    let dir = file.parent().unwrap_or_else(|| Path::new("."));
+    for entry in config.entries().cloned() {
+        let included = entry.key == "path"
+            && match (entry.section.as_str(), &entry.subsection) {
+                ("include", None) => true,
+                ("includeif", Some(condition)) => include_condition(condition, dir, root)?,
+                _ => false,
+            };
+        // Relative paths are relative to the including file.
+        let include = match entry.value.strip_prefix("~/") {
+            _ if !included => None,
+            Some(rest) => home_dir().map(|home| home.join(rest)),
+            None => Some(dir.join(&entry.value)),
+        };
+        entries.push(entry);
+        if let Some(include) = include {
+            read_file(&include, root, depth + 1, entries)?;
+        }
+    }
+    Ok(())
+}
+
+/// Evaluates the condition of an `includeIf` section: `gitdir:`, its
+/// case-insensitive `gitdir/i:`, or `onbranch:`. Conditions git doesn't know
+/// either are false.
+fn include_condition(condition: &str, dir: &Path, root: &str) -> GitResult<bool> {
+    if let Some(pattern) = condition.strip_prefix("onbranch:") {
+        let branch = refs::read_symref(root, "HEAD")?;
+        let branch = match branch
+            .as_deref()
+            .and_then(|b| b.strip_prefix("refs/heads/"))
+        {
+            Some(branch) => branch.to_owned(),
+            None => return Ok(false),
+        };
+        let mut pattern = pattern.to_owned();
+        if pattern.ends_with('/') {
+            pattern.push_str("**");
+        }
+        return Ok(wildmatch(pattern.as_bytes(), branch.as_bytes()));
+    }
+    let (pattern, ignore_case) = match (
+        condition.strip_prefix("gitdir:"),
+        condition.strip_prefix("gitdir/i:"),
+    ) {
+        (Some(pattern), _) => (pattern, false),
+        (_, Some(pattern)) => (pattern, true),
+        _ => return Ok(false),
+    };
+    let mut pattern = if let Some(rest) = pattern.strip_prefix("~/") {
+        match home_dir() {
+            Some(home) => home.join(rest).to_string_lossy().into_owned(),
+            None => return Ok(false),
+        }
+    } else if let Some(rest) = pattern.strip_prefix("./") {
+        dir.join(rest).to_string_lossy().into_owned()
+    } else if pattern.starts_with('/') {
+        pattern.to_owned()
+    } else {
+        format!("**/{}", pattern)
+    };
+    // A trailing slash matches everything inside the directory.
+    if pattern.ends_with('/') {
+        pattern.push_str("**");
+    }
+    let git_dir = match fs::canonicalize(Path::new(root).join(".git")) {
+        Ok(git_dir) => git_dir.to_string_lossy().into_owned(),
+        Err(_) => return Ok(false),
+    };
+    Ok(if ignore_case {
+        wildmatch(
+            pattern.to_lowercase().as_bytes(),
+            git_dir.to_lowercase().as_bytes(),
+        )
+    } else {
+        wildmatch(pattern.as_bytes(), git_dir.as_bytes())
+    })
+}
+
+/// Matches a path against a glob where `*` and `?` stay within one path
+/// component and `**` spans any number of them.
+pub fn wildmatch(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', b'/', rest @ ..] => {
+            // Zero or more whole directories.
+            wildmatch(rest, text)
+                || text
+                    .iter()
+                    .enumerate()
+                    .any(|(i, &c)| c == b'/' && wildmatch(rest, &text[i + 1..]))
+        }
+        [b'*', b'*', rest @ ..] => (0..=text.len()).any(|i| wildmatch(rest, &text[i..])),
+        [b'*', rest @ ..] => {
+            let end = text.iter().position(|&c| c == b'/').unwrap_or(text.len());
+            (0..=end).any(|i| wildmatch(rest, &text[i..]))
+        }
+        [b'?', rest @ ..] => matches!(text, [c, ..] if *c != b'/') && wildmatch(rest, &text[1..]),
+        [c, rest @ ..] => text.first() == Some(c) && wildmatch(rest, &text[1..]),
+    }
+}
+
+fn home_dir() -> Option<PathBuf> {
+    env::var_os("HOME").map(PathBuf::from)
+}
+
+/// Splits a dotted key such as `remote.origin.url` into its section,
+/// subsection and name. Subsections may themselves contain dots.
+pub fn split_key(key: &str) -> GitResult<(&str, Option<&str>, &str)> {
+    let first = key.find('.');
+    let last = key.rfind('.');
+    match (first, last) {
+        (Some(first), Some(last)) if first > 0 && last + 1 < key.len() => Ok((
+            &key[..first],
+            if first == last {
+                None
+            } else {
+                Some(&key[first + 1..last])
+            },
+            &key[last + 1..],
+        )),
+        _ => Err(GitError(format!("Key does not contain a section: {}", key))),
+    }
+}
+
+impl Entry {
+    fn matches(&self, section: &str, subsection: Option<&str>, key: &str) -> bool {
+        self.section.eq_ignore_ascii_case(section)
+            && self.subsection.as_deref() == subsection
+            && self.key.eq_ignore_ascii_case(key)
+    }
+}
+
+impl std::fmt::Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for line in &self.lines {
+            match line {
+                Line::Entry(Entry { raw: Some(raw), .. })
+                | Line::Header { raw, .. }
+                | Line::Other(raw) => writeln!(f, "{}", raw)?,
+                Line::Entry(e) => writeln!(f, "\t{} = {}", e.key, format_value(&e.value))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses `section`, `section "subsection"` or the legacy `section.subsection`.
+fn parse_section_header(header: &str) -> (String, Option<String>) {
+    let header = header.trim();
+    if let Some(quote) = header.find('"') {
+        let subsection = header[quote + 1..]
+            .trim_end_matches('"')
+            .replace("\\\"", "\"")
+            .replace("\\\\", "\\");
+        (header[..quote].trim().to_lowercase(), Some(subsection))
+    } else if let Some(dot) = header.find('.') {
+        (
+            header[..dot].to_lowercase(),
+            Some(header[dot + 1..].to_owned()),
+        )
+    } else {
+        (header.to_lowercase(), None)
+    }
+}
+
+/// Unquotes a value, handling escapes and dropping trailing comments.
+fn parse_value(raw: &str) -> String {
+    let mut value = String::new();
+    // Quoted or escaped characters survive the final whitespace trim.
+    let mut keep = 0;
+    let mut in_quotes = false;
+    let mut chars = raw.trim_start().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '#' | ';' if !in_quotes => break,
+            '\\' => {
+                match chars.next() {
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    Some(other) => value.push(other),
+                    None => {}
+                }
+                keep = value.len();
+            }
+            _ => {
+                value.push(c);
+                if in_quotes {
+                    keep = value.len();
+                }
+            }
+        }
+    }
+    let len = value.trim_end().len().max(keep);
+    value.truncate(len);
+    value
+}
+
+fn format_value(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+    if value.starts_with(' ') || value.ends_with(' ') || value.contains(&['#', ';'][..]) {
+        format!("\"{}\"", escaped)
+    } else {
+        escaped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    /// A fresh directory for a test to write files in.
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("config-test-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::canonicalize(dir).unwrap()
+    }
+
+    /// Reads `file` with its includes, as `load` reads each file.
+    fn read_with_includes(file: &Path, root: &Path) -> GitResult<Config> {
+        let mut entries = Vec::new();
+        read_file(file, &root.to_string_lossy(), 0, &mut entries)?;
+        Ok(Config {
+            lines: entries.into_iter().map(Line::Entry).collect(),
+        })
+    }
+
+    #[test]
+    fn values_are_parsed_as_git_does() {
+        let config = Config::parse(
+            "[Core]\n\tBare = false\n\tfilemode\n\
+[remote \"origin\"]\n\turl = \"a b \" # comment\n\tfetch = x\n\tfetch = y ; z\n\
+[branch.main]\n\tremote = origin  \n\
+[alias]\n\tlg = log \\\"-1\\\"\\tx\n",
+        )
+        .unwrap();
+        assert_eq!(config.get("core", None, "bare"), Some("false"));
+        assert_eq!(
+            config.get_bool("CORE", None, "FileMode").unwrap(),
+            Some(true)
+        );
+        assert_eq!(config.get("remote", Some("origin"), "url"), Some("a b "));
+        assert_eq!(
+            config.get_all("remote", Some("origin"), "fetch"),
+            ["x", "y"]
+        );
+        assert_eq!(config.get("remote", Some("origin"), "fetch"), Some("y"));
+        // Subsections are case-sensitive, unlike section and key names.
+        assert_eq!(config.get("remote", Some("Origin"), "url"), None);
+        assert_eq!(config.get("branch", Some("main"), "remote"), Some("origin"));
+        assert_eq!(config.get("alias", None, "lg"), Some("log \"-1\"\tx"));
+        assert!(Config::parse("[core]\n\tbare = maybe\n")
+            .unwrap()
+            .get_bool("core", None, "bare")
+            .is_err());
+
+        assert!(Config::parse("[core\n").is_err());
+        assert!(Config::parse("bare = true\n").is_err());
+    }
+
+    #[test]
+    fn includes_are_read_in_place() {
+        let dir = scratch_dir("includes");
+        fs::create_dir(dir.join(".git")).unwrap();
+        fs::write(
+            dir.join("config"),
+            format!(
+                "[user]\n\tname = Main\n\temail = m@x\n\
+[include]\n\tpath = other\n\tpath = missing\n\
+[includeIf \"gitdir:{}/\"]\n\tpath = here\n\
+[includeIf \"gitdir:/elsewhere/\"]\n\tpath = elsewhere\n\
+[user]\n\temail = last@x\n",
+                dir.display()
+            ),
+        )
+        .unwrap();
+        fs::write(dir.join("other"), "[user]\n\tname = Other\n").unwrap();
+        fs::write(dir.join("here"), "[core]\n\tbare = true\n").unwrap();
+        fs::write(dir.join("elsewhere"), "[core]\n\tbare = false\n").unwrap();
+
+        let config = read_with_includes(&dir.join("config"), &dir).unwrap();
+        assert_eq!(config.get("user", None, "name"), Some("Other"));
+        assert_eq!(config.get("user", None, "email"), Some("last@x"));
+        assert_eq!(config.get("core", None, "bare"), Some("true"));
+
+        // A file that includes itself gives up rather than recursing forever.
+        fs::write(dir.join("loop"), "[include]\n\tpath = loop\n").unwrap();
+        assert!(read_with_includes(&dir.join("loop"), &dir).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn wildmatch_keeps_single_stars_within_a_component() {
+        for (pattern, text) in [
+            ("*.c", "a.c"),
+            ("a?c", "abc"),
+            ("**/a.c", "a.c"),
+            ("**/a.c", "d/e/a.c"),
+            ("a/**", "a/b/c"),
+            ("a/**/b", "a/b"),
+            ("a/**/b", "a/x/y/b"),
+            ("/repo/**", "/repo/.git"),
+        ] {
+            assert!(
+                wildmatch(pattern.as_bytes(), text.as_bytes()),
+                "{} {}",
+                pattern,
+                text
+            );
+        }
+        for (pattern, text) in [
+            ("*.c", "d/a.c"),
+            ("a?c", "a/c"),
+            ("a/**/b", "a/bc"),
+            ("foo", "foobar"),
+            ("foo*", "fo"),
+        ] {
+            assert!(
+                !wildmatch(pattern.as_bytes(), text.as_bytes()),
+                "{} {}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn writing_keeps_unrelated_lines() {
+        let content = "# a comment\n[core]\n  bare=false ; why\n\n[remote \"origin\"]\n\turl = a\n\tfetch = x\n\tfetch = y\n";
+        let mut config = Config::parse(content).unwrap();
+        assert_eq!(config.to_string(), content);
+
+        config.set("remote", Some("origin"), "fetch", "z");
+        config.set("core", None, "filemode", "true");
+        config.add("branch", Some("main"), "remote", "origin");
+        assert_eq!(
+            config.to_string(),
+            "# a comment\n[core]\n  bare=false ; why\n\tfilemode = true\n\n\
+[remote \"origin\"]\n\turl = a\n\tfetch = z\n\
+[branch \"main\"]\n\tremote = origin\n"
+        );
+    }
+}
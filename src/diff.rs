@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+
+/// One step of turning `a` into `b`, by index into each.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edit {
+    Equal(usize, usize),
+    Delete(usize),
+    Insert(usize),
+}
+
+/// Finds a shortest edit script from `a` to `b` with Myers' algorithm,
+/// listing every element of both in order.
+pub fn diff<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Edit> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let max = (n + m) as usize;
+    let offset = max as isize + 1;
+    // v[k + offset] is the furthest x reached on diagonal k = x - y.
+    let mut v = vec![0isize; 2 * max + 3];
+    let mut trace = Vec::new();
+    'search: for d in 0..=max as isize {
+        trace.push(v.clone());
+        for k in (-d..=d).step_by(2) {
+            let i = (k + offset) as usize;
+            let mut x = if k == -d || (k != d && v[i - 1] < v[i + 1]) {
+                v[i + 1]
+            } else {
+                v[i - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[i] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    // Walk back through the saved rounds to recover the path.
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let i = (k + offset) as usize;
+        let prev_k = if k == -d || (k != d && v[i - 1] < v[i + 1]) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = v[(prev_k + offset) as usize];
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            edits.push(Edit::Equal(x as usize, y as usize));
+        }
+        if d > 0 {
+            if x == prev_x {
+                y -= 1;
+                edits.push(Edit::Insert(y as usize));
+            } else {
+                x -= 1;
+                edits.push(Edit::Delete(x as usize));
+            }
+        }
+    }
+    edits.reverse();
+    edits
+}
+
+/// How alike two contents are, as the percentage of the larger one's bytes
+/// that are also in the other. Like git's rename detection, this compares
+/// lines, with long lines cut into 64-byte pieces, wherever they are.
+pub fn similarity(a: &[u8], b: &[u8]) -> usize {
+    let max = a.len().max(b.len());
+    if max == 0 {
+        return 100;
+    }
+    let mut unmatched: HashMap<&[u8], usize> = HashMap::new();
+    for chunk in chunks(a) {
+        *unmatched.entry(chunk).or_default() += chunk.len();
+    }
+    let mut common = 0;
+    for chunk in chunks(b) {
+        if let Some(left) = unmatched.get_mut(chunk) {
+            let n = chunk.len().min(*left);
+            common += n;
+            *left -= n;
+        }
+    }
+    common * 100 / max
+}
+
+fn chunks(content: &[u8]) -> impl Iterator<Item = &[u8]> {
+    content
+        .split_inclusive(|&b| b == b'\n')
+        .flat_map(|line| line.chunks(64))
+}
@@ -1,4 +1,9 @@
 use std::fmt::{Debug, Formatter};
+use std::io;
+use std::num::ParseIntError;
+use std::str::Utf8Error;
+use std::string::FromUtf8Error;
+use std::time::SystemTimeError;
 
 pub struct GitError(pub String);
 
@@ -8,9 +13,51 @@ impl Debug for GitError {
     }
 }
 
-impl<A: ToString> From<A> for GitError {
-    fn from(a: A) -> Self {
-        GitError(a.to_string())
+impl From<String> for GitError {
+    fn from(message: String) -> Self {
+        GitError(message)
+    }
+}
+
+impl From<&str> for GitError {
+    fn from(message: &str) -> Self {
+        GitError(message.to_owned())
+    }
+}
+
+impl From<io::Error> for GitError {
+    fn from(e: io::Error) -> Self {
+        GitError(format!("IO error: {}", e))
+    }
+}
+
+impl From<reqwest::Error> for GitError {
+    fn from(e: reqwest::Error) -> Self {
+        GitError(format!("HTTP error: {}", e))
+    }
+}
+
+impl From<Utf8Error> for GitError {
+    fn from(e: Utf8Error) -> Self {
+        GitError(format!("Invalid UTF-8: {}", e))
+    }
+}
+
+impl From<FromUtf8Error> for GitError {
+    fn from(e: FromUtf8Error) -> Self {
+        GitError(format!("Invalid UTF-8: {}", e))
+    }
+}
+
+impl From<ParseIntError> for GitError {
+    fn from(e: ParseIntError) -> Self {
+        GitError(format!("Invalid number: {}", e))
+    }
+}
+
+impl From<SystemTimeError> for GitError {
+    fn from(e: SystemTimeError) -> Self {
+        GitError(format!("Clock error: {}", e))
     }
 }
 
@@ -0,0 +1,165 @@
+use regex::bytes::{Regex, RegexBuilder};
+
+use crate::git_error::{GitError, GitResult};
+
+/// A pattern to search lines for: either a fixed string, or a POSIX basic
+/// regular expression, as `git grep` takes by default.
+pub struct Pattern(Regex);
+
+impl Pattern {
+    pub fn fixed(pattern: &str) -> GitResult<Pattern> {
+        compile(&regex::escape(pattern))
+    }
+
+    pub fn regex(pattern: &str) -> GitResult<Pattern> {
+        compile(&translate_basic(pattern)?)
+    }
+
+    pub fn is_match(&self, line: &[u8]) -> bool {
+        self.0.is_match(line)
+    }
+}
+
+/// Compiles a pattern in the `regex` crate's syntax to match bytes, so that
+/// `.` matches any byte rather than only whole UTF-8 characters.
+fn compile(pattern: &str) -> GitResult<Pattern> {
+    RegexBuilder::new(pattern)
+        .unicode(false)
+        .build()
+        .map(Pattern)
+        .map_err(|e| GitError(format!("Invalid pattern: {}", e)))
+}
+
+/// Rewrites a basic regular expression in the `regex` crate's syntax. In a
+/// BRE, `\(`, `\)`, `\{`, `\}` and GNU's `\|`, `\+` and `\?` are operators
+/// while the bare characters are literal, a `*` with nothing before it is
+/// literal, and `^` and `$` only anchor at either end of the pattern or of a
+/// group.
+fn translate_basic(pattern: &str) -> GitResult<String> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut res = String::new();
+    // Whether the next character starts the pattern, a group or a branch.
+    let mut at_start = true;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        let start = at_start;
+        at_start = false;
+        match c {
+            '\\' => {
+                let next = *chars
+                    .get(i)
+                    .ok_or_else(|| GitError("Trailing backslash in pattern".to_owned()))?;
+                i += 1;
+                match next {
+                    '(' | '|' => {
+                        res.push(next);
+                        at_start = true;
+                    }
+                    ')' | '{' | '}' | '+' | '?' => res.push(next),
+                    '<' | '>' => res.push_str(r"\b"),
+                    'w' | 'W' | 's' | 'S' | 'b' | 'B' => {
+                        res.push('\\');
+                        res.push(next);
+                    }
+                    '1'..='9' => {
+                        return Err(GitError(
+                            "Back-references are not supported in patterns".to_owned(),
+                        ))
+                    }
+                    _ => res.push_str(&regex::escape(&next.to_string())),
+                }
+            }
+            '^' if start => {
+                res.push('^');
+                at_start = true;
+            }
+            '$' if i == chars.len()
+                || chars[i..].starts_with(&['\\', ')'])
+                || chars[i..].starts_with(&['\\', '|']) =>
+            {
+                res.push('$')
+            }
+            '*' if start => res.push_str(r"\*"),
+            '*' => res.push('*'),
+            '.' => res.push('.'),
+            '[' => i = translate_class(&chars, i, &mut res)?,
+            _ => res.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    Ok(res)
+}
+
+/// Rewrites a bracket expression starting just after its `[`, returning the
+/// index just past its closing `]`. Backslashes are literal inside it, and a
+/// `]` right at the start is part of it rather than closing it.
+fn translate_class(chars: &[char], mut i: usize, res: &mut String) -> GitResult<usize> {
+    res.push('[');
+    if chars.get(i) == Some(&'^') {
+        res.push('^');
+        i += 1;
+    }
+    let start = i;
+    loop {
+        match chars.get(i) {
+            Some(']') if i > start => {
+                res.push(']');
+                return Ok(i + 1);
+            }
+            // A named class such as `[:alpha:]` is copied whole.
+            Some('[') if chars.get(i + 1) == Some(&':') => {
+                let end = (i + 2..chars.len().saturating_sub(1))
+                    .find(|&j| chars[j] == ':' && chars[j + 1] == ']')
+                    .ok_or_else(|| GitError("Unmatched [: in pattern".to_owned()))?;
+                res.extend(&chars[i..end + 2]);
+                i = end + 2;
+            }
+            Some('-') => {
+                res.push('-');
+                i += 1;
+            }
+            Some(&c) => {
+                res.push_str(&regex::escape(&c.to_string()));
+                i += 1;
+            }
+            None => return Err(GitError("Unmatched [ in pattern".to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, line: &str) -> bool {
+        Pattern::regex(pattern).unwrap().is_match(line.as_bytes())
+    }
+
+    #[test]
+    fn basic_regexes_match_as_grep_does() {
+        assert!(matches(r"\(ab\)\{2\}", "xababx"));
+        assert!(!matches(r"\(ab\)\{2\}", "xabx"));
+        assert!(matches("(a+b){1}", "x(a+b){1}"));
+        assert!(matches(r"foo\|bar", "a bar"));
+        assert!(matches(r"x$\|^y", "ax"));
+        assert!(matches("*a", "*a"));
+        assert!(matches("^a.c$", "abc"));
+        assert!(!matches("^a.c$", "xabc"));
+        assert!(matches("a^b$c", "a^b$c"));
+        assert!(matches(r"[]a]x", "]x"));
+        assert!(matches(r"[\]", r"\"));
+        assert!(matches("[[:digit:]]-[^a-c]", "7-d"));
+        assert!(matches(r"\.", "."));
+        assert!(!matches(r"\.", "a"));
+        assert!(Pattern::regex(r"\(a").is_err());
+        assert!(Pattern::regex("[a").is_err());
+    }
+
+    #[test]
+    fn fixed_strings_match_literally() {
+        let pattern = Pattern::fixed("a.*(").unwrap();
+        assert!(pattern.is_match(b"xa.*(y"));
+        assert!(!pattern.is_match(b"abc("));
+    }
+}
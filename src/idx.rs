@@ -0,0 +1,212 @@
+use std::fs;
+use std::path::Path;
+
+use flate2::Crc;
+
+use crate::git_error::{GitError, GitResult};
+use crate::object::{self, Sha};
+use crate::pack::PackStats;
+
+/// A pack index (`.idx`), mapping the SHAs of the objects in a pack to their
+/// offsets in it.
+#[derive(Debug)]
+pub struct PackIndex {
+    /// Entries sorted by SHA.
+    pub entries: Vec<IndexEntry>,
+    /// The checksum of the pack this index belongs to.
+    pub pack_checksum: Sha,
+}
+
+/// Offsets with this bit set point into a version 2 index's table of 64-bit
+/// offsets, which holds those past 2GB.
+const LARGE_OFFSET: u64 = 0x8000_0000;
+
+#[derive(Debug)]
+pub struct IndexEntry {
+    pub sha: Sha,
+    pub offset: u64,
+    /// The CRC of the entry's packed data. Version 1 indexes don't have it.
+    pub crc32: Option<u32>,
+}
+
+impl PackIndex {
+    /// Reads a version 2 index, or an old version 1 one, which is told apart
+    /// by lacking the version 2 magic number.
+    pub fn read(path: &Path) -> GitResult<PackIndex> {
+        let data = fs::read(path)?;
+        if data.len() < 256 * 4 + 40 {
+            return Err(GitError(format!(
+                "Truncated pack index: {}",
+                path.display()
+            )));
+        }
+        let trailer = data.len() - 40;
+        if object::get_sha(&data[..trailer + 20])[..] != data[trailer + 20..] {
+            return Err(GitError(format!(
+                "Pack index checksum mismatch: {}",
+                path.display()
+            )));
+        }
+
+        let entries = match &data[..4] {
+            b"\xfftOc" if read_u32(&data, 4) == 2 => read_v2_entries(&data, path)?,
+            b"\xfftOc" => {
+                return Err(GitError(format!(
+                    "Unsupported pack index version {}: {}",
+                    read_u32(&data, 4),
+                    path.display()
+                )))
+            }
+            _ => read_v1_entries(&data, path)?,
+        };
+        let mut pack_checksum = [0u8; 20];
+        pack_checksum.copy_from_slice(&data[trailer..trailer + 20]);
+        Ok(PackIndex {
+            entries,
+            pack_checksum,
+        })
+    }
+
+    /// Builds the index for a pack that has just been parsed, reading the pack
+    /// file again for the CRC of each entry.
+    pub fn for_pack(pack: &Path, stats: &PackStats) -> GitResult<PackIndex> {
+        let data = fs::read(pack)?;
+        let mut entries = Vec::with_capacity(stats.entries.len());
+        for entry in &stats.entries {
+            let mut crc = Crc::new();
+            crc.update(&data[entry.offset..entry.offset + entry.packed_size]);
+            entries.push(IndexEntry {
+                sha: entry.sha,
+                offset: entry.offset as u64,
+                crc32: Some(crc.sum()),
+            });
+        }
+        entries.sort_by_key(|e| e.sha);
+        Ok(PackIndex {
+            entries,
+            pack_checksum: stats.checksum,
+        })
+    }
+
+    /// Writes the index in the v2 format.
+    pub fn write(&self, path: &Path) -> GitResult<()> {
+        let mut data = b"\xfftOc\0\0\0\x02".to_vec();
+        // Entry i of the fan-out counts the SHAs whose first byte is <= i.
+        let mut fanout = [0u32; 256];
+        for entry in &self.entries {
+            fanout[entry.sha[0] as usize] += 1;
+        }
+        let mut total = 0;
+        for count in fanout.iter() {
+            total += count;
+            data.extend_from_slice(&total.to_be_bytes());
+        }
+        for entry in &self.entries {
+            data.extend_from_slice(&entry.sha);
+        }
+        for entry in &self.entries {
+            let crc32 = entry
+                .crc32
+                .ok_or_else(|| GitError(format!("Missing CRC for {}", path.display())))?;
+            data.extend_from_slice(&crc32.to_be_bytes());
+        }
+        // Offsets that don't fit in 31 bits go in a table of 64-bit ones, which
+        // the 32-bit entry points into.
+        let mut large_offsets = Vec::new();
+        for entry in &self.entries {
+            let offset = if entry.offset < LARGE_OFFSET {
+                entry.offset as u32
+            } else {
+                large_offsets.push(entry.offset);
+                LARGE_OFFSET as u32 | (large_offsets.len() - 1) as u32
+            };
+            data.extend_from_slice(&offset.to_be_bytes());
+        }
+        for offset in large_offsets {
+            data.extend_from_slice(&offset.to_be_bytes());
+        }
+        data.extend_from_slice(&self.pack_checksum);
+        let checksum = object::get_sha(&data);
+        data.extend_from_slice(&checksum);
+        fs::write(path, data)?;
+        Ok(())
+    }
+
+    pub fn find(&self, sha: &Sha) -> Option<&IndexEntry> {
+        self.entries
+            .binary_search_by(|e| e.sha.cmp(sha))
+            .ok()
+            .map(|i| &self.entries[i])
+    }
+}
+
+/// Reads the entries of a version 2 index: after the magic number, version
+/// and fan-out table come tables of the SHAs, CRCs and offsets.
+fn read_v2_entries(data: &[u8], path: &Path) -> GitResult<Vec<IndexEntry>> {
+    let count = read_u32(data, 8 + 255 * 4) as usize;
+    let shas = 8 + 256 * 4;
+    let crcs = shas + count * 20;
+    let offsets = crcs + count * 4;
+    let large_offsets = offsets + count * 4;
+    if large_offsets > data.len() - 40 {
+        return Err(GitError(format!(
+            "Truncated pack index: {}",
+            path.display()
+        )));
+    }
+
+    let mut entries = Vec::with_capacity(count);
+    for i in 0..count {
+        let mut sha = [0u8; 20];
+        sha.copy_from_slice(&data[shas + i * 20..shas + (i + 1) * 20]);
+        let mut offset = read_u32(data, offsets + i * 4) as u64;
+        if offset & LARGE_OFFSET != 0 {
+            let at = large_offsets + (offset & !LARGE_OFFSET) as usize * 8;
+            if at + 8 > data.len() - 40 {
+                return Err(GitError(format!(
+                    "Truncated pack index: {}",
+                    path.display()
+                )));
+            }
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(&data[at..at + 8]);
+            offset = u64::from_be_bytes(bytes);
+        }
+        entries.push(IndexEntry {
+            sha,
+            offset,
+            crc32: Some(read_u32(data, crcs + i * 4)),
+        });
+    }
+    Ok(entries)
+}
+
+/// Reads the entries of a version 1 index: after the fan-out table, each
+/// object's offset followed by its SHA.
+fn read_v1_entries(data: &[u8], path: &Path) -> GitResult<Vec<IndexEntry>> {
+    let count = read_u32(data, 255 * 4) as usize;
+    let start = 256 * 4;
+    if start + count * 24 > data.len() - 40 {
+        return Err(GitError(format!(
+            "Truncated pack index: {}",
+            path.display()
+        )));
+    }
+
+    let mut entries = Vec::with_capacity(count);
+    for i in 0..count {
+        let at = start + i * 24;
+        let mut sha = [0u8; 20];
+        sha.copy_from_slice(&data[at + 4..at + 24]);
+        entries.push(IndexEntry {
+            sha,
+            offset: read_u32(data, at) as u64,
+            crc32: None,
+        });
+    }
+    Ok(entries)
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
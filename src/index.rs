@@ -0,0 +1,483 @@
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use crate::git_error::{GitError, GitResult};
+use crate::lockfile;
+use crate::object::{self, Sha};
+
+/// The staging area, `.git/index`. Versions 2 to 4 are read, and versions 2
+/// and 3 written.
+#[derive(Debug, Default)]
+pub struct Index {
+    /// Entries sorted by path, then stage.
+    pub entries: Vec<IndexEntry>,
+    /// The `TREE` extension: the SHAs of the trees the entries make up, as
+    /// they were last written.
+    pub cache_tree: Option<CacheTree>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IndexEntry {
+    pub ctime: (u32, u32),
+    pub mtime: (u32, u32),
+    pub dev: u32,
+    pub ino: u32,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u32,
+    pub sha: Sha,
+    /// The flag bits other than the name length.
+    pub flags: u16,
+    /// The second flag word of version 3, for the flags that didn't fit.
+    pub extended_flags: u16,
+    pub path: String,
+}
+
+/// A node of the cache tree, covering a directory.
+#[derive(Debug, Default)]
+pub struct CacheTree {
+    /// The number of index entries under the directory and the SHA of its
+    /// tree, or `None` once something under it has changed.
+    pub tree: Option<(usize, Sha)>,
+    pub children: Vec<(String, CacheTree)>,
+}
+
+const ASSUME_VALID: u16 = 0x8000;
+const EXTENDED: u16 = 0x4000;
+const STAGE_MASK: u16 = 0x3000;
+const SKIP_WORKTREE: u16 = 0x4000;
+
+impl Index {
+    /// Reads the index, treating a missing file as an empty index.
+    pub fn read(path: &Path) -> GitResult<Index> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(_) => return Ok(Index::default()),
+        };
+        if data.len() < 12 + 20 || &data[..4] != b"DIRC" {
+            return Err(GitError(format!("Invalid index: {}", path.display())));
+        }
+        let trailer = data.len() - 20;
+        if object::get_sha(&data[..trailer])[..] != data[trailer..] {
+            return Err(GitError(format!(
+                "Index checksum mismatch: {}",
+                path.display()
+            )));
+        }
+        let version = read_u32(&data, 4);
+        if !(2..=4).contains(&version) {
+            return Err(GitError(format!(
+                "Unsupported index version {}: {}",
+                version,
+                path.display()
+            )));
+        }
+
+        let truncated = || GitError(format!("Truncated index: {}", path.display()));
+        let count = read_u32(&data, 8) as usize;
+        let mut entries: Vec<IndexEntry> = Vec::with_capacity(count);
+        let mut at = 12;
+        for _ in 0..count {
+            if at + 62 > trailer {
+                return Err(truncated());
+            }
+            let flags = u16::from_be_bytes([data[at + 60], data[at + 61]]);
+            let mut name_start = at + 62;
+            let mut extended_flags = 0;
+            if flags & EXTENDED != 0 {
+                if version < 3 || name_start + 2 > trailer {
+                    return Err(GitError(format!(
+                        "Invalid extended index flags: {}",
+                        path.display()
+                    )));
+                }
+                extended_flags = u16::from_be_bytes([data[at + 62], data[at + 63]]);
+                name_start += 2;
+            }
+            // Version 4 gives how much of the previous path to drop from its
+            // end before appending the rest of this one.
+            let mut prefix = Vec::new();
+            if version == 4 {
+                let (strip, len) = read_varint(&data[name_start..trailer]).ok_or_else(truncated)?;
+                let previous = entries.last().map_or("", |e| e.path.as_str());
+                let keep = previous.len().checked_sub(strip).ok_or_else(|| {
+                    GitError(format!(
+                        "Invalid index path compression: {}",
+                        path.display()
+                    ))
+                })?;
+                prefix.extend_from_slice(&previous.as_bytes()[..keep]);
+                name_start += len;
+            }
+            let name_end = data[name_start..trailer]
+                .iter()
+                .position(|&b| b == 0)
+                .map(|len| name_start + len)
+                .ok_or_else(truncated)?;
+            prefix.extend_from_slice(&data[name_start..name_end]);
+            let mut sha = [0u8; 20];
+            sha.copy_from_slice(&data[at + 40..at + 60]);
+            entries.push(IndexEntry {
+                ctime: (read_u32(&data, at), read_u32(&data, at + 4)),
+                mtime: (read_u32(&data, at + 8), read_u32(&data, at + 12)),
+                dev: read_u32(&data, at + 16),
+                ino: read_u32(&data, at + 20),
+                mode: read_u32(&data, at + 24),
+                uid: read_u32(&data, at + 28),
+                gid: read_u32(&data, at + 32),
+                size: read_u32(&data, at + 36),
+                sha,
+                flags: flags & !(EXTENDED | 0x0fff),
+                extended_flags,
+                path: String::from_utf8(prefix)?,
+            });
+            // Entries are padded with 1-8 NULs to a multiple of 8 bytes,
+            // except in version 4, where just the one ends the path.
+            at = match version {
+                4 => name_end + 1,
+                _ => at + ((name_end - at + 8) & !7),
+            };
+        }
+
+        let mut cache_tree = None;
+        while at < trailer {
+            if at + 8 > trailer {
+                return Err(truncated());
+            }
+            let signature = &data[at..at + 4];
+            let size = read_u32(&data, at + 4) as usize;
+            let body = data.get(at + 8..at + 8 + size).ok_or_else(truncated)?;
+            match signature {
+                b"TREE" => cache_tree = Some(CacheTree::parse(&mut &body[..])?),
+                // Extensions starting with an uppercase letter are optional.
+                _ if signature[0].is_ascii_uppercase() => {}
+                _ => {
+                    return Err(GitError(format!(
+                        "Unsupported index extension {}: {}",
+                        String::from_utf8_lossy(signature),
+                        path.display()
+                    )))
+                }
+            }
+            at += 8 + size;
+        }
+        Ok(Index {
+            entries,
+            cache_tree,
+        })
+    }
+
+    /// Writes the index in the version 2 format, or version 3 if any entry
+    /// has extended flags.
+    pub fn write(&self, path: &Path) -> GitResult<()> {
+        let extended = self.entries.iter().any(|e| e.extended_flags != 0);
+        let mut data = b"DIRC".to_vec();
+        data.extend_from_slice(&(if extended { 3u32 } else { 2 }).to_be_bytes());
+        data.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        for entry in &self.entries {
+            let start = data.len();
+            for field in &[
+                entry.ctime.0,
+                entry.ctime.1,
+                entry.mtime.0,
+                entry.mtime.1,
+                entry.dev,
+                entry.ino,
+                entry.mode,
+                entry.uid,
+                entry.gid,
+                entry.size,
+            ] {
+                data.extend_from_slice(&field.to_be_bytes());
+            }
+            data.extend_from_slice(&entry.sha);
+            let mut flags = entry.flags | entry.path.len().min(0x0fff) as u16;
+            if entry.extended_flags != 0 {
+                flags |= EXTENDED;
+            }
+            data.extend_from_slice(&flags.to_be_bytes());
+            if entry.extended_flags != 0 {
+                data.extend_from_slice(&entry.extended_flags.to_be_bytes());
+            }
+            data.extend_from_slice(entry.path.as_bytes());
+            let len = (data.len() - start + 8) & !7;
+            data.resize(start + len, 0);
+        }
+        if let Some(cache_tree) = &self.cache_tree {
+            let mut body = Vec::new();
+            cache_tree.encode("", &mut body);
+            data.extend_from_slice(b"TREE");
+            data.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            data.extend_from_slice(&body);
+        }
+        let checksum = object::get_sha(&data);
+        data.extend_from_slice(&checksum);
+        // Through `index.lock`, so that two processes can't both write it
+        // and a crash never leaves half an index behind.
+        lockfile::write(path, "index", &data)
+    }
+
+    /// Stages an entry, replacing whatever was at its path, along with any
+    /// entries it displaces: files where it needs a directory, or files under
+    /// it if it's where a directory was.
+    pub fn add(&mut self, entry: IndexEntry) {
+        let dir = format!("{}/", entry.path);
+        self.entries.retain(|e| {
+            e.path != entry.path
+                && !e.path.starts_with(&dir)
+                && !entry.path.starts_with(&format!("{}/", e.path))
+        });
+        self.invalidate(&entry.path);
+        let position = self
+            .entries
+            .partition_point(|e| e.path.as_bytes() < entry.path.as_bytes());
+        self.entries.insert(position, entry);
+    }
+
+    /// Unstages the entry at `path`, returning whether there was one.
+    pub fn remove(&mut self, path: &str) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|e| e.path != path);
+        if self.entries.len() == len {
+            return false;
+        }
+        self.invalidate(path);
+        true
+    }
+
+    /// Forgets the cached trees of the directories leading up to `path`.
+    fn invalidate(&mut self, path: &str) {
+        if let Some(cache_tree) = &mut self.cache_tree {
+            cache_tree.invalidate(path);
+        }
+    }
+}
+
+impl IndexEntry {
+    /// Builds the entry for a file in the working tree whose content has been
+    /// stored as `sha`.
+    pub fn from_file(file: &Path, path: &str, sha: Sha) -> GitResult<IndexEntry> {
+        let metadata = fs::symlink_metadata(file)?;
+        let mode = if metadata.file_type().is_symlink() {
+            0o120000
+        } else if metadata.permissions().mode() & 0o111 != 0 {
+            0o100755
+        } else {
+            0o100644
+        };
+        // Git keeps the low 32 bits of each of these.
+        Ok(IndexEntry {
+            ctime: (metadata.ctime() as u32, metadata.ctime_nsec() as u32),
+            mtime: (metadata.mtime() as u32, metadata.mtime_nsec() as u32),
+            dev: metadata.dev() as u32,
+            ino: metadata.ino() as u32,
+            mode,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            size: metadata.size() as u32,
+            sha,
+            flags: 0,
+            extended_flags: 0,
+            path: path.to_owned(),
+        })
+    }
+
+    /// The merge stage, which is 0 unless the path has a conflict.
+    pub fn stage(&self) -> u16 {
+        (self.flags & STAGE_MASK) >> 12
+    }
+
+    /// Whether the file is to be taken as unchanged without looking at it.
+    pub fn assume_unchanged(&self) -> bool {
+        self.flags & ASSUME_VALID != 0
+    }
+
+    pub fn set_assume_unchanged(&mut self, on: bool) {
+        self.flags = if on {
+            self.flags | ASSUME_VALID
+        } else {
+            self.flags & !ASSUME_VALID
+        };
+    }
+
+    /// Whether the file is left out of the working tree, as in a sparse
+    /// checkout.
+    pub fn skip_worktree(&self) -> bool {
+        self.extended_flags & SKIP_WORKTREE != 0
+    }
+
+    pub fn set_skip_worktree(&mut self, on: bool) {
+        self.extended_flags = if on {
+            self.extended_flags | SKIP_WORKTREE
+        } else {
+            self.extended_flags & !SKIP_WORKTREE
+        };
+    }
+
+    /// Whether the file in the working tree looks the way it did when the
+    /// entry was made, going by its metadata alone.
+    pub fn matches_stat(&self, metadata: &fs::Metadata) -> bool {
+        self.mtime == (metadata.mtime() as u32, metadata.mtime_nsec() as u32)
+            && self.size == metadata.size() as u32
+            && self.ino == metadata.ino() as u32
+    }
+}
+
+impl CacheTree {
+    /// Parses a node and its subtrees, each stored as
+    /// `<name>\0<entry count> <subtree count>\n[<sha>]`.
+    fn parse(data: &mut &[u8]) -> GitResult<CacheTree> {
+        let invalid = || GitError("Invalid cache tree in index".to_owned());
+        let header_end = data.iter().position(|&b| b == b'\n').ok_or_else(invalid)?;
+        let header = std::str::from_utf8(&data[..header_end])?;
+        let (_, counts) = header.split_once('\0').ok_or_else(invalid)?;
+        let (entry_count, subtree_count) = counts.split_once(' ').ok_or_else(invalid)?;
+        let entry_count = entry_count.parse::<i64>().map_err(|_| invalid())?;
+        let subtree_count = subtree_count.parse::<usize>().map_err(|_| invalid())?;
+        *data = &data[header_end + 1..];
+
+        let mut node = CacheTree::default();
+        // A negative entry count marks an invalidated tree, which has no SHA.
+        if entry_count >= 0 {
+            if data.len() < 20 {
+                return Err(invalid());
+            }
+            let mut sha = [0u8; 20];
+            sha.copy_from_slice(&data[..20]);
+            node.tree = Some((entry_count as usize, sha));
+            *data = &data[20..];
+        }
+        for _ in 0..subtree_count {
+            let name_end = data.iter().position(|&b| b == 0).ok_or_else(invalid)?;
+            let name = String::from_utf8(data[..name_end].to_vec())?;
+            node.children.push((name, CacheTree::parse(data)?));
+        }
+        Ok(node)
+    }
+
+    fn encode(&self, name: &str, out: &mut Vec<u8>) {
+        out.extend_from_slice(name.as_bytes());
+        out.push(0);
+        let entry_count = match self.tree {
+            Some((count, _)) => count as i64,
+            None => -1,
+        };
+        out.extend_from_slice(format!("{} {}\n", entry_count, self.children.len()).as_bytes());
+        if let Some((_, sha)) = &self.tree {
+            out.extend_from_slice(sha);
+        }
+        for (name, child) in &self.children {
+            child.encode(name, out);
+        }
+    }
+
+    fn invalidate(&mut self, path: &str) {
+        self.tree = None;
+        let mut parts = path.splitn(2, '/');
+        if let (Some(dir), Some(rest)) = (parts.next(), parts.next()) {
+            if let Some((_, child)) = self.children.iter_mut().find(|(name, _)| name == dir) {
+                child.invalidate(rest);
+            }
+        }
+    }
+}
+
+/// Reads a variable-length number the way git encodes pack offsets: seven
+/// bits a byte, most significant first, with each continuation adding one.
+/// Returns it along with how many bytes it took.
+fn read_varint(bytes: &[u8]) -> Option<(usize, usize)> {
+    let mut value = (*bytes.first()? & 0x7f) as usize;
+    let mut len = 1;
+    while bytes[len - 1] & 0x80 != 0 {
+        let byte = *bytes.get(len)?;
+        value = ((value + 1) << 7) | (byte & 0x7f) as usize;
+        len += 1;
+    }
+    Some((value, len))
+}
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::path::PathBuf;
+    use std::process;
+
+    fn scratch_file(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("index-test-{}-{}", process::id(), name))
+    }
+
+    fn entry(path: &str) -> IndexEntry {
+        IndexEntry {
+            mode: 0o100644,
+            path: path.to_owned(),
+            ..IndexEntry::default()
+        }
+    }
+
+    /// Appends the checksum and writes the index out.
+    fn write_raw(path: &Path, mut data: Vec<u8>) {
+        let checksum = object::get_sha(&data);
+        data.extend_from_slice(&checksum);
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn extended_flags_are_written_as_version_3() {
+        let path = scratch_file("v3");
+        let mut index = Index::default();
+        index.add(entry("a"));
+        index.write(&path).unwrap();
+        assert_eq!(fs::read(&path).unwrap()[4..8], 2u32.to_be_bytes());
+
+        index.entries[0].set_skip_worktree(true);
+        index.add(entry("b"));
+        index.write(&path).unwrap();
+        let mut data = fs::read(&path).unwrap();
+        assert_eq!(data[4..8], 3u32.to_be_bytes());
+        let read = Index::read(&path).unwrap();
+        let flags: Vec<bool> = read.entries.iter().map(|e| e.skip_worktree()).collect();
+        assert_eq!(flags, [true, false]);
+
+        // Version 2 has no room for them.
+        data[7] = 2;
+        data.truncate(data.len() - 20);
+        write_raw(&path, data);
+        assert!(Index::read(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn version_4_paths_are_prefix_compressed() {
+        let path = scratch_file("v4");
+        let mut data = b"DIRC\0\0\0\x04\0\0\0\x03".to_vec();
+        // Each path drops that many bytes from the end of the one before.
+        for (strip, name) in [(0u8, "dir/a"), (1, "b"), (5, "top")] {
+            let mut fields = [0u8; 62];
+            fields[24..28].copy_from_slice(&0o100644u32.to_be_bytes());
+            fields[61] = name.len() as u8;
+            data.extend_from_slice(&fields);
+            data.push(strip);
+            data.extend_from_slice(name.as_bytes());
+            data.push(0);
+        }
+        write_raw(&path, data.clone());
+        let index = Index::read(&path).unwrap();
+        let paths: Vec<&str> = index.entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["dir/a", "dir/b", "top"]);
+        assert!(index.entries.iter().all(|e| e.mode == 0o100644));
+
+        // Dropping more than the previous path has.
+        let strip = data.len() - 5;
+        data[strip] = 6;
+        write_raw(&path, data);
+        assert!(Index::read(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
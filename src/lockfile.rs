@@ -0,0 +1,42 @@
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::git_error::{GitError, GitResult};
+
+/// Takes the lock on the file at `path` by creating `<path>.lock`, which
+/// fails if another process holds it. `what` names the file for the error.
+/// Returns the lock's path.
+pub fn lock(path: &Path, what: &str) -> GitResult<PathBuf> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut lock = path.as_os_str().to_owned();
+    lock.push(".lock");
+    let lock = PathBuf::from(lock);
+    match OpenOptions::new().write(true).create_new(true).open(&lock) {
+        Ok(_) => Ok(lock),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Err(GitError(format!(
+            "Unable to lock {}: '{}' exists; another git process seems to be running",
+            what,
+            lock.display()
+        ))),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes `content` to a held lock and renames it over the file, which
+/// releases the lock. Readers see either the old file or the new one.
+pub fn commit(lock: &Path, path: &Path, content: &[u8]) -> GitResult<()> {
+    let result = fs::write(lock, content).and_then(|()| fs::rename(lock, path));
+    if result.is_err() {
+        let _ = fs::remove_file(lock);
+    }
+    Ok(result?)
+}
+
+/// Writes `content` to `path` under its lock.
+pub fn write(path: &Path, what: &str, content: &[u8]) -> GitResult<()> {
+    let lock = lock(path, what)?;
+    commit(&lock, path, content)
+}
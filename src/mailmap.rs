@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::git_error::GitResult;
+use crate::object::Contributor;
+
+/// The canonical names and emails from `.mailmap`, for showing contributors
+/// who've committed under several identities as one.
+#[derive(Debug, Default)]
+pub struct Mailmap {
+    /// By the lowercased email (and optionally name) found in commits.
+    entries: HashMap<(String, Option<String>), Proper>,
+}
+
+/// What a mailmap entry replaces, either of which it may leave as it is.
+#[derive(Debug, Default)]
+struct Proper {
+    name: Option<String>,
+    email: Option<String>,
+}
+
+impl Mailmap {
+    /// Reads `.mailmap` at the top of the working tree, followed by the file
+    /// named by `mailmap.file`, whose entries win.
+    pub fn load(root: &str) -> GitResult<Mailmap> {
+        let mut mailmap = Mailmap::default();
+        let config = Config::load(root)?;
+        let mut files = vec![Path::new(root).join(".mailmap")];
+        files.extend(config.get_path("mailmap", None, "file"));
+        for file in files {
+            if let Ok(content) = fs::read_to_string(file) {
+                mailmap.parse(&content);
+            }
+        }
+        Ok(mailmap)
+    }
+
+    /// Adds the entries of a mailmap file, one per line, each in one of the
+    /// forms:
+    ///
+    /// ```text
+    /// Proper Name <commit@email>
+    /// <proper@email> <commit@email>
+    /// Proper Name <proper@email> <commit@email>
+    /// Proper Name <proper@email> Commit Name <commit@email>
+    /// ```
+    fn parse(&mut self, content: &str) {
+        for line in content.lines() {
+            if line.starts_with('#') {
+                continue;
+            }
+            let (name, email, rest) = match split_name_and_email(line) {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            // With one email, it's the commit's; with two, the second is.
+            let (commit_name, commit_email, proper_email) = match split_name_and_email(rest) {
+                Some((commit_name, commit_email, _)) => (commit_name, commit_email, Some(email)),
+                None => (None, email, None),
+            };
+            let key = (
+                commit_email.to_lowercase(),
+                commit_name.map(str::to_lowercase),
+            );
+            // A later entry for the same identity fills in or replaces what
+            // an earlier one said.
+            let entry = self.entries.entry(key).or_default();
+            if let Some(name) = name {
+                entry.name = Some(name.to_owned());
+            }
+            if let Some(email) = proper_email {
+                entry.email = Some(email.to_owned());
+            }
+        }
+    }
+
+    /// The contributor as they should be shown. Entries naming both the
+    /// commit's name and email take precedence over ones naming just the
+    /// email; case doesn't matter in either.
+    pub fn map(&self, contributor: &Contributor) -> Contributor {
+        let email = contributor.email.to_lowercase();
+        let found = self
+            .entries
+            .get(&(email.clone(), Some(contributor.name.to_lowercase())))
+            .or_else(|| self.entries.get(&(email, None)));
+        let mut mapped = contributor.clone();
+        if let Some(proper) = found {
+            // The line as read no longer matches.
+            mapped.raw = None;
+            if let Some(name) = &proper.name {
+                mapped.name = name.clone();
+            }
+            if let Some(email) = &proper.email {
+                mapped.email = email.clone();
+            }
+        }
+        mapped
+    }
+}
+
+/// Splits `Name <email> rest` into the name (if any), the email and the
+/// rest of the line.
+fn split_name_and_email(line: &str) -> Option<(Option<&str>, &str, &str)> {
+    let start = line.find('<')?;
+    let end = start + line[start..].find('>')?;
+    let name = line[..start].trim();
+    Some((
+        Some(name).filter(|name| !name.is_empty()),
+        line[start + 1..end].trim(),
+        &line[end + 1..],
+    ))
+}
@@ -1,109 +1,879 @@
 use std::env;
 use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::time::SystemTime;
 
 use bytes::Bytes;
 
+use config::Config;
 use git_error::{GitError, GitResult};
+use grep::Pattern;
+use index::{CacheTree, Index, IndexEntry};
+use mailmap::Mailmap;
 use object::{Contributor, Object, ObjectReference, Sha};
-use std::collections::HashMap;
+use refspec::Refspec;
+use repository::{git_path, Head, Repository};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use store::{MemoryStore, ObjectStore};
 
+use flate2::Crc;
+
+mod attributes;
+mod bundle;
+mod config;
+mod diff;
 mod git_error;
+mod grep;
+mod idx;
+mod index;
+mod lockfile;
+mod mailmap;
 mod object;
 mod pack;
 mod parser;
+mod patch;
+mod pkt;
+mod refs;
+mod refspec;
 mod remote;
+mod repository;
+mod shallow;
+mod sparse;
+mod store;
+mod tar;
+mod trace;
 mod zlib;
 
 fn main() -> GitResult<()> {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let mut repo = match args[1].as_str() {
+        "init" | "clone" => Repository::new("."),
+        _ => Repository::open(".")?,
+    };
+    // Like git, objects can be kept outside the repository.
+    if let Some(dir) = env::var_os("GIT_OBJECT_DIRECTORY") {
+        repo.objects_dir = PathBuf::from(dir);
+    }
+    // With --dry-run, the writing commands report SHAs without storing anything.
+    repo.dry_run = take_option(&mut args, &["--dry-run"]);
+    // Status messages go to stderr, so stdout only has what other programs
+    // read. --quiet leaves them out, and --progress shows download progress
+    // even when stderr isn't a terminal.
+    repo.quiet = take_option(&mut args, &["--quiet", "-q"]);
+    let progress = take_option(&mut args, &["--progress"]);
+    repo.progress = !repo.quiet && (progress || io::stderr().is_terminal());
     match args[1].as_str() {
         "init" => {
             init(".")?;
-            println!("Initialized git directory")
+            report(&repo, "Initialized git directory")
+        }
+        "cat-file" if args.len() > 2 && args[2] == "--batch-check" => {
+            if args[3..].iter().any(|a| a == "--batch-all-objects") {
+                batch_check_all(&repo)?
+            } else {
+                batch_check(&repo)?
+            }
+        }
+        "cat-file" if args.len() > 3 => {
+            // Lets objects of types git doesn't know be inspected, rather than rejected.
+            let allow_unknown = args.iter().any(|a| a == "--allow-unknown-type");
+            let name = args[3..]
+                .iter()
+                .find(|a| !a.starts_with('-'))
+                .ok_or("usage: cat-file (-t | -s | -p) [--allow-unknown-type] <object>")?;
+            let raw = repo.read_raw_object(&resolve_object(&repo, name)?)?;
+            let (kind, content) = object::split_header(&raw)?;
+            let known = object::is_known_type(&kind);
+            if !known && !allow_unknown {
+                return Err(GitError(format!("Invalid object type: {}", kind)));
+            }
+            match args[2].as_str() {
+                "-t" => println!("{}", kind),
+                "-s" => println!("{}", content.len()),
+                // Blobs are printed as they are, whatever they hold.
+                "-p" if kind == "blob" => io::stdout().write_all(&content)?,
+                "-p" if known => print!("{}", Object::decode(raw)?.content()?),
+                "-p" => io::stdout().write_all(&content)?,
+                _ => return Err(GitError(format!("Unknown cat-file mode: {}", args[2]))),
+            }
         }
-        "cat-file" if args[2] == "-p" => print!("{}", read_object(&args[3])?.content()?),
         "hash-object" if args[2] == "-w" => {
-            let bytes = Bytes::from(fs::read(&args[3]).expect("Could not find the object"));
-            let hash = write_object(".", &Object::Blob(bytes))?;
+            // A directory is written as a tree, like write-tree does.
+            let hash = if Path::new(&args[3]).is_dir() {
+                write_tree(&repo, &args[3], &[".git"])?
+            } else {
+                let bytes = Bytes::from(fs::read(&args[3])?);
+                repo.write_object(&Object::Blob(bytes))?
+            };
             println!("{}", object::to_hex(&hash))
         }
-        "commit-tree" if args[3] == "-p" && args[5] == "-m" => {
-            let contributor = Contributor {
-                name: "Andrei".to_owned(),
-                email: "andrei@example.com".to_owned(),
-                timestamp: SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)?
-                    .as_secs(),
-                timezone: std::str::from_utf8(&Command::new("date").arg("+%z").output()?.stdout)?
-                    .trim_end()
-                    .to_owned(),
+        "hash-object" if args.len() > 2 => {
+            // The file is hashed as it's read, so it needn't fit in memory.
+            let mut file = File::open(&args[2])?;
+            let mut hasher = object::ShaHasher::new();
+            hasher.update(format!("blob {}\0", file.metadata()?.len()).as_bytes());
+            io::copy(&mut file, &mut hasher)?;
+            println!("{}", object::to_hex(&hasher.finalize()))
+        }
+        "commit-tree" => {
+            // Any number of `-p`s: none for a root commit, several for a merge.
+            let mut parents = Vec::new();
+            // Bytes rather than a string, as messages needn't be UTF-8.
+            let mut message: Option<Vec<u8>> = None;
+            for option in args[3..].chunks(2) {
+                match option {
+                    [flag, parent] if flag == "-p" => parents.push(parent.clone()),
+                    // Each `-m` or `-F` is a paragraph of its own.
+                    [flag, msg] if flag == "-m" => {
+                        let mut paragraphs =
+                            message.map_or(Vec::new(), |m| [m, vec![b'\n']].concat());
+                        paragraphs.extend_from_slice(msg.as_bytes());
+                        paragraphs.push(b'\n');
+                        message = Some(paragraphs)
+                    }
+                    // A message from a file is kept byte for byte.
+                    [flag, file] if flag == "-F" => {
+                        let mut paragraphs =
+                            message.map_or(Vec::new(), |m| [m, vec![b'\n']].concat());
+                        if file == "-" {
+                            io::stdin().read_to_end(&mut paragraphs)?;
+                        } else {
+                            paragraphs.extend(fs::read(file)?);
+                        }
+                        message = Some(paragraphs)
+                    }
+                    _ => {
+                        return Err(GitError(
+                            "usage: commit-tree <tree> [-p <parent>]... [-m <message> | -F <file>]"
+                                .to_owned(),
+                        ))
+                    }
+                }
+            }
+            // Without -m or -F, the message comes from stdin, as in git.
+            let message = match message {
+                Some(message) => message,
+                None => {
+                    let mut message = Vec::new();
+                    io::stdin().read_to_end(&mut message)?;
+                    message
+                }
             };
-            let hash = write_object(
-                ".",
-                &Object::Commit {
-                    tree: args[2].clone(),
-                    parents: vec![args[4].clone()],
-                    author: contributor.clone(),
-                    committer: contributor,
-                    message: format!("{}\n", args[6]),
-                },
-            )?;
+            let hash = repo.write_object(&Object::Commit {
+                tree: args[2].clone(),
+                parents,
+                author: current_contributor(&repo, "AUTHOR")?,
+                committer: current_contributor(&repo, "COMMITTER")?,
+                message: message.into(),
+                crlf_headers: false,
+                extra_headers: Vec::new(),
+                blank_line: true,
+            })?;
             println!("{}", object::to_hex(&hash))
         }
-        "ls-tree" if args[2] == "--name-only" => match read_object(&args[3])? {
-            Object::Tree(refs) => println!(
-                "{}",
-                refs.iter()
-                    .map(|r| &*r.name)
-                    .collect::<Vec<&str>>()
-                    .join("\n")
-            ),
+        "ls-tree" if args.len() > 3 && args[2] == "--name-only" => {
+            match repo.read_object(&resolve_object(&repo, &args[3])?)? {
+                Object::Tree(refs) => println!(
+                    "{}",
+                    refs.iter()
+                        .map(|r| String::from_utf8_lossy(&r.name))
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                ),
+                _ => return Err(GitError("Not a tree".to_owned())),
+            }
+        }
+        "ls-tree" if args.len() > 2 => match repo.read_object(&resolve_object(&repo, &args[2])?)? {
+            Object::Tree(refs) => refs.iter().for_each(|r| println!("{}", r)),
             _ => return Err(GitError("Not a tree".to_owned())),
         },
-        "write-tree" => println!("{}", object::to_hex(&write_tree(".", &[".git"])?)),
-        "clone" => {
-            let git_url = args[2].clone();
-            let dir = args[3].clone();
-            fs::create_dir(&dir)?;
-            init(dir.as_str())?;
-            let head = &remote::get_refs(&git_url)?[0].sha;
-            let pack_objects = remote::fetch_ref(&git_url, head)?;
-            for (_, o) in pack_objects.iter() {
-                write_object(dir.as_str(), o)?;
-            }
-            let head_commit = pack_objects
-                .get(head)
-                .ok_or(format!("Head ({}) not found in the pack file", head))?;
-            let head_tree_sha = match head_commit {
-                Object::Commit { tree, .. } => tree,
+        "describe" => {
+            let all_tags = args.iter().any(|a| a == "--tags");
+            let always = args.iter().any(|a| a == "--always");
+            let rev = args[2..]
+                .iter()
+                .find(|a| !a.starts_with("--"))
+                .map_or("HEAD", |a| a.as_str());
+            println!(
+                "{}",
+                describe(&repo, &resolve_object(&repo, rev)?, all_tags, always)?
+            )
+        }
+        "notes" if args.len() > 2 && args[2] == "show" => {
+            let sha = resolve_object(&repo, args.get(3).map_or("HEAD", |a| a.as_str()))?;
+            match find_note(&repo, &sha)? {
+                Some(note) => io::stdout().write_all(&repo.read_object(&note)?.body()?)?,
+                None => return Err(GitError(format!("no note found for object {}.", sha))),
+            }
+        }
+        "worktree" if args.len() > 4 && args[2] == "add" => {
+            worktree_add(&repo, &args[3], &args[4])?
+        }
+        "show-ref" => show_ref(&repo, &args[2..])?,
+        "update-ref" if args.len() == 4 || args.len() == 5 => {
+            let new = resolve_object(&repo, &args[3])?;
+            // An empty old value is how a ref that mustn't exist is asked for.
+            let old = match args.get(4).map(String::as_str) {
+                Some("") => Some(String::new()),
+                Some(old) => Some(resolve_object(&repo, old)?),
+                None => None,
+            };
+            refs::update_ref(repo.root(), &args[2], &new, old.as_deref())?
+        }
+        "merge-base" if args.len() > 4 && args[2] == "--is-ancestor" => {
+            let ancestor = resolve_object(&repo, &args[3])?;
+            let descendant = resolve_object(&repo, &args[4])?;
+            if !is_ancestor(&repo, &ancestor, &descendant)? {
+                std::process::exit(1);
+            }
+        }
+        "blame" if args.len() > 2 => blame(&repo, &args[2])?,
+        "diff" if args.len() > 4 && args[2] == "--name-status" => {
+            let from = peel_to(&repo, &resolve_object(&repo, &args[3])?, "tree")?;
+            let to = peel_to(&repo, &resolve_object(&repo, &args[4])?, "tree")?;
+            diff_name_status(&repo, &from, &to)?
+        }
+        "diff" if args.len() > 4 && args[2] == "--stat" => {
+            let from = peel_to(&repo, &resolve_object(&repo, &args[3])?, "tree")?;
+            let to = peel_to(&repo, &resolve_object(&repo, &args[4])?, "tree")?;
+            diff_stat(&repo, &from, &to)?
+        }
+        "log" => log(
+            &repo,
+            &resolve_object(&repo, args.get(2).map_or("HEAD", |a| a.as_str()))?,
+        )?,
+        "shortlog" => {
+            // Single-letter flags combine, as in `-sn`.
+            let flags: String = args[2..]
+                .iter()
+                .filter(|a| a.starts_with('-') && !a.starts_with("--"))
+                .flat_map(|a| a[1..].chars())
+                .collect();
+            let rev = args[2..]
+                .iter()
+                .find(|a| !a.starts_with('-'))
+                .map_or("HEAD", |a| a.as_str());
+            shortlog(
+                &repo,
+                &resolve_object(&repo, rev)?,
+                flags.contains('s'),
+                flags.contains('n'),
+                flags.contains('e'),
+            )?
+        }
+        "fetch" => {
+            let mut remote_name = "origin";
+            let mut deepen = None;
+            let mut excluded = Vec::new();
+            let mut options = args[2..].iter();
+            while let Some(arg) = options.next() {
+                let (flag, value) = match arg.split_once('=') {
+                    Some((flag, value)) => (flag, Some(value)),
+                    None => (arg.as_str(), None),
+                };
+                let mut value = || {
+                    value
+                        .or_else(|| options.next().map(String::as_str))
+                        .ok_or_else(|| GitError(format!("{} needs a value", flag)))
+                };
+                let depth = |value: &str| {
+                    value
+                        .parse::<u32>()
+                        .map_err(|_| GitError(format!("Invalid depth: {}", value)))
+                };
+                match flag {
+                    "--depth" => deepen = Some(remote::Deepen::Depth(depth(value()?)?)),
+                    "--deepen" => deepen = Some(remote::Deepen::Relative(depth(value()?)?)),
+                    "--shallow-since" => {
+                        deepen = Some(remote::Deepen::Since(parse_since(value()?)?))
+                    }
+                    "--shallow-exclude" => excluded.push(value()?.to_owned()),
+                    _ if arg.starts_with('-') => {}
+                    _ => remote_name = arg,
+                }
+            }
+            if !excluded.is_empty() {
+                deepen = Some(remote::Deepen::Not(excluded));
+            }
+            if matches!(deepen, Some(remote::Deepen::Relative(_)))
+                && shallow::read(repo.root())?.is_empty()
+            {
+                return Err(GitError("Repository is not shallow".to_owned()));
+            }
+            fetch(&repo, remote_name, deepen)?
+        }
+        "pull" => pull(&repo)?,
+        "rev-list" => {
+            let count = args.iter().any(|a| a == "--count");
+            let mut max_count = None;
+            let mut revs = Vec::new();
+            let mut options = args[2..].iter();
+            while let Some(arg) = options.next() {
+                let value = match arg.as_str() {
+                    "-n" | "--max-count" => options.next().map(String::as_str),
+                    _ => arg.strip_prefix("--max-count="),
+                };
+                match value {
+                    Some(value) => {
+                        max_count = Some(
+                            value
+                                .parse::<usize>()
+                                .map_err(|_| GitError(format!("Invalid max count: {}", value)))?,
+                        )
+                    }
+                    None if arg.starts_with("--") => {}
+                    None => revs.push(arg.clone()),
+                }
+            }
+            if revs.is_empty() {
+                return Err(GitError(
+                    "usage: rev-list [--count] [--max-count=<n>] <rev>...".to_owned(),
+                ));
+            }
+            rev_list(&repo, &revs, max_count, count)?
+        }
+        "grep" if args.len() > 2 => {
+            let fixed = args[2] == "-F";
+            let mut rest = &args[if fixed { 3 } else { 2 }..];
+            // `--` lets a pattern start with a dash.
+            if rest.first().is_some_and(|a| a == "--") {
+                rest = &rest[1..];
+            }
+            let pattern = rest.first().ok_or("usage: grep [-F] <pattern> [<rev>]")?;
+            let pattern = if fixed {
+                Pattern::fixed(pattern)?
+            } else {
+                Pattern::regex(pattern)?
+            };
+            grep(&repo, &pattern, rest.get(1).map_or("HEAD", |a| a.as_str()))?
+        }
+        "checkout" if args.len() > 3 && args[2] == "-b" => {
+            let branch = format!("refs/heads/{}", args[3]);
+            if refs::read_ref(repo.root(), &branch)?.is_some() {
+                return Err(GitError(format!(
+                    "A branch named '{}' already exists",
+                    args[3]
+                )));
+            }
+            let current = repo.head_commit()?;
+            let start = match args.get(4) {
+                Some(start) => Some(refs::resolve_rev(repo.root(), start)?),
+                None => current.clone(),
+            };
+            // On an unborn HEAD there's nothing to branch from yet, so only
+            // HEAD is repointed and the branch is born with the first commit.
+            if let Some(start) = &start {
+                if current.as_ref() != Some(start) {
+                    checkout_commit(&repo, current.as_deref(), start)?;
+                }
+                refs::write_ref(repo.root(), &branch, start)?;
+            }
+            repo.set_head(&Head::Branch(branch))?;
+            // Branching off a remote-tracking branch tracks it, as in git.
+            if let Some(start) = args.get(4) {
+                let mut config = Config::read(repo.root())?;
+                if let Some((remote_name, merge)) = remote_branch(&repo, &config, start)? {
+                    config.set("branch", Some(&args[3]), "remote", &remote_name);
+                    config.set("branch", Some(&args[3]), "merge", &merge);
+                    config.write(repo.root())?;
+                    report(
+                        &repo,
+                        &format!(
+                            "branch '{}' set up to track '{}/{}'.",
+                            args[3],
+                            remote_name,
+                            merge.trim_start_matches("refs/heads/")
+                        ),
+                    );
+                }
+            }
+            report(&repo, &format!("Switched to a new branch '{}'", args[3]))
+        }
+        "checkout" if args.len() > 2 => {
+            let current = repo.head_commit()?;
+            let branch = format!("refs/heads/{}", args[2]);
+            // A branch name makes HEAD a symref; anything else detaches it.
+            let (target, branch) = match refs::read_ref(repo.root(), &branch)? {
+                Some(sha) => (sha, Some(branch)),
+                None => (refs::resolve_rev(repo.root(), &args[2])?, None),
+            };
+            let summary = match repo.read_object(&target)? {
+                Object::Commit { message, .. } => String::from_utf8_lossy(&message)
+                    .lines()
+                    .next()
+                    .unwrap_or("")
+                    .to_owned(),
+                _ => return Err(GitError(format!("Not a commit: {}", args[2]))),
+            };
+            if current.as_ref() != Some(&target) {
+                checkout_commit(&repo, current.as_deref(), &target)?;
+            }
+            match branch {
+                Some(branch) => {
+                    repo.set_head(&Head::Branch(branch))?;
+                    report(&repo, &format!("Switched to branch '{}'", args[2]))
+                }
+                None => {
+                    repo.set_head(&Head::Detached(target.clone()))?;
+                    report(
+                        &repo,
+                        &format!("HEAD is now at {} {}", &target[..7], summary),
+                    )
+                }
+            }
+        }
+        "bundle" if args.len() > 3 => {
+            let path = Path::new(&args[3]);
+            match args[2].as_str() {
+                "create" => create_bundle(&repo, path, &args[4..])?,
+                "verify" => {
+                    let (header, pack) = bundle::read(path)?;
+                    let missing: Vec<&String> = header
+                        .prerequisites
+                        .iter()
+                        .filter(|sha| !has_object(&repo, sha))
+                        .collect();
+                    if !missing.is_empty() {
+                        for sha in missing {
+                            eprintln!("{}", sha);
+                        }
+                        return Err(GitError(
+                            "Repository lacks these prerequisite commits".to_owned(),
+                        ));
+                    }
+                    let (objects, _) =
+                        pack::parse_thin_pack(pack, &pack::PackLimits::default(), &|sha| {
+                            repo.read_stored_raw_object(&object::to_hex(sha)).ok()
+                        })?;
+                    check_bundle_refs(&repo, &header, &objects)?;
+                    println!("The bundle contains {} ref(s):", header.refs.len());
+                    for r in &header.refs {
+                        println!("{} {}", r.sha, r.name);
+                    }
+                    if header.prerequisites.is_empty() {
+                        println!("The bundle records a complete history.");
+                    } else {
+                        println!(
+                            "The bundle requires {} commit(s):",
+                            header.prerequisites.len()
+                        );
+                        for sha in &header.prerequisites {
+                            println!("{}", sha);
+                        }
+                    }
+                    report(&repo, &format!("{} is okay", path.display()))
+                }
+                "unbundle" => {
+                    let (header, pack) = bundle::read(path)?;
+                    let (objects, _) =
+                        pack::parse_thin_pack(pack, &pack::PackLimits::default(), &|sha| {
+                            repo.read_stored_raw_object(&object::to_hex(sha)).ok()
+                        })?;
+                    check_bundle_refs(&repo, &header, &objects)?;
+                    write_objects(&repo, &objects)?;
+                    // Like git, leave it to the caller to decide which refs to update.
+                    for r in &header.refs {
+                        println!("{} {}", r.sha, r.name);
+                    }
+                }
+                _ => return Err(GitError(format!("Unknown bundle command: {}", args[2]))),
+            }
+        }
+        "verify-objects" => verify_objects(&repo)?,
+        "archive" => {
+            let mut prefix = "";
+            let mut tree_ish = None;
+            for arg in &args[2..] {
+                if let Some(format) = arg.strip_prefix("--format=") {
+                    if format != "tar" {
+                        return Err(GitError(format!("Unknown archive format '{}'", format)));
+                    }
+                } else if let Some(value) = arg.strip_prefix("--prefix=") {
+                    prefix = value;
+                } else if tree_ish.is_none() && !arg.starts_with('-') {
+                    tree_ish = Some(arg.as_str());
+                } else {
+                    return Err(GitError(
+                        "usage: archive [--format=tar] [--prefix=<prefix>] <tree-ish>".to_owned(),
+                    ));
+                }
+            }
+            let tree_ish = tree_ish.ok_or("archive needs a tree-ish")?;
+            archive(&repo, tree_ish, prefix)?
+        }
+        "repack" => repack(&repo, args[2..].iter().any(|a| a == "-d"))?,
+        "verify-pack" => {
+            let verbose = args.iter().any(|a| a == "-v" || a == "--verbose");
+            let pack = args[2..]
+                .iter()
+                .find(|a| !a.starts_with('-'))
+                .ok_or("usage: verify-pack [-v] <pack>")?;
+            verify_pack(Path::new(pack), verbose)?
+        }
+        "add" if args.len() > 2 => {
+            let index_path = repo.index_path();
+            let mut index = Index::read(&index_path)?;
+            for path in &args[2..] {
+                add_path(&repo, &mut index, &normalize_path(path))?;
+            }
+            if !repo.dry_run {
+                index.write(&index_path)?;
+            }
+        }
+        "rm" if args.len() > 2 => {
+            let flag = |name: &str| args[2..].iter().any(|a| a == name);
+            let paths: Vec<String> = args[2..]
+                .iter()
+                .filter(|a| !a.starts_with('-'))
+                .map(|a| normalize_path(a))
+                .collect();
+            rm(&repo, &paths, flag("--cached"), flag("-f"), flag("-r"))?
+        }
+        "apply" => {
+            let cached = args[2..].iter().any(|a| a == "--cached");
+            let mut patch = Vec::new();
+            match args[2..].iter().find(|a| !a.starts_with("--")) {
+                Some(path) if path != "-" => patch = fs::read(path)?,
                 _ => {
-                    return Err(GitError(format!(
-                        "Head ({}) is not pointing to a commit",
-                        head
-                    )))
+                    io::stdin().read_to_end(&mut patch)?;
+                }
+            }
+            apply(&repo, &patch, cached)?
+        }
+        "mv" if args.len() > 3 => {
+            let force = args[2..].iter().any(|a| a == "-f");
+            let paths: Vec<String> = args[2..]
+                .iter()
+                .filter(|a| !a.starts_with('-'))
+                .map(|a| normalize_path(a))
+                .collect();
+            match paths.as_slice() {
+                [source, destination] => mv(&repo, source, destination, force)?,
+                _ => return Err(GitError("usage: mv [-f] <source> <destination>".to_owned())),
+            }
+        }
+        "update-index" if args.len() > 3 => {
+            let index_path = repo.index_path();
+            let mut index = Index::read(&index_path)?;
+            // Each flag applies to the paths after it.
+            let mut flag = None;
+            for arg in &args[2..] {
+                if arg.starts_with("--") {
+                    flag = Some(arg.as_str());
+                    continue;
+                }
+                let entry = index
+                    .entries
+                    .iter_mut()
+                    .find(|e| &e.path == arg)
+                    .ok_or_else(|| GitError(format!("Unable to mark file {}", arg)))?;
+                match flag {
+                    Some("--assume-unchanged") => entry.set_assume_unchanged(true),
+                    Some("--no-assume-unchanged") => entry.set_assume_unchanged(false),
+                    Some("--skip-worktree") => entry.set_skip_worktree(true),
+                    Some("--no-skip-worktree") => entry.set_skip_worktree(false),
+                    _ => {
+                        return Err(GitError(
+                            "usage: update-index (--[no-]assume-unchanged | --[no-]skip-worktree) <path>..."
+                                .to_owned(),
+                        ))
+                    }
+                }
+            }
+            index.write(&index_path)?;
+        }
+        "ls-files" => {
+            let stage = args.iter().any(|a| a == "-s" || a == "--stage");
+            // Conflicted paths are listed once for each stage they have.
+            for entry in Index::read(&repo.index_path())?.entries {
+                if stage {
+                    println!(
+                        "{:06o} {} {}\t{}",
+                        entry.mode,
+                        object::to_hex(&entry.sha),
+                        entry.stage(),
+                        entry.path
+                    );
+                } else {
+                    println!("{}", entry.path);
+                }
+            }
+        }
+        "status" => status(&repo)?,
+        "commit" => {
+            let mut paragraphs = Vec::new();
+            let mut verify = true;
+            let mut allow_empty = false;
+            let mut options = args[2..].iter();
+            while let Some(option) = options.next() {
+                match option.as_str() {
+                    "-m" => paragraphs.push(options.next().ok_or("-m needs a message")?.clone()),
+                    "-n" | "--no-verify" => verify = false,
+                    "--allow-empty" => allow_empty = true,
+                    _ => {
+                        return Err(GitError(
+                            "usage: commit [-n | --no-verify] [--allow-empty] -m <message>"
+                                .to_owned(),
+                        ))
+                    }
+                }
+            }
+            if paragraphs.is_empty() {
+                return Err(GitError("commit requires a message (-m)".to_owned()));
+            }
+            commit(&repo, &paragraphs.join("\n\n"), verify, allow_empty)?
+        }
+        "stash" => match args.get(2).map(String::as_str) {
+            None | Some("push") => stash_push(&repo)?,
+            Some("pop") => stash_pop(&repo)?,
+            Some("list") => stash_list(&repo)?,
+            Some(other) => return Err(GitError(format!("Unknown stash command: {}", other))),
+        },
+        "sparse-checkout" if args.len() > 3 && args[2] == "set" => {
+            sparse_checkout(&repo, &args[3..])?
+        }
+        "write-tree" => {
+            // Without an index, the working tree is written as it is.
+            let sha = if repo.index_path().exists() {
+                write_index_tree(&repo)?
+            } else {
+                write_tree(&repo, ".", &[".git"])?
+            };
+            println!("{}", object::to_hex(&sha))
+        }
+        "clone" => {
+            let verbose = args.iter().any(|a| a == "--verbose");
+            // Keep the fetched pack as it is rather than exploding it into loose objects.
+            let keep_pack = args.iter().any(|a| a == "--keep-pack");
+            // A mirror maps every remote ref 1:1 and, like a bare clone, has no checkout.
+            let mirror = args.iter().any(|a| a == "--mirror");
+            // With --single-branch, only the checked out branch is fetched and tracked.
+            let single_branch = args.iter().any(|a| a == "--single-branch");
+            let mut positional = Vec::new();
+            // `--config key=value` settings go into the new repository's config.
+            let mut settings = Vec::new();
+            let mut branch_name = None;
+            let mut options = args[2..].iter();
+            while let Some(arg) = options.next() {
+                match arg.as_str() {
+                    "--config" | "-c" => {
+                        settings.push(options.next().ok_or("--config needs a key=value")?)
+                    }
+                    "--branch" | "-b" => {
+                        branch_name = Some(options.next().ok_or("--branch needs a name")?)
+                    }
+                    _ if arg.starts_with("--") => {}
+                    _ => positional.push(arg),
+                }
+            }
+            let git_url = positional.first().ok_or("clone needs a URL")?.to_string();
+            let dir = positional
+                .get(1)
+                .ok_or("clone needs a directory")?
+                .to_string();
+            // With --resume, a clone that was cut off picks up where it left
+            // off: objects already written aren't downloaded again.
+            let resuming = args.iter().any(|a| a == "--resume")
+                && Path::new(&dir).join(".git/objects").is_dir();
+            if !resuming {
+                fs::create_dir(&dir)?;
+                init(dir.as_str())?;
+            }
+
+            let mut config = default_config();
+            config.set("remote", Some("origin"), "url", &git_url);
+            for setting in settings {
+                let mut parts = setting.splitn(2, '=');
+                let (section, subsection, key) = config::split_key(parts.next().unwrap_or(""))?;
+                config.add(section, subsection, key, parts.next().unwrap_or("true"));
+            }
+
+            let http = http_client(&repo, &config)?;
+            let (remote_refs, capabilities) = remote::get_refs(&git_url, &http)?;
+            let head_symref = capabilities
+                .iter()
+                .find_map(|c| c.strip_prefix("symref=HEAD:"))
+                .filter(|target| {
+                    target.starts_with("refs/heads/") && refs::check_ref_format(target).is_ok()
+                })
+                .map(str::to_owned);
+            // An empty repository leaves nothing to fetch or check out, just
+            // an unborn branch set up to track the remote's.
+            if remote_refs.is_empty() {
+                let branch = head_symref.unwrap_or_else(|| "refs/heads/master".to_owned());
+                if mirror {
+                    config.set("remote", Some("origin"), "fetch", "+refs/*:refs/*");
+                    config.set("remote", Some("origin"), "mirror", "true");
+                } else {
+                    let refspec = "+refs/heads/*:refs/remotes/origin/*";
+                    config.set("remote", Some("origin"), "fetch", refspec);
+                    let short_name = &branch["refs/heads/".len()..];
+                    config.set("branch", Some(short_name), "remote", "origin");
+                    config.set("branch", Some(short_name), "merge", &branch);
+                }
+                config.write(&dir)?;
+                refs::write_symref(&dir, "HEAD", &branch)?;
+                eprintln!("warning: You appear to have cloned an empty repository.");
+                return Ok(());
+            }
+            let remote_head = &remote_refs
+                .iter()
+                .find(|r| r.name == "HEAD")
+                .ok_or("Remote did not advertise HEAD")?
+                .sha;
+            // Without a symref capability, guess from the branches at HEAD.
+            let remote_head_branch = head_symref.or_else(|| {
+                remote_refs
+                    .iter()
+                    .filter(|r| r.name.starts_with("refs/heads/") && &r.sha == remote_head)
+                    .map(|r| r.name.clone())
+                    .min_by_key(|name| match name.as_str() {
+                        "refs/heads/master" => 0,
+                        "refs/heads/main" => 1,
+                        _ => 2,
+                    })
+            });
+            // Check out the requested branch, or the one the remote HEAD points
+            // at, detaching if none does.
+            let (branch, head) = match branch_name {
+                Some(name) => {
+                    let r = remote_refs
+                        .iter()
+                        .find(|r| r.name == format!("refs/heads/{}", name))
+                        .ok_or_else(|| GitError(format!("Remote branch {} not found", name)))?;
+                    (Some(r.name.clone()), r.sha.clone())
                 }
+                None => (remote_head_branch.clone(), remote_head.clone()),
+            };
+
+            let refspec = if mirror {
+                Some("+refs/*:refs/*".to_owned())
+            } else if single_branch {
+                branch.as_ref().map(|branch| {
+                    format!(
+                        "+{}:refs/remotes/origin/{}",
+                        branch,
+                        &branch["refs/heads/".len()..]
+                    )
+                })
+            } else {
+                Some("+refs/heads/*:refs/remotes/origin/*".to_owned())
+            };
+            if let Some(refspec) = refspec {
+                let refspec = Refspec::parse(&refspec)?;
+                config.set("remote", Some("origin"), "fetch", &refspec.to_string());
+            }
+            if mirror {
+                config.set("remote", Some("origin"), "mirror", "true");
+            }
+            config.write(&dir)?;
+
+            let mut cloned = Repository::open(&dir)?;
+            cloned.quiet = repo.quiet;
+            cloned.progress = repo.progress;
+            let repo = cloned;
+            let options = FetchOptions {
+                haves: if resuming {
+                    repo.loose_commits()?
+                } else {
+                    Vec::new()
+                },
+                keep_pack,
+                verbose,
+                ..Default::default()
             };
-            let files = build_tree(
-                &pack_objects,
-                pack_objects.get(head_tree_sha).ok_or("Tree not found")?,
-                vec![dir],
+            fetch_remote_refs(
+                &repo,
+                &http,
+                "origin",
+                &remote_refs,
+                std::slice::from_ref(&head),
+                &options,
             )?;
-            for (path, content) in files {
-                // println!("{}", path);
-                fs::DirBuilder::new()
-                    .recursive(true)
-                    .create(path[..path.len() - 1].join("/"))?;
-                fs::write(path.join("/"), content)?;
+
+            match &branch {
+                Some(branch) => {
+                    refs::write_ref(&dir, branch, &head)?;
+                    refs::write_symref(&dir, "HEAD", branch)?;
+                    // Track the remote branch, for pull.
+                    if !mirror {
+                        let short_name = &branch["refs/heads/".len()..];
+                        config.set("branch", Some(short_name), "remote", "origin");
+                        config.set("branch", Some(short_name), "merge", branch);
+                        config.write(&dir)?;
+                    }
+                }
+                None => refs::write_ref(&dir, "HEAD", &head)?,
+            }
+            if let (false, false, Some(remote_head_branch)) =
+                (mirror, single_branch, &remote_head_branch)
+            {
+                refs::write_symref(
+                    &dir,
+                    "refs/remotes/origin/HEAD",
+                    &format!(
+                        "refs/remotes/origin/{}",
+                        &remote_head_branch["refs/heads/".len()..]
+                    ),
+                )?;
             }
-            println!("Done");
+            if mirror {
+                report(&repo, "Done");
+                return Ok(());
+            }
+
+            checkout_commit(&repo, None, &head)?;
+            report(&repo, "Done");
         }
         _ => println!("unknown command: {}", args[1]),
     }
     Ok(())
 }
 
+/// Who's making commits, and when, as the author or the committer. Each
+/// comes from `GIT_<ROLE>_NAME`, `GIT_<ROLE>_EMAIL` and `GIT_<ROLE>_DATE`
+/// if set, so a commit can keep its original author while being
+/// recommitted; otherwise from `user.name`, `user.email` and the clock.
+fn current_contributor(repo: &Repository, role: &str) -> GitResult<Contributor> {
+    let config = Config::load(repo.root())?;
+    let var = |key: &str| env::var(format!("GIT_{}_{}", role, key)).ok();
+    let (timestamp, timezone) = match var("DATE") {
+        Some(date) => object::parse_date(&date)?,
+        None => (
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_secs() as i64,
+            std::str::from_utf8(&Command::new("date").arg("+%z").output()?.stdout)?
+                .trim_end()
+                .to_owned(),
+        ),
+    };
+    Ok(Contributor {
+        name: var("NAME").unwrap_or_else(|| {
+            config
+                .get("user", None, "name")
+                .unwrap_or("Andrei")
+                .to_owned()
+        }),
+        email: var("EMAIL").unwrap_or_else(|| {
+            config
+                .get("user", None, "email")
+                .unwrap_or("andrei@example.com")
+                .to_owned()
+        }),
+        timestamp,
+        timezone,
+        raw: None,
+    })
+}
+
 fn init(prefix: &str) -> GitResult<()> {
     fs::create_dir(format!("{}/{}", prefix, ".git"))?;
     fs::create_dir(format!("{}/{}", prefix, ".git/objects"))?;
@@ -112,93 +882,3168 @@ fn init(prefix: &str) -> GitResult<()> {
         format!("{}/{}", prefix, ".git/HEAD"),
         "ref: refs/heads/master\n",
     )?;
-    Ok(())
+    default_config().write(prefix)
 }
 
-fn build_tree<'a>(
-    objects: &'a HashMap<String, Object>,
-    obj: &'a Object,
-    prefix: Vec<String>,
-) -> GitResult<HashMap<Vec<String>, &'a Bytes>> {
-    match obj {
-        Object::Blob(content) => {
-            let mut res = HashMap::with_capacity(1);
-            res.insert(prefix, content);
-            Ok(res)
-        }
-        Object::Tree(refs) => {
-            let mut res = HashMap::new();
-            for r in refs {
-                let inner_tree = build_tree(
-                    objects,
-                    objects
-                        .get(&object::to_hex(&r.hash))
-                        .ok_or(format!("Object not found: {}", object::to_hex(&r.hash)))?,
-                    prefix.iter().chain(&[r.name.clone()]).cloned().collect(),
-                )?;
-                res.extend(inner_tree);
+fn default_config() -> Config {
+    let mut config = Config::default();
+    config.set("core", None, "repositoryformatversion", "0");
+    config.set("core", None, "filemode", "true");
+    config.set("core", None, "bare", "false");
+    config
+}
+
+/// Stores fetched objects such that each commit lands after its parents and
+/// everything else, so that a commit being in the store means all it
+/// references is too. That's what lets an interrupted clone be resumed.
+/// Objects are written as received, never decoded and encoded again.
+fn write_objects(store: &dyn ObjectStore, objects: &MemoryStore) -> GitResult<()> {
+    let mut commits = Vec::new();
+    for sha in objects.shas() {
+        let data = objects.read_raw_object(&sha)?;
+        if data.starts_with(b"commit ") {
+            commits.push(sha);
+        } else {
+            store.write_raw_object(data)?;
+        }
+    }
+    let mut written = HashSet::new();
+    for sha in commits {
+        // Walk down to parents first, without recursing, as history can be deep.
+        let mut stack = vec![(sha, false)];
+        while let Some((sha, parents_done)) = stack.pop() {
+            if written.contains(&sha) {
+                continue;
+            }
+            if parents_done {
+                store.write_raw_object(objects.read_raw_object(&sha)?)?;
+                written.insert(sha);
+                continue;
+            }
+            let commit = objects.read_object(&sha)?;
+            stack.push((sha, true));
+            if let Object::Commit { parents, .. } = commit {
+                for parent in parents {
+                    if objects.contains(&parent) {
+                        stack.push((parent, false));
+                    }
+                }
             }
-            Ok(res)
         }
-        Object::Commit { .. } => Err(GitError(String::from("Tree is pointing to a commit"))),
     }
+    Ok(())
 }
 
-fn write_tree(path: &str, ignore: &[&str]) -> GitResult<Sha> {
-    let mut refs = Vec::new();
+/// How `fetch_remote_refs` fetches.
+#[derive(Default)]
+struct FetchOptions {
+    /// Where to cut history off, updating `.git/shallow`.
+    deepen: Option<remote::Deepen>,
+    /// Commits we already have, along with all they reference, so the server
+    /// can leave them out of the pack.
+    haves: Vec<String>,
+    /// Keeps the fetched pack as it is rather than exploding it into loose objects.
+    keep_pack: bool,
+    verbose: bool,
+}
 
-    for f in fs::read_dir(path)? {
-        let path_buf = f?.path();
-        let name = path_buf
-            .file_name()
-            .ok_or("Could not get a file path")?
-            .to_str()
-            .ok_or("Could not get a file path")?
-            .to_owned();
-        if ignore.contains(&&*name) {
+/// Fetches the advertised refs matched by the remote's configured refspecs
+/// (plus any `extra_wants`) that we don't have yet, stores the received
+/// objects and points the mapped local refs at them. `http` is the client
+/// the refs were listed with, whose connection is reused.
+fn fetch_remote_refs(
+    repo: &Repository,
+    http: &remote::HttpClient,
+    remote_name: &str,
+    remote_refs: &[remote::Ref],
+    extra_wants: &[String],
+    options: &FetchOptions,
+) -> GitResult<Vec<RefUpdate>> {
+    let deepen = options.deepen.clone();
+    let config = Config::load(repo.root())?;
+    let url = config
+        .get("remote", Some(remote_name), "url")
+        .ok_or_else(|| GitError(format!("No url configured for remote {}", remote_name)))?;
+    let refspecs = remote_refspecs(&config, remote_name)?;
+
+    let mut updates = Vec::new();
+    for r in remote_refs {
+        // Annotated tags come with a peeled `<tag>^{}` entry, which isn't a ref.
+        if r.name == "HEAD" || r.name.ends_with("^{}") {
             continue;
         }
-        let hash;
-        let mode;
+        if let Some((spec, local)) = refspecs
+            .iter()
+            .find_map(|spec| spec.map(&r.name).map(|local| (spec, local)))
+        {
+            // Names come from the remote and end up as paths in `.git`.
+            if refs::check_ref_format(&r.name)
+                .and(refs::check_ref_format(&local))
+                .is_err()
+            {
+                eprintln!("warning: ignoring ref with broken name {}", r.name);
+                continue;
+            }
+            updates.push((local, r.clone(), spec.force));
+        }
+    }
 
-        if path_buf.is_dir() {
-            hash = write_tree(
-                path_buf.to_str().ok_or("Could not get a file path")?,
-                &ignore,
-            )?;
-            mode = 40000;
+    // The pack is downloaded next to where it'd be kept, rather than to a
+    // shared temporary directory.
+    let pack_dir = repo.objects_dir.join("pack");
+    fs::create_dir_all(&pack_dir)?;
+    let mut wants: Vec<String> = extra_wants.to_vec();
+    for (_, r, _) in &updates {
+        if !wants.contains(&r.sha) {
+            wants.push(r.sha.clone());
+        }
+    }
+    // Commits are only stored once all they reference is, so having one
+    // means having its history too. Deepening asks for what we have again,
+    // so the server can send the history further back.
+    if deepen.is_none() {
+        wants.retain(|sha| !has_object(repo, sha));
+    }
+    if wants.is_empty() && deepen.is_none() {
+        return update_fetched_refs(repo, updates);
+    }
+    // When every refspec names a single ref, ask for the refs by name where
+    // the server allows it, so they can't move between listing and fetching.
+    let exact = !refspecs.is_empty() && refspecs.iter().all(|spec| !spec.src.contains('*'));
+    let features: Vec<String> = if exact || deepen.is_some() {
+        remote::get_capabilities_v2(url, http)?
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|c| c.strip_prefix("fetch="))
+            .flat_map(|features| features.split(' ').map(str::to_owned))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let ref_in_want = exact && features.iter().any(|f| f == "ref-in-want");
+    if deepen.is_some() && !features.iter().any(|f| f == "shallow") {
+        return Err(GitError(format!(
+            "Remote {} does not support shallow fetches",
+            remote_name
+        )));
+    }
+
+    let (objects, stats) = if ref_in_want || deepen.is_some() {
+        let mut request = remote::FetchRequest {
+            haves: options.haves.clone(),
+            shallow: shallow::read(repo.root())?,
+            deepen,
+            ..Default::default()
+        };
+        if ref_in_want {
+            request.want_refs = updates.iter().map(|(_, r, _)| r.name.clone()).collect();
+            request.wants = extra_wants.to_vec();
         } else {
-            let bytes = Bytes::from(fs::read(&path_buf)?);
-            hash = write_object(".", &Object::Blob(bytes))?;
-            mode = 100644;
+            request.wants = wants;
+        }
+        let (response, objects, stats) =
+            remote::fetch_v2(url, &request, &pack_dir, options.keep_pack, http)?;
+        if ref_in_want {
+            for (_, r, _) in updates.iter_mut() {
+                r.sha = response
+                    .wanted_refs
+                    .iter()
+                    .find(|w| w.name == r.name)
+                    .ok_or_else(|| GitError(format!("Server did not send ref {}", r.name)))?
+                    .sha
+                    .clone();
+            }
+        }
+        let mut boundary = request.shallow;
+        boundary.extend(response.shallow.iter().cloned());
+        boundary.retain(|sha| !response.unshallow.contains(sha));
+        shallow::write(repo.root(), &boundary)?;
+        (objects, stats)
+    } else {
+        remote::fetch_refs(
+            url,
+            &wants,
+            &options.haves,
+            &pack_dir,
+            options.keep_pack,
+            http,
+        )?
+    };
+    if options.verbose {
+        println!("{}", stats);
+    }
+    if !options.keep_pack {
+        write_objects(repo, &objects)?;
+    }
+    update_fetched_refs(repo, updates)
+}
+
+/// Reads a `--shallow-since` date: seconds since the epoch, optionally
+/// after an `@`, or a date with a timezone as `GIT_AUTHOR_DATE` takes.
+fn parse_since(date: &str) -> GitResult<i64> {
+    match date.trim_start_matches('@').parse() {
+        Ok(time) => Ok(time),
+        Err(_) => Ok(object::parse_date(date)?.0),
+    }
+}
+
+/// Removes the options named in `names` from the command's arguments,
+/// returning whether there were any. Only options count: not anything after
+/// `--`, which ends them, nor the value given to `-m`.
+fn take_option(args: &mut Vec<String>, names: &[&str]) -> bool {
+    let mut end = args.iter().position(|a| a == "--").unwrap_or(args.len());
+    let count = args.len();
+    let mut i = 2;
+    while i < end {
+        if names.contains(&args[i].as_str()) && args[i - 1] != "-m" {
+            args.remove(i);
+            end -= 1;
+        } else {
+            i += 1;
+        }
+    }
+    args.len() != count
+}
+
+/// Builds the HTTP client for a command's requests to a remote, with the
+/// options from `config`, showing progress if the repository is set to.
+fn http_client(repo: &Repository, config: &Config) -> GitResult<remote::HttpClient> {
+    let mut options = remote::HttpOptions::from_config(config)?;
+    options.progress = repo.progress;
+    options.quiet = repo.quiet;
+    options.connect()
+}
+
+/// Prints a status message for people. It goes to stderr, leaving stdout to
+/// output meant for other programs, and `--quiet` leaves it out.
+fn report(repo: &Repository, message: &str) {
+    if !repo.quiet {
+        eprintln!("{}", message);
+    }
+}
+
+/// Fetches what's new from a remote, updating its remote-tracking refs.
+fn fetch(repo: &Repository, remote_name: &str, deepen: Option<remote::Deepen>) -> GitResult<()> {
+    let config = Config::load(repo.root())?;
+    let url = config
+        .get("remote", Some(remote_name), "url")
+        .ok_or_else(|| GitError(format!("No url configured for remote {}", remote_name)))?;
+    let http = http_client(repo, &config)?;
+    let (remote_refs, _) = remote::get_refs(url, &http)?;
+    // Our own refs tell the server what it can leave out.
+    let mut haves: Vec<String> = refs::list_refs(repo.root(), "refs/")?
+        .into_values()
+        .filter(|sha| has_object(repo, sha))
+        .collect();
+    haves.sort();
+    haves.dedup();
+    let options = FetchOptions {
+        haves,
+        deepen,
+        ..Default::default()
+    };
+    let updated = fetch_remote_refs(repo, &http, remote_name, &remote_refs, &[], &options)?;
+    if !updated.is_empty() {
+        report(repo, &format!("From {}", url));
+    }
+    for update in updated {
+        let remote_name = update.remote_name.trim_start_matches("refs/heads/");
+        let name = update.name.trim_start_matches("refs/remotes/");
+        let line = match update.old {
+            Some(old) => format!(
+                "   {}..{}  {} -> {}",
+                &old[..7],
+                &update.new[..7],
+                remote_name,
+                name
+            ),
+            None => format!(" * [new ref]         {} -> {}", remote_name, name),
         };
+        report(repo, &line);
+    }
+    Ok(())
+}
+
+/// Fetches the current branch's upstream and fast-forwards the branch to it,
+/// checking out the new commit. Branches that have diverged are left for
+/// the user to merge.
+fn pull(repo: &Repository) -> GitResult<()> {
+    let head = repo.head()?;
+    let (branch, short_name) = match (&head, head.branch_name()) {
+        (Head::Branch(branch), Some(short_name)) => (branch, short_name),
+        _ => return Err(GitError("You are not currently on a branch".to_owned())),
+    };
+    let config = Config::load(repo.root())?;
+    let (remote_name, merge) = match branch_upstream(&config, short_name) {
+        Some(upstream) => upstream,
+        None => ("origin".to_owned(), branch.clone()),
+    };
+    fetch(repo, &remote_name, None)?;
+
+    let tracking = tracking_ref(&config, &remote_name, &merge)?;
+    let upstream = refs::read_ref(repo.root(), &tracking)?
+        .ok_or_else(|| GitError(format!("Remote has no {}", merge)))?;
+    let local = refs::read_ref(repo.root(), branch)?;
+    match &local {
+        Some(local) if is_ancestor(repo, &upstream, local)? => {
+            report(repo, "Already up to date.");
+            return Ok(());
+        }
+        Some(local) if !is_ancestor(repo, local, &upstream)? => {
+            return Err(GitError(format!(
+                "Not possible to fast-forward {} to {}; merge them manually",
+                short_name, tracking
+            )));
+        }
+        Some(local) => report(
+            repo,
+            &format!("Updating {}..{}\nFast-forward", &local[..7], &upstream[..7]),
+        ),
+        None => {}
+    }
+    checkout_commit(repo, local.as_deref(), &upstream)?;
+    refs::update_ref(
+        repo.root(),
+        branch,
+        &upstream,
+        Some(local.as_deref().unwrap_or("")),
+    )
+}
+
+/// The remote and remote ref a branch is set up to track, if any.
+fn branch_upstream(config: &Config, branch: &str) -> Option<(String, String)> {
+    let remote_name = config.get("branch", Some(branch), "remote")?;
+    let merge = config.get("branch", Some(branch), "merge")?;
+    Some((remote_name.to_owned(), merge.to_owned()))
+}
+
+/// The local ref a remote's refspecs store the remote ref `name` under.
+fn tracking_ref(config: &Config, remote_name: &str, name: &str) -> GitResult<String> {
+    remote_refspecs(config, remote_name)?
+        .iter()
+        .find_map(|spec| spec.map(name))
+        .ok_or_else(|| GitError(format!("No remote-tracking ref for {}", name)))
+}
 
-        refs.push(ObjectReference { mode, name, hash })
+/// Finds the remote and remote ref that `rev` is the remote-tracking ref of,
+/// if it's one.
+fn remote_branch(
+    repo: &Repository,
+    config: &Config,
+    rev: &str,
+) -> GitResult<Option<(String, String)>> {
+    let name = match refs::expand_ref(repo.root(), rev)? {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let remote_name = match name
+        .strip_prefix("refs/remotes/")
+        .and_then(|rest| rest.split('/').next())
+    {
+        Some(remote_name) => remote_name,
+        None => return Ok(None),
+    };
+    Ok(remote_refspecs(config, remote_name)?
+        .iter()
+        .find_map(|spec| spec.map_back(&name))
+        .map(|merge| (remote_name.to_owned(), merge)))
+}
+
+fn remote_refspecs(config: &Config, remote_name: &str) -> GitResult<Vec<Refspec>> {
+    config
+        .get_all("remote", Some(remote_name), "fetch")
+        .into_iter()
+        .map(Refspec::parse)
+        .collect()
+}
+
+/// Counts the commits `local` has that `upstream` doesn't, and the other way
+/// around.
+fn ahead_behind(repo: &Repository, local: &str, upstream: &str) -> GitResult<(usize, usize)> {
+    let ours = ancestors(repo, local)?;
+    let theirs = ancestors(repo, upstream)?;
+    Ok((
+        ours.difference(&theirs).count(),
+        theirs.difference(&ours).count(),
+    ))
+}
+
+/// A local ref moved by a fetch, from its old SHA, if it existed, to a new one.
+struct RefUpdate {
+    name: String,
+    remote_name: String,
+    old: Option<String>,
+    new: String,
+}
+
+/// Points the local refs at what was fetched for them, returning the ones
+/// that moved. Without `force`, an existing ref only moves forward to a
+/// descendant of what it held, and an existing tag doesn't move at all.
+fn update_fetched_refs(
+    repo: &Repository,
+    updates: Vec<(String, remote::Ref, bool)>,
+) -> GitResult<Vec<RefUpdate>> {
+    let mut updated = Vec::new();
+    for (local, r, force) in updates {
+        let current = refs::read_ref(repo.root(), &local)?;
+        match current {
+            Some(ref current) if current == &r.sha => {}
+            Some(_) if !force && local.starts_with("refs/tags/") => {
+                eprintln!("! [rejected] {} (would clobber existing tag)", local);
+            }
+            // Either side not being a commit makes it no fast-forward.
+            Some(ref current) if !force && !is_ancestor(repo, current, &r.sha).unwrap_or(false) => {
+                eprintln!("! [rejected] {} (non-fast-forward)", local);
+            }
+            _ => {
+                refs::update_ref(
+                    repo.root(),
+                    &local,
+                    &r.sha,
+                    Some(current.as_deref().unwrap_or("")),
+                )?;
+                updated.push(RefUpdate {
+                    name: local,
+                    remote_name: r.name,
+                    old: current,
+                    new: r.sha,
+                });
+            }
+        }
+    }
+    Ok(updated)
+}
+
+/// Adds a linked working tree at `path`, with `rev` checked out: a branch,
+/// or else a commit to detach HEAD at. Its `.git` is a file naming its own
+/// git directory, `.git/worktrees/<name>`, which keeps its HEAD and index.
+/// A branch can't be checked out in two working trees at once.
+fn worktree_add(repo: &Repository, path: &str, rev: &str) -> GitResult<()> {
+    let worktree = Path::new(path);
+    if worktree.exists() && fs::read_dir(worktree)?.next().is_some() {
+        return Err(GitError(format!("'{}' already exists", path)));
+    }
+    let branch = format!("refs/heads/{}", rev);
+    let (head, sha) = match refs::read_ref(repo.root(), &branch)? {
+        Some(sha) => {
+            if let Some(used_by) = worktree_with_branch(repo, &branch)? {
+                return Err(GitError(format!(
+                    "'{}' is already used by worktree at '{}'",
+                    rev,
+                    used_by.display()
+                )));
+            }
+            (Head::Branch(branch), sha)
+        }
+        None => {
+            let sha = peel_to(repo, &resolve_object(repo, rev)?, "commit")?;
+            (Head::Detached(sha.clone()), sha)
+        }
+    };
+
+    // The git directory is named after the working tree, made unique.
+    let worktrees = git_path(repo.root(), "worktrees");
+    let base = worktree
+        .file_name()
+        .ok_or_else(|| GitError(format!("Invalid worktree path: {}", path)))?
+        .to_string_lossy()
+        .into_owned();
+    let mut name = base.clone();
+    for n in 1.. {
+        if !worktrees.join(&name).exists() {
+            break;
+        }
+        name = format!("{}{}", base, n);
+    }
+    fs::create_dir_all(worktrees.join(&name))?;
+    fs::create_dir_all(worktree)?;
+    let git_dir = fs::canonicalize(worktrees.join(&name))?;
+    let worktree = fs::canonicalize(worktree)?;
+    fs::write(
+        worktree.join(".git"),
+        format!("gitdir: {}\n", git_dir.display()),
+    )?;
+    fs::write(
+        git_dir.join("gitdir"),
+        format!("{}\n", worktree.join(".git").display()),
+    )?;
+    fs::write(git_dir.join("commondir"), "../..\n")?;
+
+    let linked = Repository::open(worktree.to_str().ok_or("Could not get a file path")?)?;
+    linked.set_head(&head)?;
+    match &head {
+        Head::Branch(_) => report(
+            repo,
+            &format!("Preparing worktree (checking out '{}')", rev),
+        ),
+        Head::Detached(sha) => report(
+            repo,
+            &format!("Preparing worktree (detached HEAD {})", &sha[..7]),
+        ),
+    }
+    checkout_commit(&linked, None, &sha)
+}
+
+/// Finds the working tree that has `branch` checked out, if one does: the
+/// main one, or one of those linked to it.
+fn worktree_with_branch(repo: &Repository, branch: &str) -> GitResult<Option<PathBuf>> {
+    let common = git_path(repo.root(), "");
+    let main = common.parent().map(Path::to_path_buf).unwrap_or_default();
+    let mut heads = vec![(common.join("HEAD"), main)];
+    if let Ok(entries) = fs::read_dir(common.join("worktrees")) {
+        for entry in entries {
+            let git_dir = entry?.path();
+            // `gitdir` names the linked working tree's `.git` file.
+            let dot_git = fs::read_to_string(git_dir.join("gitdir")).unwrap_or_default();
+            let worktree = Path::new(dot_git.trim_end())
+                .parent()
+                .map(Path::to_path_buf);
+            heads.push((git_dir.join("HEAD"), worktree.unwrap_or_default()));
+        }
     }
+    for (head, worktree) in heads {
+        let head = fs::read_to_string(head).unwrap_or_default();
+        if head.trim_end().strip_prefix("ref: ") == Some(branch) {
+            return Ok(Some(fs::canonicalize(&worktree).unwrap_or(worktree)));
+        }
+    }
+    Ok(None)
+}
 
-    refs.sort_by(|a, b| a.name.cmp(&b.name));
-    write_object(".", &Object::Tree(refs))
+fn checkout_commit(repo: &Repository, from: Option<&str>, to: &str) -> GitResult<()> {
+    let from = match from {
+        Some(from) => Some(commit_tree(repo, from)?),
+        None => None,
+    };
+    checkout_tree(repo, from.as_deref(), &commit_tree(repo, to)?)
 }
 
-fn read_object(sha: &str) -> GitResult<Object> {
-    let path = format!("./.git/objects/{}/{}", &sha[0..2], &sha[2..]);
-    let bytes = fs::read(path)?;
-    let (_, content) = zlib::read(Bytes::from(bytes))?;
-    Object::decode(content)
+/// Swaps the files of tree `from` in the working tree for those of `to`,
+/// refusing to overwrite local changes to any file that would be touched.
+/// Files marked skip-worktree in the index are left alone. The index is
+/// updated to match `to`.
+fn checkout_tree(repo: &Repository, from: Option<&str>, to: &str) -> GitResult<()> {
+    let old = match from {
+        Some(from) => tree_blobs(repo, from, Path::new(""))?,
+        None => BTreeMap::new(),
+    };
+    let new = tree_blobs(repo, to, Path::new(""))?;
+    if repo.ignore_case {
+        let mut seen: HashMap<String, &PathBuf> = HashMap::new();
+        for path in new.keys() {
+            let folded = path.to_string_lossy().to_lowercase();
+            if let Some(other) = seen.insert(folded, path) {
+                eprintln!(
+                    "warning: {} and {} differ only in case; only one of them can be checked out",
+                    other.display(),
+                    path.display()
+                );
+            }
+        }
+    }
+    let index_path = repo.index_path();
+    let index = Index::read(&index_path)?;
+    let cone = sparse::read(repo.root())?;
+    let outside_cone = |path: &str| cone.as_ref().is_some_and(|cone| !cone.contains(path));
+    let skipped: HashSet<&str> = index
+        .entries
+        .iter()
+        .filter(|e| e.skip_worktree())
+        .map(|e| e.path.as_str())
+        .collect();
+
+    let changed: Vec<&PathBuf> = old
+        .keys()
+        .chain(new.keys())
+        .filter(|path| old.get(*path) != new.get(*path))
+        .filter(|path| {
+            !path
+                .to_str()
+                .is_some_and(|path| skipped.contains(path) || outside_cone(path))
+        })
+        .collect();
+    let root = Path::new(repo.root());
+    for path in &changed {
+        // A symlink is compared by its target, as it's stored.
+        let on_disk = match worktree_content(&root.join(path)) {
+            Ok(content) => Some(object::hash_content("blob", &content)),
+            Err(_) => None,
+        };
+        if on_disk.is_some() && on_disk.as_ref() != old.get(*path).map(|(_, sha)| sha) {
+            return Err(GitError(format!(
+                "Your local changes to {} would be overwritten by checkout",
+                path.display()
+            )));
+        }
+    }
+
+    for path in changed {
+        match new.get(path) {
+            Some((mode, sha)) => {
+                checkout_file(repo, path, u32::from_str_radix(&mode.to_string(), 8)?, sha)?
+            }
+            None => remove_file(repo, path)?,
+        }
+    }
+
+    // Entries for files that didn't change are kept, stat data and flags
+    // included, so they don't look modified afterwards.
+    let kept: HashMap<&str, &IndexEntry> = index
+        .entries
+        .iter()
+        .filter(|e| e.stage() == 0)
+        .map(|e| (e.path.as_str(), e))
+        .collect();
+    let mut entries = Vec::with_capacity(new.len());
+    for (path, (mode, sha)) in &new {
+        let name = path.to_str().ok_or("Could not get a file path")?;
+        let mode = u32::from_str_radix(&mode.to_string(), 8)?;
+        let entry = match kept.get(name) {
+            Some(e) if e.sha == *sha && e.mode == mode => (*e).clone(),
+            Some(e) if e.skip_worktree() => IndexEntry {
+                mode,
+                sha: *sha,
+                ..(*e).clone()
+            },
+            _ if outside_cone(name) => {
+                let mut entry = IndexEntry {
+                    mode,
+                    sha: *sha,
+                    path: name.to_owned(),
+                    ..Default::default()
+                };
+                entry.set_skip_worktree(true);
+                entry
+            }
+            _ => IndexEntry {
+                mode,
+                ..IndexEntry::from_file(&root.join(path), name, *sha)?
+            },
+        };
+        entries.push(entry);
+    }
+    // Sort as the index does, which isn't quite how paths compare.
+    entries.sort_by(|a, b| a.path.as_bytes().cmp(b.path.as_bytes()));
+    Index {
+        entries,
+        cache_tree: None,
+    }
+    .write(&index_path)
 }
 
-fn write_object(root: &str, obj: &Object) -> GitResult<Sha> {
-    let (hash, data) = obj.encode();
-    let result = zlib::write(&data)?;
-    let hex = object::to_hex(&hash);
+/// Writes the blob `sha` to `path` in the working tree, as a file with the
+/// given mode or, where `core.symlinks` allows, a symlink.
+fn checkout_file(repo: &Repository, path: &Path, mode: u32, sha: &Sha) -> GitResult<()> {
+    let root = Path::new(repo.root());
+    let file = root.join(path);
+    // A symlink where a leading directory should be would have the file
+    // written wherever it points.
+    let mut dirs: Vec<&Path> = path.ancestors().skip(1).collect();
+    dirs.pop();
+    for dir in dirs.into_iter().rev() {
+        if fs::symlink_metadata(root.join(dir)).is_ok_and(|m| m.file_type().is_symlink()) {
+            return Err(GitError(format!(
+                "{}: beyond a symbolic link",
+                path.display()
+            )));
+        }
+    }
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let (kind, size, mut content) = repo.open_object(&object::to_hex(sha))?;
+    if kind != "blob" {
+        return Err(GitError(format!("Not a blob: {}", path.display())));
+    }
+    if fs::symlink_metadata(&file).is_ok() {
+        fs::remove_file(&file)?;
+    }
+    if mode == 0o120000 && repo.symlinks {
+        let mut target = String::new();
+        content.read_to_string(&mut target)?;
+        std::os::unix::fs::symlink(target, &file)?;
+        return Ok(());
+    }
+    // Blobs are streamed, so one larger than memory can still be checked out.
+    let written = io::copy(&mut content, &mut File::create(&file)?)?;
+    if written != size as u64 {
+        return Err(GitError(format!(
+            "Object {} is truncated: expected {} bytes, got {}",
+            object::to_hex(sha),
+            size,
+            written
+        )));
+    }
+    let permissions = if mode == 0o100755 { 0o755 } else { 0o644 };
+    fs::set_permissions(&file, fs::Permissions::from_mode(permissions))?;
+    Ok(())
+}
 
-    let dir = format!("{}/.git/objects/{}", root, &hex[0..2]);
-    if fs::metadata(&dir).is_err() {
-        fs::create_dir(&dir)?;
+/// Removes `path` from the working tree, along with the directories that
+/// leaves empty, as git does.
+fn remove_file(repo: &Repository, path: &Path) -> GitResult<()> {
+    let root = Path::new(repo.root());
+    if fs::symlink_metadata(root.join(path)).is_ok() {
+        fs::remove_file(root.join(path))?;
     }
-    let path = format!("{}/{}", dir, &hex[2..]);
-    if fs::metadata(&path).is_err() {
-        fs::write(path, result)?;
+    for dir in path.ancestors().skip(1) {
+        if dir.as_os_str().is_empty() || fs::remove_dir(root.join(dir)).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Narrows the working tree down to the cone of `dirs`: entries outside it
+/// are marked skip-worktree and their files removed, unless they have local
+/// changes, and entries inside it are brought back.
+fn sparse_checkout(repo: &Repository, dirs: &[String]) -> GitResult<()> {
+    let cone = sparse::Cone::new(dirs);
+    let index_path = repo.index_path();
+    let mut index = Index::read(&index_path)?;
+    let root = Path::new(repo.root());
+    for entry in index.entries.iter_mut().filter(|e| e.stage() == 0) {
+        let path = Path::new(&entry.path);
+        match (cone.contains(&entry.path), entry.skip_worktree()) {
+            (true, true) => {
+                checkout_file(repo, path, entry.mode, &entry.sha)?;
+                *entry = IndexEntry {
+                    mode: entry.mode,
+                    ..IndexEntry::from_file(&root.join(path), &entry.path, entry.sha)?
+                };
+            }
+            (false, false) => {
+                if worktree_status(root, entry)? == 'M' {
+                    eprintln!(
+                        "warning: not removing {}, which has local changes",
+                        entry.path
+                    );
+                    continue;
+                }
+                remove_file(repo, path)?;
+                entry.set_skip_worktree(true);
+            }
+            _ => {}
+        }
+    }
+    index.write(&index_path)?;
+    sparse::write(repo.root(), &cone)
+}
+
+fn commit_tree(repo: &Repository, sha: &str) -> GitResult<String> {
+    match peel(repo, sha)?.1 {
+        Object::Commit { tree, .. } => Ok(tree),
+        _ => Err(GitError(format!("Not a commit: {}", sha))),
+    }
+}
+
+/// Lists the files under a tree by path, with their modes, skipping submodules.
+fn tree_blobs(
+    store: &dyn ObjectStore,
+    tree: &str,
+    prefix: &Path,
+) -> GitResult<BTreeMap<PathBuf, (usize, Sha)>> {
+    let refs = match store.read_object(tree)? {
+        Object::Tree(refs) => refs,
+        _ => return Err(GitError(format!("Not a tree: {}", tree))),
+    };
+    let mut blobs = BTreeMap::new();
+    for r in refs {
+        // Trees can come from anywhere; their names become paths on disk.
+        object::check_entry_name(&r.name)?;
+        let path = prefix.join(OsStr::from_bytes(&r.name));
+        match r.mode {
+            40000 => blobs.extend(tree_blobs(store, &object::to_hex(&r.hash), &path)?),
+            160000 => {}
+            _ => {
+                blobs.insert(path, (r.mode, r.hash));
+            }
+        }
+    }
+    Ok(blobs)
+}
+
+/// Records the index as a commit on top of HEAD. Unless `verify` is off, the
+/// `pre-commit` hook may stop the commit, and `commit-msg` may stop it or
+/// edit the message in `.git/COMMIT_EDITMSG`.
+fn commit(repo: &Repository, message: &str, verify: bool, allow_empty: bool) -> GitResult<()> {
+    if verify {
+        run_hook(repo, "pre-commit", &[])?;
+    }
+    let message_file = git_path(repo.root(), "COMMIT_EDITMSG");
+    fs::write(&message_file, format!("{}\n", message))?;
+    if verify {
+        let path = message_file.to_str().ok_or("Could not get a file path")?;
+        run_hook(repo, "commit-msg", &[path])?;
+    }
+    // Like git, drop comment lines and surrounding blank lines.
+    let edited = fs::read_to_string(&message_file)?;
+    let lines: Vec<&str> = edited
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(str::trim_end)
+        .collect();
+    let message = lines.join("\n").trim_matches('\n').to_owned();
+    if message.is_empty() {
+        return Err(GitError(
+            "Aborting commit due to empty commit message.".to_owned(),
+        ));
+    }
+
+    let tree = object::to_hex(&write_index_tree(repo)?);
+    let head = repo.head()?;
+    let parent = repo.head_commit()?;
+    // Like git, a commit has to change something unless asked not to.
+    if !allow_empty {
+        let unchanged = match &parent {
+            Some(parent) => commit_tree(repo, parent)? == tree,
+            None => tree == object::to_hex(&Object::Tree(Vec::new()).encode()?.0),
+        };
+        if unchanged {
+            return Err(GitError(
+                "nothing to commit (use --allow-empty to record a commit anyway)".to_owned(),
+            ));
+        }
+    }
+    let contributor = current_contributor(repo, "COMMITTER")?;
+    let sha = object::to_hex(&repo.write_object(&Object::Commit {
+        tree,
+        parents: parent.iter().cloned().collect(),
+        author: current_contributor(repo, "AUTHOR")?,
+        committer: contributor.clone(),
+        message: Bytes::from(format!("{}\n", message)),
+        crlf_headers: false,
+        extra_headers: Vec::new(),
+        blank_line: true,
+    })?);
+
+    let subject = message.lines().next().unwrap_or("");
+    let entry = refs::ReflogEntry {
+        old: parent.clone().unwrap_or_else(|| "0".repeat(40)),
+        new: sha.clone(),
+        identity: format!(
+            "{} <{}> {} {}",
+            contributor.name, contributor.email, contributor.timestamp, contributor.timezone
+        ),
+        message: match parent {
+            Some(_) => format!("commit: {}", subject),
+            None => format!("commit (initial): {}", subject),
+        },
+    };
+    // A detached HEAD moves itself; otherwise the branch moves with it.
+    let target = match &head {
+        Head::Branch(branch) => branch.as_str(),
+        Head::Detached(_) => "HEAD",
+    };
+    // Should another commit land first, fail rather than drop it.
+    refs::update_ref(repo.root(), target, &sha, Some(&entry.old))?;
+    refs::append_reflog(repo.root(), target, entry.clone())?;
+    if target != "HEAD" {
+        refs::append_reflog(repo.root(), "HEAD", entry)?;
+    }
+    report(
+        repo,
+        &format!(
+            "[{}{} {}] {}",
+            head.branch_name().unwrap_or("detached HEAD"),
+            if parent.is_none() {
+                " (root-commit)"
+            } else {
+                ""
+            },
+            &sha[..7],
+            subject
+        ),
+    );
+    Ok(())
+}
+
+/// Runs the hook `name` in the working tree, failing if it exits non-zero.
+/// Hooks are looked for in `core.hooksPath`, relative to the working tree,
+/// or else in `.git/hooks`. A hook that's missing or not executable is
+/// skipped.
+fn run_hook(repo: &Repository, name: &str, args: &[&str]) -> GitResult<()> {
+    let root = Path::new(repo.root());
+    let hooks = match Config::load(repo.root())?.get_path("core", None, "hookspath") {
+        Some(dir) => root.join(dir),
+        None => git_path(repo.root(), "hooks"),
+    };
+    let hook = hooks.join(name);
+    match fs::metadata(&hook) {
+        Ok(metadata) if metadata.permissions().mode() & 0o111 != 0 => {}
+        Ok(_) => {
+            eprintln!(
+                "hint: The '{}' hook was ignored because it's not set as executable.",
+                hook.display()
+            );
+            return Ok(());
+        }
+        Err(_) => return Ok(()),
+    }
+    let status = Command::new(fs::canonicalize(&hook)?)
+        .args(args)
+        .current_dir(repo.root())
+        .env(
+            "GIT_INDEX_FILE",
+            env::current_dir()?.join(repo.index_path()),
+        )
+        .status()?;
+    if !status.success() {
+        return Err(GitError(format!("The {} hook stopped the commit", name)));
+    }
+    Ok(())
+}
+
+/// Saves the local changes as a stash commit under `refs/stash` and resets
+/// the working tree and index to HEAD. Like git's, the stash commit has the
+/// working tree as its tree, and HEAD and a commit of the index as parents.
+fn stash_push(repo: &Repository) -> GitResult<()> {
+    let head = repo
+        .head_commit()?
+        .ok_or("You do not have the initial commit yet")?;
+    let head_tree = commit_tree(repo, &head)?;
+    let index_tree = if repo.index_path().exists() {
+        object::to_hex(&write_index_tree(repo)?)
+    } else {
+        head_tree.clone()
+    };
+
+    // The working tree's versions of the tracked files, untracked ones aside.
+    let index = Index::read(&repo.index_path())?;
+    let mut worktree = Index {
+        entries: index.entries.clone(),
+        cache_tree: None,
+    };
+    for entry in index.entries.iter().filter(|e| !e.skip_worktree()) {
+        add_path(repo, &mut worktree, &entry.path)?;
+    }
+    let worktree_tree = object::to_hex(&write_cached_tree(
+        repo,
+        &worktree.entries,
+        "",
+        &mut CacheTree::default(),
+    )?);
+    if index_tree == head_tree && worktree_tree == head_tree {
+        report(repo, "No local changes to save");
+        return Ok(());
+    }
+
+    let branch = repo
+        .head()?
+        .branch_name()
+        .unwrap_or("(no branch)")
+        .to_owned();
+    let subject = match repo.read_object(&head)? {
+        Object::Commit { message, .. } => String::from_utf8_lossy(&message)
+            .lines()
+            .next()
+            .unwrap_or("")
+            .to_owned(),
+        _ => return Err(GitError(format!("Not a commit: {}", head))),
+    };
+    let description = format!("{}: {} {}", branch, &head[..7], subject);
+    let author = current_contributor(repo, "AUTHOR")?;
+    let contributor = current_contributor(repo, "COMMITTER")?;
+    let commit = |tree: &str, parents: Vec<String>, message: String| {
+        repo.write_object(&Object::Commit {
+            tree: tree.to_owned(),
+            parents,
+            author: author.clone(),
+            committer: contributor.clone(),
+            message: message.into(),
+            crlf_headers: false,
+            extra_headers: Vec::new(),
+            blank_line: true,
+        })
+        .map(|sha| object::to_hex(&sha))
+    };
+    let index_commit = commit(
+        &index_tree,
+        vec![head.clone()],
+        format!("index on {}\n", description),
+    )?;
+    let message = format!("WIP on {}", description);
+    let stash = commit(
+        &worktree_tree,
+        vec![head.clone(), index_commit],
+        format!("{}\n", message),
+    )?;
+
+    let old = refs::read_ref(repo.root(), "refs/stash")?;
+    refs::write_ref(repo.root(), "refs/stash", &stash)?;
+    refs::append_reflog(
+        repo.root(),
+        "refs/stash",
+        refs::ReflogEntry {
+            old: old.unwrap_or_else(|| "0".repeat(40)),
+            new: stash.clone(),
+            identity: format!(
+                "{} <{}> {} {}",
+                contributor.name, contributor.email, contributor.timestamp, contributor.timezone
+            ),
+            message: message.clone(),
+        },
+    )?;
+
+    // Going from the stashed tree, which is what's on disk, puts back every
+    // file the stash changed.
+    checkout_tree(repo, Some(&worktree_tree), &head_tree)?;
+    report(
+        repo,
+        &format!("Saved working directory and index state {}", message),
+    );
+    Ok(())
+}
+
+/// Applies the latest stash to the working tree and drops it. Each file is
+/// merged on its own: a stash can't be applied over a file that changed both
+/// in it and since it was made.
+fn stash_pop(repo: &Repository) -> GitResult<()> {
+    let stash = refs::read_ref(repo.root(), "refs/stash")?.ok_or("No stash entries found.")?;
+    let base = match repo.read_object(&stash)? {
+        Object::Commit { parents, .. } => parents.first().cloned().ok_or("Invalid stash commit")?,
+        _ => return Err(GitError(format!("Not a commit: {}", stash))),
+    };
+    let head = repo
+        .head_commit()?
+        .ok_or("No commit to apply the stash on")?;
+    let head_tree = commit_tree(repo, &head)?;
+    let base_files = tree_blobs(repo, &commit_tree(repo, &base)?, Path::new(""))?;
+    let ours = tree_blobs(repo, &head_tree, Path::new(""))?;
+    let theirs = tree_blobs(repo, &commit_tree(repo, &stash)?, Path::new(""))?;
+
+    let mut merged = ours.clone();
+    let paths: BTreeSet<&PathBuf> = base_files.keys().chain(theirs.keys()).collect();
+    for path in paths {
+        let (base, ours, theirs) = (base_files.get(path), ours.get(path), theirs.get(path));
+        if theirs == base || theirs == ours {
+            continue;
+        }
+        if ours != base {
+            return Err(GitError(format!(
+                "{} has changed since the stash was made; not applying it",
+                path.display()
+            )));
+        }
+        match theirs {
+            Some(file) => merged.insert(path.clone(), *file),
+            None => merged.remove(path),
+        };
+    }
+    let mut entries = Vec::new();
+    for (path, (mode, sha)) in &merged {
+        entries.push(IndexEntry {
+            mode: u32::from_str_radix(&mode.to_string(), 8)?,
+            sha: *sha,
+            path: path.to_str().ok_or("Could not get a file path")?.to_owned(),
+            ..Default::default()
+        });
+    }
+    entries.sort_by(|a, b| a.path.as_bytes().cmp(b.path.as_bytes()));
+    let merged_tree = write_cached_tree(repo, &entries, "", &mut CacheTree::default())?;
+    checkout_tree(repo, Some(&head_tree), &object::to_hex(&merged_tree))?;
+
+    // As with git, the changes come back unstaged, apart from new files.
+    let index_path = repo.index_path();
+    let mut index = Index::read(&index_path)?;
+    for (path, (mode, sha)) in &ours {
+        let path = path.to_str().ok_or("Could not get a file path")?;
+        let staged = index.entries.iter().find(|e| e.path == path).map(|e| e.sha);
+        if staged != Some(*sha) {
+            index.add(IndexEntry {
+                mode: u32::from_str_radix(&mode.to_string(), 8)?,
+                sha: *sha,
+                path: path.to_owned(),
+                ..Default::default()
+            });
+        }
+    }
+    index.write(&index_path)?;
+
+    let mut reflog = refs::read_reflog(repo.root(), "refs/stash")?;
+    reflog.pop();
+    match reflog.last() {
+        Some(previous) => {
+            refs::write_ref(repo.root(), "refs/stash", &previous.new)?;
+            refs::write_reflog(repo.root(), "refs/stash", &reflog)?;
+        }
+        None => refs::delete_ref(repo.root(), "refs/stash")?,
+    }
+    report(repo, &format!("Dropped refs/stash@{{0}} ({})", stash));
+    Ok(())
+}
+
+/// Prints the stashes, newest first.
+fn stash_list(repo: &Repository) -> GitResult<()> {
+    for (i, entry) in refs::read_reflog(repo.root(), "refs/stash")?
+        .iter()
+        .rev()
+        .enumerate()
+    {
+        println!("stash@{{{}}}: {}", i, entry.message);
+    }
+    Ok(())
+}
+
+/// Prints how HEAD, the index and the working tree differ in the short
+/// format, `XY <path>`, followed by untracked files as `?? <path>`. Files
+/// marked assume-unchanged or skip-worktree aren't looked at.
+fn status(repo: &Repository) -> GitResult<()> {
+    let head = match repo.head_commit()? {
+        Some(sha) => tree_blobs(repo, &commit_tree(repo, &sha)?, Path::new(""))?,
+        None => BTreeMap::new(),
+    };
+    let index = Index::read(&repo.index_path())?;
+    let root = Path::new(repo.root());
+    if let Head::Branch(branch) = repo.head()? {
+        let short_name = branch.trim_start_matches("refs/heads/");
+        println!("On branch {}", short_name);
+        print_tracking(repo, &branch, short_name)?;
+    }
+
+    let mut changes = BTreeMap::new();
+    for entry in &index.entries {
+        if entry.stage() != 0 {
+            changes.insert(entry.path.clone(), ('U', 'U'));
+            continue;
+        }
+        let staged = match head.get(Path::new(&entry.path)) {
+            None => 'A',
+            Some((mode, sha))
+                if *sha != entry.sha || format!("{:o}", entry.mode) != mode.to_string() =>
+            {
+                'M'
+            }
+            Some(_) => ' ',
+        };
+        let unstaged = if entry.assume_unchanged() || entry.skip_worktree() {
+            ' '
+        } else {
+            worktree_status(root, entry)?
+        };
+        if (staged, unstaged) != (' ', ' ') {
+            changes.insert(entry.path.clone(), (staged, unstaged));
+        }
+    }
+    let tracked: HashSet<&str> = index.entries.iter().map(|e| e.path.as_str()).collect();
+    for path in head.keys() {
+        let path = path.to_str().ok_or("Could not get a file path")?;
+        if !tracked.contains(path) {
+            changes.insert(path.to_owned(), ('D', ' '));
+        }
+    }
+
+    // A deleted file that is like one added to the index was renamed.
+    let deleted: Vec<&PathBuf> = head
+        .keys()
+        .filter(|path| changes.get(path.to_str().unwrap_or("")) == Some(&('D', ' ')))
+        .collect();
+    let added: Vec<&IndexEntry> = index
+        .entries
+        .iter()
+        .filter(|entry| changes.get(&entry.path).map(|c| c.0) == Some('A'))
+        .collect();
+    let mut renamed_from = HashMap::new();
+    for (old, new, _) in find_renames(
+        repo,
+        &deleted.iter().map(|path| head[*path].1).collect::<Vec<_>>(),
+        &added.iter().map(|entry| entry.sha).collect::<Vec<_>>(),
+    )? {
+        let old_path = deleted[old].to_string_lossy().into_owned();
+        changes.remove(&old_path);
+        if let Some(change) = changes.get_mut(&added[new].path) {
+            change.0 = 'R';
+        }
+        renamed_from.insert(added[new].path.clone(), old_path);
+    }
+    for (path, (staged, unstaged)) in changes {
+        match renamed_from.get(&path) {
+            Some(old_path) => println!("{}{} {} -> {}", staged, unstaged, old_path, path),
+            None => println!("{}{} {}", staged, unstaged, path),
+        }
+    }
+
+    let mut dirs = HashSet::new();
+    for path in &tracked {
+        dirs.extend(Path::new(path).ancestors().skip(1).map(Path::to_path_buf));
+    }
+    let mut untracked = Vec::new();
+    list_untracked(root, Path::new(""), &tracked, &dirs, &mut untracked)?;
+    untracked.sort();
+    for path in untracked {
+        println!("?? {}", path);
+    }
+    Ok(())
+}
+
+/// Tells how a branch compares to the one it tracks, in git's words.
+fn print_tracking(repo: &Repository, branch: &str, short_name: &str) -> GitResult<()> {
+    let config = Config::load(repo.root())?;
+    let (remote_name, merge) = match branch_upstream(&config, short_name) {
+        Some(upstream) => upstream,
+        None => return Ok(()),
+    };
+    let tracking = tracking_ref(&config, &remote_name, &merge)?;
+    let name = tracking.trim_start_matches("refs/remotes/");
+    let (local, upstream) = match (
+        refs::read_ref(repo.root(), branch)?,
+        refs::read_ref(repo.root(), &tracking)?,
+    ) {
+        (Some(local), Some(upstream)) => (local, upstream),
+        (_, None) => {
+            println!(
+                "Your branch is based on '{}', but the upstream is gone.",
+                name
+            );
+            return Ok(());
+        }
+        (None, _) => return Ok(()),
+    };
+    let commits = |n: usize| if n == 1 { "commit" } else { "commits" };
+    match ahead_behind(repo, &local, &upstream)? {
+        (0, 0) => println!("Your branch is up to date with '{}'.", name),
+        (ahead, 0) => println!(
+            "Your branch is ahead of '{}' by {} {}.",
+            name,
+            ahead,
+            commits(ahead)
+        ),
+        (0, behind) => println!(
+            "Your branch is behind '{}' by {} {}, and can be fast-forwarded.",
+            name,
+            behind,
+            commits(behind)
+        ),
+        (ahead, behind) => println!(
+            "Your branch and '{}' have diverged,\nand have {} and {} different commits each, respectively.",
+            name, ahead, behind
+        ),
+    }
+    Ok(())
+}
+
+/// How the working tree copy of an entry differs from it: ` `, `M` or `D`.
+/// The file is only read when its metadata has changed.
+fn worktree_status(root: &Path, entry: &IndexEntry) -> GitResult<char> {
+    let file = root.join(&entry.path);
+    let metadata = match fs::symlink_metadata(&file) {
+        Ok(metadata) => metadata,
+        Err(_) => return Ok('D'),
+    };
+    if entry.matches_stat(&metadata) {
+        return Ok(' ');
+    }
+    let executable = metadata.permissions().mode() & 0o111 != 0;
+    if !metadata.file_type().is_symlink() && executable != (entry.mode == 0o100755) {
+        return Ok('M');
+    }
+    let content = worktree_content(&file)?;
+    let (sha, _) = Object::Blob(Bytes::from(content)).encode()?;
+    Ok(if sha == entry.sha { ' ' } else { 'M' })
+}
+
+/// Collects the files under `dir` that aren't tracked. A directory with
+/// nothing tracked in it is listed as a whole, as `<dir>/`.
+fn list_untracked(
+    root: &Path,
+    dir: &Path,
+    tracked: &HashSet<&str>,
+    dirs: &HashSet<PathBuf>,
+    untracked: &mut Vec<String>,
+) -> GitResult<()> {
+    for entry in fs::read_dir(root.join(dir))? {
+        let entry = entry?;
+        let path = dir.join(entry.file_name());
+        let name = path.to_str().ok_or("Could not get a file path")?;
+        if name == ".git" {
+            continue;
+        }
+        if !entry.file_type()?.is_dir() {
+            if !tracked.contains(name) {
+                untracked.push(name.to_owned());
+            }
+        } else if dirs.contains(&path) {
+            list_untracked(root, &path, tracked, dirs, untracked)?;
+        } else if has_files(&root.join(&path))? {
+            untracked.push(format!("{}/", name));
+        }
+    }
+    Ok(())
+}
+
+/// Whether there's a file anywhere under `dir`, which git needs to list it.
+fn has_files(dir: &Path) -> GitResult<bool> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() || has_files(&entry.path())? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Prints `path:lineno:line` for each line matching `pattern` in the files of
+/// `rev`, which may name a commit or a tree. Binary files are skipped.
+fn grep(repo: &Repository, pattern: &Pattern, rev: &str) -> GitResult<()> {
+    let sha = resolve_object(repo, rev)?;
+    let tree = match repo.read_object(&sha)? {
+        Object::Commit { tree, .. } => tree,
+        Object::Tree(_) => sha,
+        _ => return Err(GitError(format!("Not a tree-ish: {}", rev))),
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for (path, (_, hash)) in tree_blobs(repo, &tree, Path::new(""))? {
+        let blob = repo.read_object(&object::to_hex(&hash))?;
+        if is_binary_file(repo, &path.to_string_lossy(), &blob.body()?) {
+            continue;
+        }
+        for (n, line) in blob.blob_lines().enumerate() {
+            if pattern.is_match(line) {
+                write!(out, "{}:{}:", path.display(), n + 1)?;
+                out.write_all(line)?;
+                out.write_all(b"\n")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether the file at `path` with the given content is binary, as
+/// `.gitattributes` says or else going by its content.
+fn is_binary_file(repo: &Repository, path: &str, content: &[u8]) -> bool {
+    attributes::binary_override(repo.root(), path).unwrap_or_else(|| object::is_binary(content))
+}
+
+/// Lists refs with their SHAs, loose and packed alike, failing if none are
+/// found. `--heads` and `--tags` keep to branches and tags, and patterns to
+/// refs ending in them. `--head` adds HEAD, `-d` follows each annotated tag
+/// with `<ref>^{}` and what it peels to, and `-s` leaves out the names.
+fn show_ref(repo: &Repository, args: &[String]) -> GitResult<()> {
+    let has = |flags: &[&str]| args.iter().any(|a| flags.contains(&a.as_str()));
+    let (heads, tags) = (has(&["--heads"]), has(&["--tags"]));
+    let dereference = has(&["-d", "--dereference"]);
+    let hash_only = has(&["-s", "--hash"]);
+    let patterns: Vec<&String> = args.iter().filter(|a| !a.starts_with('-')).collect();
+
+    let mut refs: Vec<(String, String)> = Vec::new();
+    if has(&["--head"]) {
+        if let Some(sha) = repo.head_commit()? {
+            refs.push(("HEAD".to_owned(), sha));
+        }
+    }
+    refs.extend(refs::list_refs(repo.root(), "refs/")?);
+    refs.retain(|(name, _)| {
+        let kind_matches = (!heads && !tags)
+            || name == "HEAD"
+            || (heads && name.starts_with("refs/heads/"))
+            || (tags && name.starts_with("refs/tags/"));
+        let pattern_matches = patterns.is_empty()
+            || patterns
+                .iter()
+                .any(|p| name == *p || name.ends_with(&format!("/{}", p)));
+        kind_matches && pattern_matches
+    });
+    if refs.is_empty() {
+        std::process::exit(1);
+    }
+    if repo.quiet {
+        return Ok(());
+    }
+    for (name, sha) in refs {
+        let mut lines = vec![(sha.clone(), name.clone())];
+        if dereference {
+            if let Object::Tag { .. } = repo.read_object(&sha)? {
+                lines.push((peel(repo, &sha)?.0, format!("{}^{{}}", name)));
+            }
+        }
+        for (sha, name) in lines {
+            if hash_only {
+                println!("{}", sha);
+            } else {
+                println!("{} {}", sha, name);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Names a commit after the nearest tag it descends from, as
+/// `<tag>-<n>-g<abbrev>` where n counts the commits made since the tag, or
+/// just `<tag>` if the commit is tagged. Only annotated tags are considered
+/// unless `all_tags` is set; `always` falls back to the abbreviated SHA.
+fn describe(repo: &Repository, sha: &str, all_tags: bool, always: bool) -> GitResult<String> {
+    // The tags by the commit they name, peeling tags down to it and skipping
+    // other objects. Where a commit has several, the first by name wins.
+    let mut tags = HashMap::new();
+    for (name, tag_sha) in refs::list_refs(repo.root(), "refs/tags/")? {
+        let annotated = matches!(repo.read_object(&tag_sha)?, Object::Tag { .. });
+        if let (commit, Object::Commit { .. }) = peel(repo, &tag_sha)? {
+            if annotated || all_tags {
+                tags.entry(commit)
+                    .or_insert_with(|| name["refs/tags/".len()..].to_owned());
+            }
+        }
+    }
+
+    // Walk back from the commit nearest first, stopping at the first one
+    // that's tagged.
+    let shallow = shallow_commits(repo)?;
+    let mut seen = HashSet::from([sha.to_owned()]);
+    let mut pending = VecDeque::from([sha.to_owned()]);
+    let mut best = None;
+    while let Some(commit) = pending.pop_front() {
+        if let Some(tag) = tags.get(&commit) {
+            best = Some((commit, tag));
+            break;
+        }
+        match repo.read_object(&commit)? {
+            Object::Commit { .. } if shallow.contains(&commit) => {}
+            Object::Commit { parents, .. } => {
+                for parent in parents {
+                    if seen.insert(parent.clone()) {
+                        pending.push_back(parent);
+                    }
+                }
+            }
+            _ => return Err(GitError(format!("Not a commit: {}", commit))),
+        }
+    }
+
+    match best {
+        Some((commit, tag)) if commit == sha => Ok(tag.clone()),
+        Some((commit, tag)) => {
+            let distance = commits_since(repo, sha, &commit)?;
+            Ok(format!("{}-{}-g{}", tag, distance, &sha[..7]))
+        }
+        None if always => Ok(sha[..7].to_owned()),
+        None => Err(GitError(format!("No tags can describe {}", sha))),
+    }
+}
+
+/// Counts the commits reachable from `sha` but not from `base`, as in the
+/// `<tag>-<n>-g<sha>` that `describe` gives.
+fn commits_since(repo: &Repository, sha: &str, base: &str) -> GitResult<usize> {
+    let shallow = shallow_commits(repo)?;
+    let base = ancestors(repo, base)?;
+    let mut seen = HashSet::new();
+    let mut pending = vec![sha.to_owned()];
+    while let Some(sha) = pending.pop() {
+        if base.contains(&sha) || !seen.insert(sha.clone()) {
+            continue;
+        }
+        match repo.read_object(&sha)? {
+            Object::Commit { .. } if shallow.contains(&sha) => {}
+            Object::Commit { parents, .. } => pending.extend(parents),
+            _ => return Err(GitError(format!("Not a commit: {}", sha))),
+        }
+    }
+    Ok(seen.len())
+}
+
+/// Collects a commit and everything reachable from it through its parents.
+fn ancestors(repo: &Repository, sha: &str) -> GitResult<HashSet<String>> {
+    let shallow = shallow_commits(repo)?;
+    let mut seen = HashSet::new();
+    let mut pending = vec![sha.to_owned()];
+    while let Some(sha) = pending.pop() {
+        if !seen.insert(sha.clone()) {
+            continue;
+        }
+        match repo.read_object(&sha)? {
+            Object::Commit { .. } if shallow.contains(&sha) => {}
+            Object::Commit { parents, .. } => pending.extend(parents),
+            _ => return Err(GitError(format!("Not a commit: {}", sha))),
+        }
+    }
+    Ok(seen)
+}
+
+/// Whether `ancestor` is reachable from `descendant` through its parents,
+/// counting a commit as its own ancestor.
+fn is_ancestor(repo: &Repository, ancestor: &str, descendant: &str) -> GitResult<bool> {
+    let shallow = shallow_commits(repo)?;
+    let mut seen = HashSet::new();
+    let mut pending = vec![descendant.to_owned()];
+    while let Some(sha) = pending.pop() {
+        if sha == ancestor {
+            return Ok(true);
+        }
+        if !seen.insert(sha.clone()) {
+            continue;
+        }
+        match repo.read_object(&sha)? {
+            Object::Commit { .. } if shallow.contains(&sha) => {}
+            Object::Commit { parents, .. } => pending.extend(parents),
+            _ => return Err(GitError(format!("Not a commit: {}", sha))),
+        }
+    }
+    Ok(false)
+}
+
+fn log(repo: &Repository, sha: &str) -> GitResult<()> {
+    // Authors show as `.mailmap` has them unless `log.mailmap` is off.
+    let mailmap = match Config::load(repo.root())?.get_bool("log", None, "mailmap")? {
+        Some(false) => Mailmap::default(),
+        _ => Mailmap::load(repo.root())?,
+    };
+    let mut first = true;
+    rev_walk(repo, &[sha.to_owned()], &HashSet::new(), |sha, commit| {
+        let (parents, author, message) = match commit {
+            Object::Commit {
+                parents,
+                author,
+                message,
+                ..
+            } => (parents, author, message),
+            _ => return Err(GitError(format!("Not a commit: {}", sha))),
+        };
+
+        if !first {
+            println!();
+        }
+        first = false;
+        println!("commit {}", sha);
+        if parents.len() > 1 {
+            let abbrevs: Vec<&str> = parents.iter().map(|p| &p[..7]).collect();
+            println!("Merge: {}", abbrevs.join(" "));
+        }
+        let author = mailmap.map(author);
+        println!("Author: {} <{}>", author.name, author.email);
+        println!("Date:   {}", author.format_date()?);
+        println!();
+        for line in String::from_utf8_lossy(message).lines() {
+            println!("    {}", line);
+        }
+        Ok(true)
+    })
+}
+
+/// Summarizes the history of `sha` by author, as `.mailmap` has them: each
+/// with their commit count and the subjects of their commits, oldest first.
+/// Authors are in name order, or with `numbered` most commits first. With
+/// `summary`, only the counts are shown; with `email`, authors' emails too.
+fn shortlog(
+    repo: &Repository,
+    sha: &str,
+    summary: bool,
+    numbered: bool,
+    email: bool,
+) -> GitResult<()> {
+    let mailmap = Mailmap::load(repo.root())?;
+    let mut authors: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    rev_walk(repo, &[sha.to_owned()], &HashSet::new(), |sha, commit| {
+        let (author, message) = match commit {
+            Object::Commit {
+                author, message, ..
+            } => (mailmap.map(author), String::from_utf8_lossy(message)),
+            _ => return Err(GitError(format!("Not a commit: {}", sha))),
+        };
+        let name = if email {
+            format!("{} <{}>", author.name, author.email)
+        } else {
+            author.name
+        };
+        // The subject is the first paragraph, joined onto one line.
+        let subject: Vec<&str> = message
+            .lines()
+            .map(str::trim)
+            .skip_while(|line| line.is_empty())
+            .take_while(|line| !line.is_empty())
+            .collect();
+        authors.entry(name).or_default().push(subject.join(" "));
+        Ok(true)
+    })?;
+
+    let mut authors: Vec<(String, Vec<String>)> = authors.into_iter().collect();
+    if numbered {
+        authors.sort_by_key(|(_, subjects)| Reverse(subjects.len()));
+    }
+    for (name, subjects) in authors {
+        if summary {
+            println!("{:>6}\t{}", subjects.len(), name);
+            continue;
+        }
+        println!("{} ({}):", name, subjects.len());
+        for subject in subjects.iter().rev() {
+            println!("      {}", subject);
+        }
+        println!();
+    }
+    Ok(())
+}
+
+/// Prints each line of the file at `path` in HEAD along with the commit that
+/// last changed it. Lines are traced back through history by diffing the
+/// file against each commit's parents; those a parent has too are passed on
+/// to it, and the rest are the commit's own.
+fn blame(repo: &Repository, path: &str) -> GitResult<()> {
+    let head = resolve_object(repo, "HEAD")?;
+    let lines = file_lines(repo, &head, path)?
+        .ok_or_else(|| GitError(format!("No such path {} in HEAD", path)))?;
+    let shallow = shallow_commits(repo)?;
+    let mailmap = Mailmap::load(repo.root())?;
+    let mut origins: Vec<Option<(String, Contributor, bool)>> = vec![None; lines.len()];
+
+    // The lines still being traced, by the commit they've been traced to, as
+    // (index in that commit's version of the file, index in HEAD's).
+    let mut pending: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+    let mut contents = HashMap::new();
+    let mut queue = BinaryHeap::new();
+    pending.insert(head.clone(), (0..lines.len()).map(|i| (i, i)).collect());
+    contents.insert(head.clone(), lines.clone());
+    queue.push((commit_time(repo, &head)?, head));
+
+    while let Some((_, sha)) = queue.pop() {
+        let mut remaining = match pending.remove(&sha) {
+            Some(remaining) => remaining,
+            None => continue,
+        };
+        let content = contents.remove(&sha).unwrap_or_default();
+        let (parents, author) = match repo.read_object(&sha)? {
+            Object::Commit {
+                parents, author, ..
+            } => (parents, author),
+            _ => return Err(GitError(format!("Not a commit: {}", sha))),
+        };
+        let boundary = parents.is_empty() || shallow.contains(&sha);
+        if !boundary {
+            for parent in &parents {
+                if remaining.is_empty() {
+                    break;
+                }
+                let parent_content = match file_lines(repo, parent, path)? {
+                    Some(parent_content) => parent_content,
+                    None => continue,
+                };
+                let unchanged: HashMap<usize, usize> = diff::diff(&parent_content, &content)
+                    .into_iter()
+                    .filter_map(|edit| match edit {
+                        diff::Edit::Equal(old, new) => Some((new, old)),
+                        _ => None,
+                    })
+                    .collect();
+                let (passed, kept): (Vec<_>, Vec<_>) = remaining
+                    .into_iter()
+                    .partition(|(line, _)| unchanged.contains_key(line));
+                remaining = kept;
+                if passed.is_empty() {
+                    continue;
+                }
+                if !pending.contains_key(parent) {
+                    contents.insert(parent.clone(), parent_content);
+                    queue.push((commit_time(repo, parent)?, parent.clone()));
+                }
+                pending.entry(parent.clone()).or_default().extend(
+                    passed
+                        .into_iter()
+                        .map(|(line, head_line)| (unchanged[&line], head_line)),
+                );
+            }
+        }
+        let author = mailmap.map(&author);
+        for (_, head_line) in remaining {
+            origins[head_line] = Some((sha.clone(), author.clone(), boundary));
+        }
+    }
+
+    let name_width = origins
+        .iter()
+        .flatten()
+        .map(|(_, author, _)| author.name.chars().count())
+        .max()
+        .unwrap_or(0);
+    let number_width = lines.len().to_string().len();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for (n, (line, origin)) in lines.iter().zip(origins).enumerate() {
+        let (sha, author, boundary) = origin.ok_or("Line was left unblamed")?;
+        // Boundary commits are marked with a `^` in place of a hex digit.
+        let abbrev = if boundary {
+            format!("^{}", &sha[..7])
+        } else {
+            sha[..8].to_owned()
+        };
+        write!(
+            out,
+            "{} ({:<name_width$} {} {:>number_width$}) ",
+            abbrev,
+            author.name,
+            author.format_iso_date()?,
+            n + 1,
+            name_width = name_width,
+            number_width = number_width
+        )?;
+        out.write_all(line)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// The lines of the file at `path` in a commit, if it has one there.
+fn file_lines(repo: &Repository, commit: &str, path: &str) -> GitResult<Option<Vec<Vec<u8>>>> {
+    let sha = match lookup_path(repo, &commit_tree(repo, commit)?, path)? {
+        Some(sha) => sha,
+        None => return Ok(None),
+    };
+    match repo.read_object(&sha)? {
+        blob @ Object::Blob(_) => Ok(Some(blob.blob_lines().map(<[u8]>::to_vec).collect())),
+        _ => Ok(None),
+    }
+}
+
+/// A file that differs between two trees, by its path, mode and SHA on each
+/// side. A renamed file has different paths on each side, and a similarity.
+struct FileChange {
+    old: Option<(String, usize, Sha)>,
+    new: Option<(String, usize, Sha)>,
+    similarity: Option<usize>,
+}
+
+/// How similar, in percent, a deleted and an added file must be at least to
+/// count as a rename.
+const RENAME_THRESHOLD: usize = 50;
+
+/// Lists the files that differ between two trees, in path order. A file
+/// deleted on one side and added on the other is a rename if they are
+/// similar enough.
+fn tree_changes(repo: &Repository, from: &str, to: &str) -> GitResult<Vec<FileChange>> {
+    let to_string = |path: PathBuf| path.to_string_lossy().into_owned();
+    let old_files = tree_blobs(repo, from, Path::new(""))?;
+    let mut new_files = tree_blobs(repo, to, Path::new(""))?;
+    let mut changes = Vec::new();
+    let mut deleted = Vec::new();
+    for (path, (mode, sha)) in old_files {
+        match new_files.remove(&path) {
+            Some(new) if new == (mode, sha) => {}
+            Some((new_mode, new_sha)) => changes.push(FileChange {
+                old: Some((to_string(path.clone()), mode, sha)),
+                new: Some((to_string(path), new_mode, new_sha)),
+                similarity: None,
+            }),
+            None => deleted.push(Some((to_string(path), mode, sha))),
+        }
+    }
+    let mut added: Vec<Option<(String, usize, Sha)>> = new_files
+        .into_iter()
+        .map(|(path, (mode, sha))| Some((to_string(path), mode, sha)))
+        .collect();
+    let shas = |files: &[Option<(String, usize, Sha)>]| -> Vec<Sha> {
+        files.iter().flatten().map(|f| f.2).collect()
+    };
+    for (old, new, similarity) in find_renames(repo, &shas(&deleted), &shas(&added))? {
+        changes.push(FileChange {
+            old: deleted[old].take(),
+            new: added[new].take(),
+            similarity: Some(similarity),
+        });
+    }
+    for old in deleted.into_iter().flatten() {
+        changes.push(FileChange {
+            old: Some(old),
+            new: None,
+            similarity: None,
+        });
+    }
+    changes.extend(added.into_iter().flatten().map(|new| FileChange {
+        old: None,
+        new: Some(new),
+        similarity: None,
+    }));
+    changes.sort_by(|a, b| {
+        let path = |c: &FileChange| c.new.as_ref().or(c.old.as_ref()).map(|f| f.0.clone());
+        path(a).cmp(&path(b))
+    });
+    Ok(changes)
+}
+
+/// Pairs deleted blobs with added ones similar enough to be renames of them,
+/// taking the most similar pairs first. Each pair is given by the index of
+/// both blobs and their similarity.
+fn find_renames(
+    repo: &Repository,
+    deleted: &[Sha],
+    added: &[Sha],
+) -> GitResult<Vec<(usize, usize, usize)>> {
+    if deleted.is_empty() || added.is_empty() {
+        return Ok(Vec::new());
+    }
+    let read = |sha: &Sha| -> GitResult<Bytes> { repo.read_object(&object::to_hex(sha))?.body() };
+    let old_contents = deleted.iter().map(read).collect::<GitResult<Vec<_>>>()?;
+    let new_contents = added.iter().map(read).collect::<GitResult<Vec<_>>>()?;
+    let mut candidates = Vec::new();
+    for (i, old) in old_contents.iter().enumerate() {
+        for (j, new) in new_contents.iter().enumerate() {
+            let similarity = if deleted[i] == added[j] {
+                100
+            } else {
+                diff::similarity(old, new)
+            };
+            if similarity >= RENAME_THRESHOLD {
+                candidates.push((i, j, similarity));
+            }
+        }
+    }
+    candidates.sort_by_key(|&(i, j, similarity)| (Reverse(similarity), i, j));
+    let (mut old_used, mut new_used) = (HashSet::new(), HashSet::new());
+    let mut renames = Vec::new();
+    for (i, j, similarity) in candidates {
+        if !old_used.contains(&i) && !new_used.contains(&j) {
+            old_used.insert(i);
+            new_used.insert(j);
+            renames.push((i, j, similarity));
+        }
+    }
+    Ok(renames)
+}
+
+/// Prints each file that differs between two trees with a letter saying
+/// how: `A`dded, `D`eleted, `M`odified, or `R`enamed with a similarity.
+fn diff_name_status(repo: &Repository, from: &str, to: &str) -> GitResult<()> {
+    for change in tree_changes(repo, from, to)? {
+        match (change.old, change.new, change.similarity) {
+            (Some(old), Some(new), Some(similarity)) => {
+                println!("R{:03}\t{}\t{}", similarity, old.0, new.0)
+            }
+            (Some(_), Some(new), None) => println!("M\t{}", new.0),
+            (Some(old), None, _) => println!("D\t{}", old.0),
+            (None, Some(new), _) => println!("A\t{}", new.0),
+            (None, None, _) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Prints how many lines changed in each file between two trees, with a
+/// graph of them, laid out as `git diff --stat` does.
+fn diff_stat(repo: &Repository, from: &str, to: &str) -> GitResult<()> {
+    let content = |file: &Option<(String, usize, Sha)>| -> GitResult<Bytes> {
+        match file {
+            Some((_, _, sha)) => repo.read_object(&object::to_hex(sha))?.body(),
+            None => Ok(Bytes::new()),
+        }
+    };
+    // Each file's name, and its deleted and added lines, or sizes if binary.
+    let mut files = Vec::new();
+    for change in tree_changes(repo, from, to)? {
+        let (old, new) = (content(&change.old)?, content(&change.new)?);
+        let name = match (&change.old, &change.new) {
+            (Some(old), Some(new)) if old.0 != new.0 => rename_name(&old.0, &new.0),
+            (Some(file), _) | (_, Some(file)) => file.0.clone(),
+            (None, None) => continue,
+        };
+        let path = change
+            .new
+            .as_ref()
+            .or(change.old.as_ref())
+            .map_or("", |f| &f.0);
+        if is_binary_file(repo, path, &old) || is_binary_file(repo, path, &new) {
+            files.push((name, old.len(), new.len(), true));
+            continue;
+        }
+        let old_lines: Vec<&[u8]> = old.split_inclusive(|&b| b == b'\n').collect();
+        let new_lines: Vec<&[u8]> = new.split_inclusive(|&b| b == b'\n').collect();
+        let (mut deleted, mut added) = (0, 0);
+        for edit in diff::diff(&old_lines, &new_lines) {
+            match edit {
+                diff::Edit::Delete(_) => deleted += 1,
+                diff::Edit::Insert(_) => added += 1,
+                diff::Edit::Equal(..) => {}
+            }
+        }
+        files.push((name, deleted, added, false));
+    }
+
+    // Share out the width between the names and the graph as git does.
+    let width = env::var("COLUMNS")
+        .ok()
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(80);
+    let digits = |n: usize| n.to_string().len();
+    let max_name = files.iter().map(|f| f.0.chars().count()).max().unwrap_or(0);
+    let max_change = files
+        .iter()
+        .filter(|f| !f.3)
+        .map(|f| f.1 + f.2)
+        .max()
+        .unwrap_or(0);
+    let bin_width = files
+        .iter()
+        .filter(|f| f.3)
+        .map(|f| 14 + digits(f.1) + digits(f.2))
+        .max()
+        .unwrap_or(0);
+    let mut number_width = if bin_width > 0 { 3 } else { 0 };
+    number_width = number_width.max(digits(max_change));
+    let width = width.max(16 + 6 + number_width);
+    let mut graph_width = if max_change + 4 > bin_width {
+        max_change
+    } else {
+        bin_width - 4
+    };
+    let mut name_width = max_name;
+    if name_width + number_width + 6 + graph_width > width {
+        if graph_width + number_width + 6 > width * 3 / 8 {
+            graph_width = (width * 3 / 8).saturating_sub(number_width + 6).max(6);
+        }
+        if name_width + number_width + 6 + graph_width > width {
+            name_width = width - number_width - 6 - graph_width;
+        } else {
+            graph_width = width - number_width - 6 - name_width;
+        }
+    }
+
+    let scale = |n: usize| {
+        if n == 0 {
+            0
+        } else {
+            1 + n * (graph_width - 1) / max_change
+        }
+    };
+    let (mut insertions, mut deletions) = (0, 0);
+    for (name, deleted, added, binary) in &files {
+        // Names too long to fit are cut down from the front.
+        let mut name = name.as_str();
+        let mut prefix = "";
+        if name.chars().count() > name_width {
+            prefix = "...";
+            let keep = name_width.saturating_sub(3);
+            let skip = name.chars().count() - keep;
+            name = &name[name.char_indices().nth(skip).map_or(name.len(), |(i, _)| i)..];
+            if let Some(slash) = name.find('/') {
+                name = &name[slash..];
+            }
+        }
+        let padding = (name_width - prefix.len()).saturating_sub(name.chars().count());
+        if *binary {
+            println!(
+                " {}{}{} | {:>width$} {} -> {} bytes",
+                prefix,
+                name,
+                " ".repeat(padding),
+                "Bin",
+                deleted,
+                added,
+                width = number_width
+            );
+            continue;
+        }
+        insertions += added;
+        deletions += deleted;
+        let (mut plus, mut minus) = (*added, *deleted);
+        if graph_width <= max_change {
+            let mut total = scale(added + deleted);
+            if total < 2 && *added > 0 && *deleted > 0 {
+                total = 2;
+            }
+            if added < deleted {
+                plus = scale(*added);
+                minus = total - plus;
+            } else {
+                minus = scale(*deleted);
+                plus = total - minus;
+            }
+        }
+        println!(
+            " {}{}{} | {:>width$}{}{}{}",
+            prefix,
+            name,
+            " ".repeat(padding),
+            added + deleted,
+            if added + deleted > 0 { " " } else { "" },
+            "+".repeat(plus),
+            "-".repeat(minus),
+            width = number_width
+        );
+    }
+
+    let plural = |n: usize, one: &str, many: &str| {
+        if n == 1 {
+            format!("{} {}", n, one)
+        } else {
+            format!("{} {}", n, many)
+        }
+    };
+    let mut summary = format!(" {}", plural(files.len(), "file changed", "files changed"));
+    if insertions > 0 || deletions == 0 {
+        summary += &format!(", {}", plural(insertions, "insertion(+)", "insertions(+)"));
+    }
+    if deletions > 0 || insertions == 0 {
+        summary += &format!(", {}", plural(deletions, "deletion(-)", "deletions(-)"));
+    }
+    if !files.is_empty() {
+        println!("{}", summary);
+    }
+    Ok(())
+}
+
+/// Names a renamed file the way git's diffstat does, with the parts of the
+/// paths that differ in braces: `dir/{old => new}/file`.
+fn rename_name(old: &str, new: &str) -> String {
+    let (a, b) = (old.as_bytes(), new.as_bytes());
+    // The common prefix and suffix only count up to a slash.
+    let mut prefix = 0;
+    for (i, (x, y)) in a.iter().zip(b).enumerate() {
+        if x != y {
+            break;
+        }
+        if *x == b'/' {
+            prefix = i + 1;
+        }
+    }
+    let mut suffix = 0;
+    let floor = prefix.saturating_sub(1);
+    let (mut i, mut j) = (a.len(), b.len());
+    while i > floor && j > floor && a[i - 1] == b[j - 1] {
+        i -= 1;
+        j -= 1;
+        if a[i] == b'/' {
+            suffix = a.len() - i;
+        }
+    }
+    let old_mid = &old[prefix..a.len().saturating_sub(suffix).max(prefix)];
+    let new_mid = &new[prefix..b.len().saturating_sub(suffix).max(prefix)];
+    if prefix + suffix == 0 {
+        return format!("{} => {}", old_mid, new_mid);
+    }
+    format!(
+        "{}{{{} => {}}}{}",
+        &old[..prefix],
+        old_mid,
+        new_mid,
+        &old[a.len() - suffix..]
+    )
+}
+
+/// Whether the repository has the object named by the hex `sha`.
+fn has_object(repo: &Repository, sha: &str) -> bool {
+    object::from_hex(sha).is_ok_and(|sha| repo.has_object(&sha))
+}
+
+fn commit_time(repo: &Repository, sha: &str) -> GitResult<i64> {
+    match repo.read_object(sha)? {
+        Object::Commit { committer, .. } => Ok(committer.timestamp),
+        _ => Err(GitError(format!("Not a commit: {}", sha))),
+    }
+}
+
+/// Prints the commits reachable from the included revisions but not the
+/// excluded ones, newest first, or just how many there are with `count`.
+fn rev_list(
+    repo: &Repository,
+    revs: &[String],
+    max_count: Option<usize>,
+    count: bool,
+) -> GitResult<()> {
+    let (include, exclude) = split_revs(repo, revs)?;
+    let tips = include
+        .iter()
+        .map(|rev| resolve_object(repo, rev))
+        .collect::<GitResult<Vec<String>>>()?;
+    let mut hidden = HashSet::new();
+    for sha in &exclude {
+        hidden.extend(ancestors(repo, sha)?);
+    }
+
+    let limit = max_count.unwrap_or(usize::MAX);
+    let mut listed = 0;
+    rev_walk(repo, &tips, &hidden, |sha, _| {
+        if listed == limit {
+            return Ok(false);
+        }
+        listed += 1;
+        if !count {
+            println!("{}", sha);
+        }
+        Ok(true)
+    })?;
+    if count {
+        println!("{}", listed);
+    }
+    Ok(())
+}
+
+/// Visits the commits reachable from `tips` but not in `hidden`, most recently
+/// committed first, until `visit` returns false. Like git, commits committed
+/// at the same time are visited in the order they were reached.
+fn rev_walk(
+    repo: &Repository,
+    tips: &[String],
+    hidden: &HashSet<String>,
+    mut visit: impl FnMut(&str, &Object) -> GitResult<bool>,
+) -> GitResult<()> {
+    let shallow = shallow_commits(repo)?;
+    let mut seen = HashSet::new();
+    // Ordered by commit time, then by when each was reached.
+    let mut queue = BinaryHeap::new();
+    let mut pending = HashMap::new();
+    let mut reached = 0;
+    let mut push = |sha: &str,
+                    queue: &mut BinaryHeap<_>,
+                    pending: &mut HashMap<String, Object>|
+     -> GitResult<()> {
+        if hidden.contains(sha) || !seen.insert(sha.to_owned()) {
+            return Ok(());
+        }
+        let commit = repo.read_object(sha)?;
+        let timestamp = match &commit {
+            Object::Commit { committer, .. } => committer.timestamp,
+            _ => return Err(GitError(format!("Not a commit: {}", sha))),
+        };
+        reached += 1;
+        queue.push((timestamp, Reverse(reached), sha.to_owned()));
+        pending.insert(sha.to_owned(), commit);
+        Ok(())
+    };
+    for tip in tips {
+        push(tip, &mut queue, &mut pending)?;
+    }
+    while let Some((_, _, sha)) = queue.pop() {
+        let commit = pending
+            .remove(&sha)
+            .ok_or("Commit went missing from the walk")?;
+        if !visit(&sha, &commit)? {
+            break;
+        }
+        if let (Object::Commit { parents, .. }, false) = (&commit, shallow.contains(&sha)) {
+            for parent in parents {
+                push(parent, &mut queue, &mut pending)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Splits revision arguments into the names to include and the SHAs whose
+/// history to exclude, given as `^<rev>` or the `<from>` of `<from>..<to>`.
+/// Either side of `..` defaults to `HEAD`.
+fn split_revs(repo: &Repository, revs: &[String]) -> GitResult<(Vec<String>, Vec<String>)> {
+    let mut include = Vec::new();
+    let mut exclude = Vec::new();
+    for rev in revs {
+        let or_head = |rev: &str| if rev.is_empty() { "HEAD" } else { rev }.to_owned();
+        let mut range = rev.splitn(2, "..");
+        match (range.next(), range.next()) {
+            (Some(from), Some(to)) => {
+                exclude.push(resolve_object(repo, &or_head(from))?);
+                include.push(or_head(to));
+            }
+            _ => match rev.strip_prefix('^') {
+                Some(excluded) => exclude.push(resolve_object(repo, excluded)?),
+                None => include.push(rev.clone()),
+            },
+        }
+    }
+    Ok((include, exclude))
+}
+
+/// Checks that every ref a bundle lists points at an object it holds, or one
+/// the repository has already.
+fn check_bundle_refs(
+    repo: &Repository,
+    header: &bundle::Bundle,
+    objects: &MemoryStore,
+) -> GitResult<()> {
+    match header
+        .refs
+        .iter()
+        .find(|r| !objects.contains(&r.sha) && !has_object(repo, &r.sha))
+    {
+        Some(r) => Err(GitError(format!(
+            "Bundle ref {} points at missing object {}",
+            r.name, r.sha
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Bundles the given refs with every object they need. Revisions prefixed
+/// with `^`, or on the left of `a..b`, are left out along with their history,
+/// and the bundle then records the commits it builds on as prerequisites.
+fn create_bundle(repo: &Repository, path: &Path, revs: &[String]) -> GitResult<()> {
+    let (include, exclude) = split_revs(repo, revs)?;
+
+    let mut bundle_refs = Vec::new();
+    for name in include {
+        let full_name = match name.as_str() {
+            "HEAD" => Some(name.clone()),
+            _ => refs::expand_ref(repo.root(), &name)?,
+        };
+        let full_name = full_name.ok_or_else(|| GitError(format!("Not a ref: {}", name)))?;
+        let sha = refs::resolve_rev(repo.root(), &full_name)?;
+        bundle_refs.push(remote::Ref {
+            sha,
+            name: full_name,
+        });
+    }
+    if bundle_refs.is_empty() {
+        return Err(GitError("Refusing to create an empty bundle".to_owned()));
+    }
+
+    let mut seen = HashSet::new();
+    collect_objects(repo, &exclude, &mut seen)?;
+    let tips: Vec<String> = bundle_refs.iter().map(|r| r.sha.clone()).collect();
+    let objects = collect_objects(repo, &tips, &mut seen)?;
+
+    // Excluded parents of the bundled commits must exist wherever it's unbundled.
+    let bundled: HashSet<&String> = objects.iter().map(|(sha, _)| sha).collect();
+    let mut prerequisites = Vec::new();
+    for (_, obj) in &objects {
+        if let Object::Commit { parents, .. } = obj {
+            for parent in parents {
+                if !bundled.contains(parent) && !prerequisites.contains(parent) {
+                    prerequisites.push(parent.clone());
+                }
+            }
+        }
+    }
+
+    let objects: Vec<Object> = objects.into_iter().map(|(_, obj)| obj).collect();
+    let options = pack::DeltaOptions::from_config(&Config::load(repo.root())?)?;
+    let pack = pack::write_pack(&objects, &options)?;
+    bundle::write(path, &prerequisites, &bundle_refs, &pack)
+}
+
+/// Writes the tree of `rev` to stdout as a tar archive, with every path under
+/// `prefix`. Like git, a commit's archive is stamped with its commit time and
+/// records its SHA; a bare tree's is stamped with the current time.
+fn archive(repo: &Repository, rev: &str, prefix: &str) -> GitResult<()> {
+    let sha = resolve_object(repo, rev)?;
+    let commit = peel_to(repo, &sha, "commit").ok();
+    let mtime = match &commit {
+        Some(commit) => commit_time(repo, commit)?,
+        None => SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs() as i64,
+    };
+    let tree = peel_to(repo, &sha, "tree")?;
+
+    let mut writer = tar::TarWriter::new(io::BufWriter::new(io::stdout().lock()), mtime);
+    if let Some(commit) = &commit {
+        writer.comment(commit)?;
+    }
+    if prefix.ends_with('/') {
+        writer.add(prefix, &tree, tar::Entry::Directory)?;
+    }
+    archive_tree(repo, &mut writer, &tree, prefix)?;
+    writer.finish()
+}
+
+/// Adds the entries of a tree to an archive, each directory before what's
+/// in it. Submodules become empty directories.
+fn archive_tree<W: Write>(
+    repo: &Repository,
+    writer: &mut tar::TarWriter<W>,
+    tree: &str,
+    prefix: &str,
+) -> GitResult<()> {
+    let refs = match repo.read_object(tree)? {
+        Object::Tree(refs) => refs,
+        _ => return Err(GitError(format!("Not a tree: {}", tree))),
+    };
+    for r in refs {
+        let path = format!("{}{}", prefix, String::from_utf8_lossy(&r.name));
+        let sha = object::to_hex(&r.hash);
+        match r.mode {
+            40000 => {
+                let dir = format!("{}/", path);
+                writer.add(&dir, &sha, tar::Entry::Directory)?;
+                archive_tree(repo, writer, &sha, &dir)?;
+            }
+            160000 => writer.add(&format!("{}/", path), &sha, tar::Entry::Directory)?,
+            mode => {
+                let content = match repo.read_object(&sha)? {
+                    Object::Blob(content) => content,
+                    _ => return Err(GitError(format!("Not a blob: {}", sha))),
+                };
+                let entry = match mode {
+                    120000 => tar::Entry::Symlink(&content),
+                    _ => tar::Entry::File {
+                        executable: mode == 100755,
+                        content: &content,
+                    },
+                };
+                writer.add(&path, &sha, entry)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The commits whose parents a shallow clone left out. History walks treat
+/// them as roots.
+fn shallow_commits(repo: &Repository) -> GitResult<HashSet<String>> {
+    Ok(shallow::read(repo.root())?.into_iter().collect())
+}
+
+/// Collects the objects reachable from `tips` that aren't in `seen` yet,
+/// adding them to it. Replacements are ignored, since the objects are meant to
+/// be copied as they're stored.
+fn collect_objects(
+    repo: &Repository,
+    tips: &[String],
+    seen: &mut HashSet<String>,
+) -> GitResult<Vec<(String, Object)>> {
+    let shallow = shallow_commits(repo)?;
+    let mut objects = Vec::new();
+    let mut pending = tips.to_vec();
+    while let Some(sha) = pending.pop() {
+        if !seen.insert(sha.clone()) {
+            continue;
+        }
+        let obj = repo.read_stored_object(&sha)?;
+        match &obj {
+            Object::Commit { tree, parents, .. } => {
+                pending.push(tree.clone());
+                if !shallow.contains(&sha) {
+                    pending.extend(parents.iter().cloned());
+                }
+            }
+            Object::Tree(entries) => pending.extend(
+                entries
+                    .iter()
+                    .filter(|e| e.mode != 160000)
+                    .map(|e| object::to_hex(&e.hash)),
+            ),
+            Object::Tag { object, .. } => pending.push(object.clone()),
+            Object::Blob(_) => {}
+        }
+        objects.push((sha, obj));
+    }
+    Ok(objects)
+}
+
+/// Writes every object reachable from the refs, their reflogs, HEAD and the
+/// index into a single new pack, like `git repack -a`. With `delete`, the
+/// packs there were before and the loose objects now in the pack are
+/// removed; loose objects nothing reaches are left alone.
+fn repack(repo: &Repository, delete: bool) -> GitResult<()> {
+    let mut names: Vec<String> = refs::list_refs(repo.root(), "refs/")?.into_keys().collect();
+    names.push("HEAD".to_owned());
+    let mut tips = Vec::new();
+    for name in &names {
+        tips.extend(refs::read_ref(repo.root(), name)?);
+        for entry in refs::read_reflog(repo.root(), name)? {
+            tips.push(entry.old);
+            tips.push(entry.new);
+        }
+    }
+    if let Ok(index) = Index::read(&repo.index_path()) {
+        tips.extend(
+            index
+                .entries
+                .iter()
+                .filter(|e| e.mode != 0o160000)
+                .map(|e| object::to_hex(&e.sha)),
+        );
+    }
+    // Reflogs can name objects long gone, and the null SHA for creation.
+    tips.retain(|sha| has_object(repo, sha));
+    let shas: Vec<String> = collect_objects(repo, &tips, &mut HashSet::new())?
+        .into_iter()
+        .map(|(sha, _)| sha)
+        .collect();
+    if shas.is_empty() {
+        report(repo, "Nothing new to pack.");
+        return Ok(());
+    }
+
+    let pack_dir = repo.objects_dir.join("pack");
+    fs::create_dir_all(&pack_dir)?;
+    let old_packs: Vec<PathBuf> = fs::read_dir(&pack_dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|path| path.extension() == Some("pack".as_ref()))
+        .collect();
+
+    let options = pack::DeltaOptions::from_config(&Config::load(repo.root())?)?;
+    // Objects go in as they're stored, so their SHAs can't change.
+    let objects = shas
+        .iter()
+        .map(|sha| repo.read_stored_raw_object(sha))
+        .collect::<GitResult<Vec<_>>>()?;
+    let temp = pack_dir.join(format!("tmp_pack_{}", std::process::id()));
+    fs::write(&temp, pack::write_raw_pack(&objects, &options)?)?;
+    let (_, stats) = pack::parse_pack(
+        BufReader::new(File::open(&temp)?),
+        &pack::PackLimits::default(),
+    )?;
+    let name = format!("pack-{}", object::to_hex(&stats.checksum));
+    let pack_path = pack_dir.join(format!("{}.pack", name));
+    // The index goes in last, so the pack is never found without all of it.
+    fs::rename(&temp, &pack_path)?;
+    idx::PackIndex::for_pack(&pack_path, &stats)?.write(&pack_path.with_extension("idx"))?;
+
+    if delete {
+        // Nothing goes unless the new pack's index, as read back, has it all.
+        let index = idx::PackIndex::read(&pack_path.with_extension("idx"))?;
+        for sha in &shas {
+            if index.find(&object::from_hex(sha)?).is_none() {
+                return Err(GitError(format!(
+                    "New pack is missing {}; nothing was deleted",
+                    sha
+                )));
+            }
+        }
+        for old in old_packs.iter().filter(|old| **old != pack_path) {
+            for extension in &["idx", "rev", "bitmap"] {
+                let path = old.with_extension(extension);
+                if path.exists() {
+                    fs::remove_file(path)?;
+                }
+            }
+            fs::remove_file(old)?;
+        }
+        for dir in fs::read_dir(&repo.objects_dir)? {
+            let dir = dir?;
+            let prefix = dir.file_name().to_string_lossy().into_owned();
+            if prefix.len() != 2 || !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(dir.path())? {
+                let file = file?;
+                let sha = format!("{}{}", prefix, file.file_name().to_string_lossy());
+                let packed = object::from_hex(&sha).is_ok_and(|sha| index.find(&sha).is_some());
+                if packed {
+                    fs::remove_file(file.path())?;
+                }
+            }
+            // Only succeeds once the directory is empty.
+            let _ = fs::remove_dir(dir.path());
+        }
+    }
+    Ok(())
+}
+
+/// Prints `<sha> <type> <size>` for each object named on stdin, one per
+/// line, or `<name> missing` for those that can't be found.
+fn batch_check(repo: &Repository) -> GitResult<()> {
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    for name in io::stdin().lock().lines() {
+        let name = name?;
+        let found = resolve_object(repo, name.trim()).and_then(|sha| {
+            let (kind, size, _) = repo.open_object(&sha)?;
+            Ok((sha, kind, size))
+        });
+        match found {
+            Ok((sha, kind, size)) => writeln!(out, "{} {} {}", sha, kind, size)?,
+            Err(_) => writeln!(out, "{} missing", name.trim())?,
+        }
+    }
+    Ok(())
+}
+
+/// Prints `<sha> <type> <size>` for every object in the repository, loose or
+/// packed, once each and in SHA order. Only the objects' headers are read,
+/// and each line is written as soon as it's known.
+fn batch_check_all(repo: &Repository) -> GitResult<()> {
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    for sha in repo.all_objects()? {
+        let (kind, size, _) = repo.open_stored_object(&sha)?;
+        writeln!(out, "{} {} {}", sha, kind, size)?;
+    }
+    Ok(())
+}
+
+/// Checks that every object in the repository decodes and encodes back to
+/// exactly the same bytes, which its SHA depends on, and that an object both
+/// loose and packed is the same in both places. Objects that aren't are
+/// listed, along with why.
+fn verify_objects(repo: &Repository) -> GitResult<()> {
+    let shas = repo.all_objects()?;
+    let mut failed = 0;
+    for sha in &shas {
+        let loose = repo.read_loose_object(sha)?;
+        let packed = repo.read_packed_object(sha)?;
+        if let (Some(loose), Some(packed)) = (&loose, &packed) {
+            if loose != packed {
+                println!("{}: loose and packed copies differ", sha);
+                failed += 1;
+                continue;
+            }
+        }
+        let raw = loose
+            .or(packed)
+            .ok_or_else(|| GitError(format!("Object not found: {}", sha)))?;
+        let problem = match Object::decode(raw.clone()).and_then(|obj| Ok((obj.encode()?, obj))) {
+            Ok(((_, data), _)) if data == raw => continue,
+            Ok((_, obj)) => format!("{} does not encode back to the same bytes", obj.kind()),
+            Err(GitError(e)) => e,
+        };
+        println!("{}: {}", sha, problem);
+        failed += 1;
+    }
+    if failed > 0 {
+        return Err(GitError(format!(
+            "{} of {} objects did not round-trip",
+            failed,
+            shas.len()
+        )));
+    }
+    report(repo, &format!("{} objects round-trip", shas.len()));
+    Ok(())
+}
+
+/// Checks a pack against its index: the checksums, and that every object
+/// sits at the offset and has the CRC the index records for it.
+fn verify_pack(path: &Path, verbose: bool) -> GitResult<()> {
+    let pack_path = path.with_extension("pack");
+    let index = idx::PackIndex::read(&pack_path.with_extension("idx"))?;
+    let (_, stats) = pack::parse_pack(
+        BufReader::new(File::open(&pack_path)?),
+        &pack::PackLimits::default(),
+    )?;
+    if stats.checksum != index.pack_checksum {
+        return Err(GitError(format!(
+            "{}: pack checksum doesn't match its index",
+            pack_path.display()
+        )));
+    }
+    if stats.entries.len() != index.entries.len() {
+        return Err(GitError(format!(
+            "{}: pack has {} objects but its index has {}",
+            pack_path.display(),
+            stats.entries.len(),
+            index.entries.len()
+        )));
+    }
+
+    let data = fs::read(&pack_path)?;
+    let mut non_delta = 0;
+    let mut chain_lengths = BTreeMap::new();
+    for entry in &stats.entries {
+        let sha = object::to_hex(&entry.sha);
+        let indexed = index
+            .find(&entry.sha)
+            .ok_or_else(|| GitError(format!("{} is missing from the index", sha)))?;
+        let mut crc = Crc::new();
+        crc.update(&data[entry.offset..entry.offset + entry.packed_size]);
+        // Version 1 indexes have no CRCs to check.
+        if indexed.offset != entry.offset as u64 || indexed.crc32.is_some_and(|c| c != crc.sum()) {
+            return Err(GitError(format!("{} doesn't match its index entry", sha)));
+        }
+
+        if verbose {
+            print!(
+                "{} {:<6} {} {} {}",
+                sha, entry.kind, entry.size, entry.packed_size, entry.offset
+            );
+            match entry.base {
+                Some(base) => println!(" {} {}", entry.depth, object::to_hex(&base)),
+                None => println!(),
+            }
+        }
+        if entry.depth == 0 {
+            non_delta += 1;
+        } else {
+            *chain_lengths.entry(entry.depth).or_insert(0) += 1;
+        }
+    }
+
+    if verbose {
+        let plural = |count: usize| if count == 1 { "object" } else { "objects" };
+        // An empty pack has nothing to count.
+        if non_delta > 0 {
+            println!("non delta: {} {}", non_delta, plural(non_delta));
+        }
+        for (depth, count) in chain_lengths {
+            println!("chain length = {}: {} {}", depth, count, plural(count));
+        }
+        println!("{}: ok", pack_path.display());
+    }
+    Ok(())
+}
+
+fn write_tree(repo: &Repository, path: &str, ignore: &[&str]) -> GitResult<Sha> {
+    let mut refs = Vec::new();
+
+    for f in fs::read_dir(path)? {
+        let path_buf = f?.path();
+        let name = path_buf
+            .file_name()
+            .ok_or("Could not get a file path")?
+            .to_str()
+            .ok_or("Could not get a file path")?
+            .to_owned();
+        if ignore.contains(&&*name) {
+            continue;
+        }
+        let hash;
+        let mode;
+
+        let file_type = fs::symlink_metadata(&path_buf)?.file_type();
+        if file_type.is_symlink() && repo.symlinks {
+            let bytes = Bytes::from(worktree_content(&path_buf)?);
+            hash = repo.write_object(&Object::Blob(bytes))?;
+            mode = 120000;
+        } else if path_buf.is_dir() {
+            hash = write_tree(
+                repo,
+                path_buf.to_str().ok_or("Could not get a file path")?,
+                ignore,
+            )?;
+            mode = 40000;
+        } else {
+            let bytes = Bytes::from(fs::read(&path_buf)?);
+            hash = repo.write_object(&Object::Blob(bytes))?;
+            mode = 100644;
+        };
+
+        refs.push(ObjectReference {
+            mode,
+            padded_mode: false,
+            name: name.into(),
+            hash,
+        })
+    }
+
+    repo.write_tree_entries(refs)
+}
+
+/// Drops empty and `.` components from a path given on the command line.
+fn normalize_path(path: &str) -> String {
+    let components: Vec<&str> = path
+        .split('/')
+        .filter(|c| !c.is_empty() && *c != ".")
+        .collect();
+    components.join("/")
+}
+
+/// Removes `paths` from the index and, unless `cached`, the working tree.
+/// Like git, this refuses to lose changes that aren't committed unless
+/// `force` is set.
+fn rm(
+    repo: &Repository,
+    paths: &[String],
+    cached: bool,
+    force: bool,
+    recursive: bool,
+) -> GitResult<()> {
+    let index_path = repo.index_path();
+    let mut index = Index::read(&index_path)?;
+    let head = match repo.head_commit()? {
+        Some(sha) => tree_blobs(repo, &commit_tree(repo, &sha)?, Path::new(""))?,
+        None => BTreeMap::new(),
+    };
+    let root = Path::new(repo.root());
+
+    let mut removed = BTreeSet::new();
+    for path in paths {
+        let dir = format!("{}/", path);
+        let matched: Vec<&IndexEntry> = index
+            .entries
+            .iter()
+            .filter(|e| e.path == *path || path.is_empty() || e.path.starts_with(&dir))
+            .collect();
+        if matched.is_empty() {
+            return Err(GitError(format!(
+                "pathspec '{}' did not match any files",
+                path
+            )));
+        }
+        if !recursive && matched.iter().any(|e| e.path != *path) {
+            return Err(GitError(format!(
+                "not removing '{}' recursively without -r",
+                path
+            )));
+        }
+        for entry in matched {
+            if !force {
+                let staged = match head.get(Path::new(&entry.path)) {
+                    Some((mode, sha)) => {
+                        *sha != entry.sha || format!("{:o}", entry.mode) != mode.to_string()
+                    }
+                    None => true,
+                };
+                let local = !matches!(worktree_status(root, entry)?, ' ' | 'D');
+                if staged && local {
+                    return Err(GitError(format!(
+                        "'{}' has staged content different from both the file and the HEAD (use -f to force removal)",
+                        entry.path
+                    )));
+                }
+                if staged && !cached {
+                    return Err(GitError(format!(
+                        "'{}' has changes staged in the index (use --cached to keep the file, or -f to force removal)",
+                        entry.path
+                    )));
+                }
+                if local && !cached {
+                    return Err(GitError(format!(
+                        "'{}' has local modifications (use --cached to keep the file, or -f to force removal)",
+                        entry.path
+                    )));
+                }
+            }
+            removed.insert(entry.path.clone());
+        }
+    }
+
+    for path in &removed {
+        index.remove(path);
+        if !cached {
+            remove_file(repo, Path::new(path))?;
+        }
+        report(repo, &format!("rm '{}'", path));
+    }
+    index.write(&index_path)
+}
+
+/// Moves a tracked file or directory in both the working tree and the
+/// index. Moving onto an existing directory moves into it.
+fn mv(repo: &Repository, source: &str, destination: &str, force: bool) -> GitResult<()> {
+    let index_path = repo.index_path();
+    let mut index = Index::read(&index_path)?;
+    let root = Path::new(repo.root());
+    let destination = match Path::new(source).file_name() {
+        Some(name) if root.join(destination).is_dir() => Path::new(destination)
+            .join(name)
+            .to_string_lossy()
+            .into_owned(),
+        _ => destination.to_owned(),
+    };
+
+    let dir = format!("{}/", source);
+    let moved: Vec<IndexEntry> = index
+        .entries
+        .iter()
+        .filter(|e| e.path == source || e.path.starts_with(&dir))
+        .cloned()
+        .collect();
+    if moved.is_empty() || fs::symlink_metadata(root.join(source)).is_err() {
+        return Err(GitError(format!("bad source: {}", source)));
+    }
+    if destination == source || destination.starts_with(&dir) {
+        return Err(GitError(format!("can not move '{}' into itself", source)));
+    }
+    match fs::symlink_metadata(root.join(&destination)) {
+        Ok(metadata) if metadata.is_dir() || !force => {
+            return Err(GitError(format!("destination exists: {}", destination)))
+        }
+        _ => {}
+    }
+
+    if let Some(parent) = root.join(&destination).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(root.join(source), root.join(&destination))?;
+    for mut entry in moved {
+        index.remove(&entry.path);
+        entry.path = format!("{}{}", destination, &entry.path[source.len()..]);
+        index.add(entry);
+    }
+    index.write(&index_path)
+}
+
+/// Applies a unified diff to the working tree, or with `cached` to the index
+/// alone. Nothing is changed unless every file's hunks apply.
+fn apply(repo: &Repository, patch: &[u8], cached: bool) -> GitResult<()> {
+    let root = Path::new(repo.root());
+    let index_path = repo.index_path();
+    let mut index = Index::read(&index_path)?;
+    let patches = patch::parse(patch)?;
+
+    let mut results = Vec::with_capacity(patches.len());
+    for file in &patches {
+        let path = file.path();
+        let (old, old_mode) = match &file.old_path {
+            None => {
+                let exists = if cached {
+                    index.entries.iter().any(|e| e.path == path)
+                } else {
+                    fs::symlink_metadata(root.join(path)).is_ok()
+                };
+                if exists {
+                    let place = if cached { "index" } else { "working directory" };
+                    return Err(GitError(format!("{}: already exists in {}", path, place)));
+                }
+                (Vec::new(), None)
+            }
+            Some(old_path) if cached => {
+                let entry = index
+                    .entries
+                    .iter()
+                    .find(|e| e.path == *old_path && e.stage() == 0)
+                    .ok_or_else(|| GitError(format!("{}: does not exist in index", old_path)))?;
+                match repo.read_object(&object::to_hex(&entry.sha))? {
+                    Object::Blob(content) => (content.to_vec(), Some(entry.mode)),
+                    _ => return Err(GitError(format!("Not a blob: {}", old_path))),
+                }
+            }
+            Some(old_path) => {
+                let file = root.join(old_path);
+                let missing = |_| GitError(format!("{}: No such file or directory", old_path));
+                let metadata = fs::symlink_metadata(&file).map_err(missing)?;
+                let mode = if metadata.file_type().is_symlink() {
+                    0o120000
+                } else if metadata.permissions().mode() & 0o111 != 0 {
+                    0o100755
+                } else {
+                    0o100644
+                };
+                (worktree_content(&file)?, Some(mode))
+            }
+        };
+        let content = patch::apply(&old, file).map_err(|hunk| {
+            eprintln!("error: patch failed: {}:{}", path, hunk.old_start);
+            eprint!("{}", hunk.text);
+            GitError(format!("{}: patch does not apply", path))
+        })?;
+        if file.new_path.is_none() && !content.is_empty() {
+            return Err(GitError(format!(
+                "{}: removal patch leaves file contents",
+                path
+            )));
+        }
+        let mode = file.new_mode.or(old_mode).unwrap_or(0o100644);
+        results.push((file, content, mode));
+    }
+
+    for (file, content, mode) in results {
+        // A deleted or renamed file goes from where it was.
+        if let Some(old_path) = file
+            .old_path
+            .as_ref()
+            .filter(|p| file.new_path.as_ref() != Some(p))
+        {
+            if cached {
+                index.remove(old_path);
+            } else {
+                remove_file(repo, Path::new(old_path))?;
+            }
+        }
+        let path = match &file.new_path {
+            Some(path) => path,
+            None => continue,
+        };
+        if cached {
+            let sha = repo.write_object(&Object::Blob(Bytes::from(content)))?;
+            index.add(IndexEntry {
+                mode,
+                sha,
+                path: path.clone(),
+                ..Default::default()
+            });
+            continue;
+        }
+        let file_path = root.join(path);
+        if let Some(dir) = file_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let is_symlink = fs::symlink_metadata(&file_path).is_ok_and(|m| m.file_type().is_symlink());
+        if is_symlink || (mode == 0o120000 && file_path.exists()) {
+            fs::remove_file(&file_path)?;
+        }
+        if mode == 0o120000 && repo.symlinks {
+            std::os::unix::fs::symlink(String::from_utf8_lossy(&content).as_ref(), &file_path)?;
+            continue;
+        }
+        fs::write(&file_path, content)?;
+        if file.new_mode.is_some() {
+            let permissions = if mode == 0o100755 { 0o755 } else { 0o644 };
+            fs::set_permissions(&file_path, fs::Permissions::from_mode(permissions))?;
+        }
+    }
+    if cached {
+        index.write(&index_path)?;
+    }
+    Ok(())
+}
+
+/// Stages the file at `path`, or everything under it if it's a directory.
+/// Tracked files that are gone from the working tree are unstaged.
+fn add_path(repo: &Repository, index: &mut Index, path: &str) -> GitResult<()> {
+    let file = Path::new(repo.root()).join(path);
+    let metadata = match fs::symlink_metadata(&file) {
+        Ok(metadata) => metadata,
+        Err(_) => {
+            let dir = format!("{}/", path);
+            let tracked: Vec<String> = index
+                .entries
+                .iter()
+                .filter(|e| e.path == path || e.path.starts_with(&dir))
+                .map(|e| e.path.clone())
+                .collect();
+            if tracked.is_empty() {
+                return Err(GitError(format!(
+                    "pathspec '{}' did not match any files",
+                    path
+                )));
+            }
+            for tracked in tracked {
+                index.remove(&tracked);
+            }
+            return Ok(());
+        }
+    };
+
+    if metadata.is_dir() {
+        let prefix = if path.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", path)
+        };
+        let deleted: Vec<String> = index
+            .entries
+            .iter()
+            .filter(|e| e.path.starts_with(&prefix))
+            .filter(|e| fs::symlink_metadata(Path::new(repo.root()).join(&e.path)).is_err())
+            .map(|e| e.path.clone())
+            .collect();
+        for path in deleted {
+            index.remove(&path);
+        }
+        for entry in fs::read_dir(&file)? {
+            let name = entry?.file_name();
+            let name = name.to_str().ok_or("Could not get a file path")?;
+            if name != ".git" {
+                add_path(repo, index, &format!("{}{}", prefix, name))?;
+            }
+        }
+        return Ok(());
+    }
+
+    let content = worktree_content(&file)?;
+    let sha = repo.write_object(&Object::Blob(Bytes::from(content)))?;
+    // On a case-insensitive filesystem, the file is the tracked one whatever
+    // case it was named in.
+    let tracked = index
+        .entries
+        .iter()
+        .find(|e| e.path == path || (repo.ignore_case && e.path.eq_ignore_ascii_case(path)));
+    let mut entry = IndexEntry::from_file(&file, tracked.map_or(path, |e| &e.path), sha)?;
+    // Without symlinks, a tracked symlink is checked out as a plain file.
+    if !repo.symlinks && tracked.is_some_and(|e| e.mode == 0o120000) {
+        entry.mode = 0o120000;
+    }
+    if let Some(tracked) = tracked.filter(|e| e.path != path) {
+        let tracked = tracked.path.clone();
+        index.remove(&tracked);
+    }
+    index.add(entry);
+    Ok(())
+}
+
+/// Reads a file in the working tree the way it's stored: a symlink as the
+/// path it points to.
+fn worktree_content(file: &Path) -> GitResult<Vec<u8>> {
+    if fs::symlink_metadata(file)?.file_type().is_symlink() {
+        let target = fs::read_link(file)?;
+        Ok(target
+            .to_str()
+            .ok_or("Could not get a symlink target")?
+            .as_bytes()
+            .to_vec())
+    } else {
+        Ok(fs::read(file)?)
+    }
+}
+
+/// Writes the trees for the staged entries, reusing those the index's cache
+/// tree still has and recording the ones it had to write.
+fn write_index_tree(repo: &Repository) -> GitResult<Sha> {
+    let path = repo.index_path();
+    let mut index = Index::read(&path)?;
+    if let Some(entry) = index.entries.iter().find(|e| e.stage() != 0) {
+        return Err(GitError(format!("{}: unmerged entry", entry.path)));
+    }
+    let mut cache_tree = index.cache_tree.take().unwrap_or_default();
+    let sha = write_cached_tree(repo, &index.entries, "", &mut cache_tree)?;
+    index.cache_tree = Some(cache_tree);
+    if !repo.dry_run {
+        index.write(&path)?;
+    }
+    Ok(sha)
+}
+
+/// Writes the tree for `entries`, which are all the entries under `prefix`.
+fn write_cached_tree(
+    repo: &Repository,
+    entries: &[IndexEntry],
+    prefix: &str,
+    node: &mut CacheTree,
+) -> GitResult<Sha> {
+    if let Some((count, sha)) = node.tree {
+        if count == entries.len() {
+            return Ok(sha);
+        }
+    }
+    let mut old_children = std::mem::take(&mut node.children);
+    let mut refs = Vec::new();
+    let mut i = 0;
+    while i < entries.len() {
+        let name = &entries[i].path[prefix.len()..];
+        match name.find('/') {
+            Some(slash) => {
+                let dir = &name[..slash];
+                let dir_prefix = format!("{}{}/", prefix, dir);
+                // The entries under a directory sort next to each other.
+                let end = i + entries[i..]
+                    .iter()
+                    .take_while(|e| e.path.starts_with(&dir_prefix))
+                    .count();
+                let mut child = match old_children.iter().position(|(n, _)| n == dir) {
+                    Some(position) => old_children.swap_remove(position).1,
+                    None => CacheTree::default(),
+                };
+                let hash = write_cached_tree(repo, &entries[i..end], &dir_prefix, &mut child)?;
+                refs.push(ObjectReference {
+                    mode: 40000,
+                    padded_mode: false,
+                    name: dir.to_owned().into(),
+                    hash,
+                });
+                node.children.push((dir.to_owned(), child));
+                i = end;
+            }
+            None => {
+                refs.push(ObjectReference {
+                    mode: format!("{:o}", entries[i].mode).parse()?,
+                    padded_mode: false,
+                    name: name.to_owned().into(),
+                    hash: entries[i].sha,
+                });
+                i += 1;
+            }
+        }
+    }
+    let sha = repo.write_tree_entries(refs)?;
+    node.tree = Some((entries.len(), sha));
+    Ok(sha)
+}
+
+/// Resolves an object name to a SHA. Besides revisions, `<rev>:<path>` names
+/// the tree entry at `path` within the tree of `rev`.
+fn resolve_object(repo: &Repository, name: &str) -> GitResult<String> {
+    let mut parts = name.splitn(2, ':');
+    let rev = parts.next().unwrap_or("");
+    // `<rev>^{<type>}` peels tags until reaching that type; `<rev>^{}` peels
+    // them all.
+    let sha = match rev.strip_suffix('}').and_then(|r| r.rsplit_once("^{")) {
+        Some((base, kind)) => peel_to(repo, &refs::resolve_rev(repo.root(), base)?, kind)?,
+        None => refs::resolve_rev(repo.root(), rev)?,
+    };
+    let path = match parts.next() {
+        Some(path) => path,
+        None => return Ok(sha),
+    };
+
+    let tree = match peel(repo, &sha)? {
+        (_, Object::Commit { tree, .. }) => tree,
+        (tree, Object::Tree(_)) => tree,
+        _ => return Err(GitError(format!("Not a tree-ish: {}", name))),
+    };
+    lookup_path(repo, &tree, path)?
+        .ok_or_else(|| GitError(format!("Path not found: {} in {}", path, name)))
+}
+
+/// Git stops following a chain of tags that name tags after this many.
+const MAX_TAG_DEPTH: usize = 32;
+
+/// Follows tags from `sha`, which may name further tags in turn, to the
+/// object they finally name.
+fn peel(repo: &Repository, sha: &str) -> GitResult<(String, Object)> {
+    let mut sha = sha.to_owned();
+    for _ in 0..=MAX_TAG_DEPTH {
+        match repo.read_object(&sha)? {
+            Object::Tag { object, .. } => sha = object,
+            obj => return Ok((sha, obj)),
+        }
+    }
+    Err(GitError(format!("Tag chain too deep at {}", sha)))
+}
+
+/// Peels `sha` to an object of type `kind`, as `<rev>^{<kind>}` does. An
+/// empty kind peels all tags; a commit peels further to its tree.
+fn peel_to(repo: &Repository, sha: &str, kind: &str) -> GitResult<String> {
+    if kind == "object" {
+        return Ok(sha.to_owned());
+    }
+    if kind == "tag" {
+        return match repo.read_object(sha)? {
+            Object::Tag { .. } => Ok(sha.to_owned()),
+            obj => Err(GitError(format!("{} is a {}, not a tag", sha, obj.kind()))),
+        };
+    }
+    match (kind, peel(repo, sha)?) {
+        ("", (peeled, _)) => Ok(peeled),
+        ("tree", (_, Object::Commit { tree, .. })) => Ok(tree),
+        (kind, (peeled, obj)) if obj.kind() == kind => Ok(peeled),
+        (kind, (_, obj)) if matches!(kind, "commit" | "tree" | "blob") => Err(GitError(format!(
+            "{} dereferences to a {}, not a {}",
+            sha,
+            obj.kind(),
+            kind
+        ))),
+        (kind, _) => Err(GitError(format!("Unknown object type: {}", kind))),
+    }
+}
+
+/// Finds the blob holding the note for an object, in the notes ref named by
+/// `GIT_NOTES_REF` or `core.notesRef`, or else `refs/notes/commits`. A notes
+/// tree may fan out, keeping the note for `abcd...` at `ab/cd...`, so each
+/// level is searched for the rest of the SHA, then for its next two digits.
+fn find_note(repo: &Repository, sha: &str) -> GitResult<Option<String>> {
+    let config = Config::load(repo.root())?;
+    let notes_ref = env::var("GIT_NOTES_REF")
+        .ok()
+        .or_else(|| config.get("core", None, "notesref").map(str::to_owned))
+        .unwrap_or_else(|| "refs/notes/commits".to_owned());
+    let notes = match refs::read_ref(repo.root(), &notes_ref)? {
+        Some(notes) => notes,
+        None => return Ok(None),
+    };
+    let mut tree = repo.read_object(&peel_to(repo, &notes, "tree")?)?;
+    let mut rest = sha;
+    loop {
+        if let Some(note) = tree.tree_entry(rest) {
+            return Ok(Some(object::to_hex(&note.hash)));
+        }
+        match tree.tree_entry(&rest[..2]) {
+            Some(dir) if dir.mode == 40000 && rest.len() > 2 => {
+                tree = repo.read_object(&object::to_hex(&dir.hash))?;
+                rest = &rest[2..];
+            }
+            _ => return Ok(None),
+        }
+    }
+}
+
+/// Finds the SHA of the entry at `path` under a tree, if there's one.
+fn lookup_path(repo: &Repository, tree: &str, path: &str) -> GitResult<Option<String>> {
+    let mut sha = tree.to_owned();
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        let tree = repo.read_object(&sha)?;
+        if !matches!(tree, Object::Tree(_)) {
+            return Ok(None);
+        }
+        match tree.tree_entry(component) {
+            Some(r) => sha = object::to_hex(&r.hash),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(sha))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renames_are_named_as_git_does() {
+        // As `git diff --stat -M` names them.
+        for (old, new, name) in [
+            ("a/b/c.txt", "a/d/c.txt", "a/{b => d}/c.txt"),
+            ("dir/a.txt", "dir/b.txt", "dir/{a.txt => b.txt}"),
+            ("old.txt", "new.txt", "old.txt => new.txt"),
+            ("x/f", "y/x/f", "{x => y/x}/f"),
+            ("p/g", "q/p/g", "{p => q/p}/g"),
+            ("f", "sub/f", "f => sub/f"),
+        ] {
+            assert_eq!(rename_name(old, new), name);
+        }
     }
-    Ok(hash)
 }
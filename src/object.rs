@@ -1,3 +1,6 @@
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+
 use bytes::Bytes;
 use sha1::{Digest, Sha1};
 
@@ -6,7 +9,7 @@ use crate::parser::{parse_string_until, take_until};
 
 pub type Sha = [u8; 20];
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Object {
     Blob(Bytes),
     Tree(Vec<ObjectReference>),
@@ -15,80 +18,360 @@ pub enum Object {
         parents: Vec<String>,
         author: Contributor,
         committer: Contributor,
-        message: String,
+        /// Usually UTF-8, but as the `encoding` header allows, not always.
+        message: Bytes,
+        /// Whether the header lines end in `\r\n`, as a few tools write them.
+        /// Kept so that such commits encode back to the same SHA.
+        crlf_headers: bool,
+        /// Headers after the committer, such as `encoding`, `mergetag` or
+        /// `gpgsig`, in order, each as it was read. The lines of a multi-line
+        /// value are joined with `\n`, keeping the space that starts each.
+        extra_headers: Vec<Bytes>,
+        /// Whether a blank line ends the headers. Only a commit without a
+        /// message can lack one, which git reads though it never writes.
+        blank_line: bool,
+    },
+    /// An annotated tag, pointing at another object (usually a commit).
+    Tag {
+        object: String,
+        kind: String,
+        tag: String,
+        /// Missing from some very old tags.
+        tagger: Option<Contributor>,
+        /// Any other headers, kept like a commit's `extra_headers`.
+        extra_headers: Vec<Bytes>,
+        /// Like a commit's `blank_line`.
+        blank_line: bool,
+        message: Bytes,
     },
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ObjectReference {
+    /// The mode's octal digits read as a decimal number, as trees store them:
+    /// `40000` for a tree, `100644` for a file.
     pub mode: usize,
-    pub name: String,
+    /// Whether the mode was written with a leading zero, as some old tools
+    /// did. Kept so that such trees encode back to the same SHA.
+    pub padded_mode: bool,
+    /// Usually UTF-8, but like a path on disk, any bytes.
+    pub name: Bytes,
     pub hash: Sha,
 }
 
+impl ObjectReference {
+    /// The type of object the entry points to, going by its mode. Submodules
+    /// point to a commit in another repository.
+    pub fn kind(&self) -> &'static str {
+        match self.mode {
+            40000 => "tree",
+            160000 => "commit",
+            _ => "blob",
+        }
+    }
+}
+
+/// Checks that a tree entry's name is safe to use as a path component in a
+/// working tree: not empty, `.` or `..`, without slashes or NULs, and not
+/// `.git` in any case, which would reach into the repository itself.
+pub fn check_entry_name(name: &[u8]) -> GitResult<()> {
+    if name.is_empty()
+        || name == b"."
+        || name == b".."
+        || name.iter().any(|&b| b == b'/' || b == 0)
+        || name.eq_ignore_ascii_case(b".git")
+    {
+        return Err(GitError(format!(
+            "Invalid tree entry name: {:?}",
+            String::from_utf8_lossy(name)
+        )));
+    }
+    Ok(())
+}
+
+/// Puts tree entries into git's canonical order, comparing names bytewise
+/// as if those of trees ended with a slash. Since names are unique within a
+/// tree, no two entries compare equal.
+pub fn sort_tree_entries(entries: &mut [ObjectReference]) {
+    entries.sort_by_cached_key(|entry| {
+        let mut key = entry.name.to_vec();
+        if entry.mode == 40000 {
+            key.push(b'/');
+        }
+        key
+    });
+}
+
+/// Formats the entry as `ls-tree` lists it, with the mode padded to six
+/// digits. Only the listing pads it; trees store `40000` for a directory.
+impl std::fmt::Display for ObjectReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:06} {} {}\t{}",
+            self.mode,
+            self.kind(),
+            to_hex(&self.hash),
+            String::from_utf8_lossy(&self.name)
+        )
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Contributor {
     pub name: String,
     pub email: String,
-    pub timestamp: u64,
+    /// Seconds since the epoch; negative before 1970, as git allows.
+    pub timestamp: i64,
     pub timezone: String,
+    /// The line as it was read, when the fields above don't reproduce it
+    /// byte for byte, so that the object is written back unchanged.
+    pub raw: Option<Bytes>,
+}
+
+impl Contributor {
+    /// The contributor as it's written in an object: `Name <email> date`.
+    pub fn line(&self) -> Vec<u8> {
+        match &self.raw {
+            Some(line) => line.to_vec(),
+            None => format!(
+                "{} <{}> {} {}",
+                self.name, self.email, self.timestamp, self.timezone
+            )
+            .into_bytes(),
+        }
+    }
+
+    /// Formats the timestamp in the contributor's own timezone, the way
+    /// `git log` does by default: `Mon Jan 2 15:04:05 2006 +0200`.
+    pub fn format_date(&self) -> GitResult<String> {
+        const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+
+        let local = self
+            .timestamp
+            .saturating_add(parse_timezone_offset(&self.timezone)?);
+        let days = local.div_euclid(86400);
+        let seconds = local.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+
+        Ok(format!(
+            "{} {} {} {:02}:{:02}:{:02} {} {}",
+            DAYS[(days + 4).rem_euclid(7) as usize],
+            MONTHS[month as usize - 1],
+            day,
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60,
+            year,
+            self.timezone
+        ))
+    }
+
+    /// Formats the timestamp in the contributor's own timezone, the way
+    /// `git blame` does: `2006-01-02 15:04:05 +0200`.
+    pub fn format_iso_date(&self) -> GitResult<String> {
+        let local = self
+            .timestamp
+            .saturating_add(parse_timezone_offset(&self.timezone)?);
+        let seconds = local.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(local.div_euclid(86400));
+        Ok(format!(
+            "{}-{:02}-{:02} {:02}:{:02}:{:02} {}",
+            year,
+            month,
+            day,
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60,
+            self.timezone
+        ))
+    }
+}
+
+/// Converts a `+hhmm`/`-hhmm` timezone into an offset in seconds.
+pub fn parse_timezone_offset(timezone: &str) -> GitResult<i64> {
+    let invalid = || GitError(format!("Invalid timezone: {}", timezone));
+    if timezone.len() != 5 || !timezone.is_char_boundary(1) {
+        return Err(invalid());
+    }
+    let sign = match &timezone[..1] {
+        "+" => 1,
+        "-" => -1,
+        _ => return Err(invalid()),
+    };
+    let hours: i64 = timezone[1..3].parse().map_err(|_| invalid())?;
+    let minutes: i64 = timezone[3..5].parse().map_err(|_| invalid())?;
+    Ok(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Parses a date as git accepts it in `GIT_AUTHOR_DATE` and
+/// `GIT_COMMITTER_DATE`: either git's own `<seconds> <timezone>` (optionally
+/// with a leading `@`), or ISO 8601 like `2005-04-07T22:13:13 +0200`.
+/// Returns the timestamp and the timezone.
+pub fn parse_date(date: &str) -> GitResult<(i64, String)> {
+    let invalid = || GitError(format!("Invalid date: {}", date));
+    let (time, timezone) = date.trim().rsplit_once(' ').ok_or_else(invalid)?;
+    let offset = parse_timezone_offset(timezone)?;
+    if let Ok(timestamp) = time.trim_start_matches('@').parse() {
+        return Ok((timestamp, timezone.to_owned()));
+    }
+    let (day, time) = time
+        .split_once('T')
+        .or_else(|| time.split_once(' '))
+        .ok_or_else(invalid)?;
+    let numbers = |s: &str, sep| -> GitResult<Vec<i64>> {
+        let parts: Result<Vec<i64>, _> = s.split(sep).map(str::parse).collect();
+        parts.ok().filter(|p| p.len() == 3).ok_or_else(invalid)
+    };
+    let (day, time) = (numbers(day, '-')?, numbers(time, ':')?);
+    let local =
+        days_from_civil(day[0], day[1], day[2]) * 86400 + time[0] * 3600 + time[1] * 60 + time[2];
+    Ok((local - offset, timezone.to_owned()))
+}
+
+/// Turns a (year, month, day) date into a number of days since 1970-01-01.
+/// See http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Turns a number of days since 1970-01-01 into a (year, month, day) date.
+/// See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
 }
 
 impl Object {
+    /// Looks up an entry of a tree by name. Returns `None` for other object
+    /// types. Entries stay in their canonical order in the tree itself.
+    pub fn tree_entry(&self, name: &str) -> Option<&ObjectReference> {
+        match self {
+            Self::Tree(refs) => refs.iter().find(|r| r.name == name.as_bytes()),
+            _ => None,
+        }
+    }
+
     pub fn content(&self) -> GitResult<String> {
         match self {
             Self::Blob(bytes) => Ok(std::str::from_utf8(bytes)?.to_owned()),
             Self::Tree(refs) => {
                 let mut res = String::new();
                 for r in refs {
-                    res.push_str(&format!(
-                        "{:0>6} {} {}    {}",
-                        r.mode,
-                        if r.mode.to_string().starts_with('1') {
-                            "blob"
-                        } else {
-                            "tree"
-                        },
-                        to_hex(&r.hash),
-                        r.name
-                    ));
-                    res.push('\n')
+                    res.push_str(&format!("{}\n", r));
                 }
                 Ok(res)
             }
-            Self::Commit {
-                tree,
-                parents,
-                author,
-                committer,
-                message,
-            } => {
-                let mut content = String::new();
-
-                content.push_str(&format!("tree {}\n", tree));
+            Self::Commit { .. } => Ok(String::from_utf8_lossy(&self.commit_content()).into_owned()),
+            Self::Tag { .. } => Ok(String::from_utf8_lossy(&self.tag_content()).into_owned()),
+        }
+    }
 
-                for parent in parents {
-                    content.push_str(&format!("parent {}\n", parent));
-                }
+    /// The body of a commit object, as it's hashed and stored.
+    fn commit_content(&self) -> Vec<u8> {
+        let mut content = Vec::new();
+        if let Self::Commit {
+            tree,
+            parents,
+            author,
+            committer,
+            message,
+            crlf_headers,
+            extra_headers,
+            blank_line,
+        } = self
+        {
+            let eol: &[u8] = if *crlf_headers { b"\r\n" } else { b"\n" };
+            push_header(&mut content, format!("tree {}", tree).as_bytes(), eol);
+            for parent in parents {
+                push_header(&mut content, format!("parent {}", parent).as_bytes(), eol);
+            }
+            push_header(
+                &mut content,
+                &[&b"author "[..], &author.line()].concat(),
+                eol,
+            );
+            push_header(
+                &mut content,
+                &[&b"committer "[..], &committer.line()].concat(),
+                eol,
+            );
+            for header in extra_headers {
+                push_header(&mut content, header, eol);
+            }
+            if *blank_line {
+                content.extend_from_slice(eol);
+            }
+            content.extend_from_slice(message);
+        }
+        content
+    }
 
-                content.push_str(&format!(
-                    "author {} <{}> {} {}\n",
-                    author.name, author.email, author.timestamp, author.timezone
-                ));
-                content.push_str(&format!(
-                    "committer {} <{}> {} {}\n",
-                    committer.name, committer.email, committer.timestamp, committer.timezone
-                ));
-
-                content.push('\n');
-                content.push_str(&message);
-                Ok(content)
+    /// The body of a tag object, as it's hashed and stored.
+    fn tag_content(&self) -> Vec<u8> {
+        let mut content = Vec::new();
+        if let Self::Tag {
+            object,
+            kind,
+            tag,
+            tagger,
+            extra_headers,
+            blank_line,
+            message,
+        } = self
+        {
+            content.extend_from_slice(
+                format!("object {}\ntype {}\ntag {}\n", object, kind, tag).as_bytes(),
+            );
+            if let Some(tagger) = tagger {
+                push_header(
+                    &mut content,
+                    &[&b"tagger "[..], &tagger.line()].concat(),
+                    b"\n",
+                );
+            }
+            for header in extra_headers {
+                push_header(&mut content, header, b"\n");
+            }
+            if *blank_line {
+                content.push(b'\n');
             }
+            content.extend_from_slice(message);
         }
+        content
     }
 
-    pub fn encode(&self) -> (Sha, Bytes) {
+    /// The type name used in object headers, e.g. `blob`.
+    pub fn kind(&self) -> &'static str {
         match self {
+            Self::Blob(_) => "blob",
+            Self::Tree(_) => "tree",
+            Self::Commit { .. } => "commit",
+            Self::Tag { .. } => "tag",
+        }
+    }
+
+    /// The object in the loose format, `<type> <size>\0` and its content,
+    /// along with its SHA. Trees must not name an entry twice.
+    pub fn encode(&self) -> GitResult<(Sha, Bytes)> {
+        Ok(match self {
             Self::Blob(bytes) => {
                 let mut res = Vec::new();
                 res.extend_from_slice(b"blob ");
@@ -98,13 +381,23 @@ impl Object {
                 (get_sha(&res), Bytes::from(res))
             }
             Self::Tree(refs) => {
+                let mut names = HashSet::new();
+                if let Some(r) = refs.iter().find(|r| !names.insert(&r.name)) {
+                    return Err(GitError(format!(
+                        "Duplicate tree entry: {}",
+                        String::from_utf8_lossy(&r.name)
+                    )));
+                }
                 let mut res = Vec::new();
                 res.extend_from_slice(b"tree ");
                 let mut content = Vec::new();
                 for r in refs {
+                    if r.padded_mode {
+                        content.push(b'0');
+                    }
                     content.extend_from_slice(r.mode.to_string().as_bytes());
                     content.push(b' ');
-                    content.extend_from_slice(r.name.as_bytes());
+                    content.extend_from_slice(&r.name);
                     content.push(b'\0');
                     content.extend(&r.hash);
                 }
@@ -113,71 +406,64 @@ impl Object {
                 res.extend(content);
                 (get_sha(&res), Bytes::from(res))
             }
-            Self::Commit {
-                tree,
-                parents,
-                author,
-                committer,
-                message,
-            } => {
-                let mut res = Vec::new();
-                res.extend_from_slice(b"commit ");
-                let mut content = Vec::new();
-
-                content.extend_from_slice(b"tree ");
-                content.extend_from_slice(tree.as_bytes());
-                content.push(b'\n');
-
-                for parent in parents {
-                    content.extend_from_slice(b"parent ");
-                    content.extend_from_slice(parent.as_bytes());
-                    content.push(b'\n');
-                }
-
-                content.extend_from_slice(
-                    format!(
-                        "author {} <{}> {} {}\n",
-                        author.name, author.email, author.timestamp, author.timezone
-                    )
-                    .as_bytes(),
-                );
-                content.extend_from_slice(
-                    format!(
-                        "committer {} <{}> {} {}\n",
-                        committer.name, committer.email, committer.timestamp, committer.timezone
-                    )
-                    .as_bytes(),
-                );
-
-                content.push(b'\n');
-                content.extend_from_slice(message.as_bytes());
-
-                res.extend_from_slice(content.len().to_string().as_bytes());
-                res.push(b'\0');
+            Self::Commit { .. } => {
+                let content = self.commit_content();
+                let mut res = format!("commit {}\0", content.len()).into_bytes();
                 res.extend(content);
                 (get_sha(&res), Bytes::from(res))
             }
-        }
+            Self::Tag { .. } => {
+                let content = self.tag_content();
+                let mut res = format!("tag {}\0", content.len()).into_bytes();
+                res.extend(content);
+                (get_sha(&res), Bytes::from(res))
+            }
+        })
+    }
+
+    /// Iterates over the lines of a blob without copying it, dropping the
+    /// `\n` or `\r\n` that ends each one. Other objects have no lines.
+    pub fn blob_lines(&self) -> impl Iterator<Item = &[u8]> {
+        let mut rest: &[u8] = match self {
+            Self::Blob(bytes) => bytes,
+            _ => &[],
+        };
+        std::iter::from_fn(move || {
+            if rest.is_empty() {
+                return None;
+            }
+            let end = rest
+                .iter()
+                .position(|&b| b == b'\n')
+                .map_or(rest.len(), |i| i + 1);
+            let (line, tail) = rest.split_at(end);
+            rest = tail;
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            Some(line.strip_suffix(b"\r").unwrap_or(line))
+        })
+    }
+
+    /// The content of the object as it's hashed, minus the `<type> <size>\0`
+    /// header of loose objects.
+    pub fn body(&self) -> GitResult<Bytes> {
+        let (_, data) = self.encode()?;
+        let start = data.iter().position(|&b| b == 0).map_or(0, |i| i + 1);
+        Ok(data.slice(start..))
     }
 
     pub fn decode(bytes: Bytes) -> GitResult<Self> {
-        let i = bytes
-            .iter()
-            .position(|&b| b == b'\0')
-            .ok_or("No null character found in object")?
-            + 1;
-
-        if &bytes[0..4] == b"blob" {
-            Object::decode_blob(bytes.slice(i..))
-        } else if &bytes[0..4] == b"tree" {
-            Object::decode_tree(bytes.slice(i..))
-        } else if &bytes[0..6] == b"commit" {
-            Object::decode_commit(bytes.slice(i..))
-        } else {
-            Err(GitError(format!(
-                "Unsupported object type: {}",
-                parse_string_until(&bytes, b' ')?
-            )))
+        let (kind, content) = split_header(&bytes)?;
+        Object::decode_as(&kind, content)
+    }
+
+    /// Decodes the content of an object of the given type, header excluded.
+    pub fn decode_as(kind: &str, content: Bytes) -> GitResult<Self> {
+        match kind {
+            "blob" => Object::decode_blob(content),
+            "tree" => Object::decode_tree(content),
+            "commit" => Object::decode_commit(content),
+            "tag" => Object::decode_tag(content),
+            _ => Err(GitError(format!("Unsupported object type: {}", kind))),
         }
     }
 
@@ -191,45 +477,68 @@ impl Object {
         let mut refs = Vec::new();
         while i < bytes.len() {
             let mode_bytes = take_until(&bytes[i..], b' ');
+            // Old trees may pad a mode with a zero, which git still reads but
+            // never writes.
             let mode: usize = std::str::from_utf8(&mode_bytes)?.parse()?;
+            let padded_mode = mode_bytes.first() == Some(&b'0');
             i += mode_bytes.len() + 1;
-            let name = parse_string_until(&bytes[i..], b'\0')?;
-            i += name.len() + 1;
+            let name_len = bytes
+                .get(i..)
+                .unwrap_or_default()
+                .iter()
+                .position(|&b| b == b'\0')
+                .ok_or("Missing end of tree entry name")?;
+            let name = bytes.slice(i..i + name_len);
+            i += name_len + 1;
             let mut hash = [0u8; 20];
-            hash.copy_from_slice(&bytes[i..i + 20]);
+            hash.copy_from_slice(bytes.get(i..i + 20).ok_or("Tree entry is truncated")?);
             i += 20;
-            refs.push(ObjectReference { mode, name, hash });
+            refs.push(ObjectReference {
+                mode,
+                padded_mode,
+                name,
+                hash,
+            });
         }
         Ok(Self::Tree(refs))
     }
 
     pub fn decode_commit(bytes: Bytes) -> GitResult<Self> {
-        let mut i = 5; // "tree "
-        let tree = parse_string_until(&bytes[i..], b'\n')?;
-        i += tree.len() + 1;
+        let mut i = 0;
+        let (line, crlf_headers) = header_line(&bytes, &mut i);
+        let tree = header_value(line, "tree ")?;
 
         let mut parents = Vec::new();
-        while let b"parent" = &bytes[i..i + 6] {
-            i += 7; // "parent "
-            let parent = parse_string_until(&bytes[i..], b'\n')?;
-            i += parent.len() + 1;
-            parents.push(parent);
+        let mut line = header_line(&bytes, &mut i).0;
+        while line.starts_with(b"parent ") {
+            parents.push(header_value(line, "parent ")?);
+            line = header_line(&bytes, &mut i).0;
         }
 
-        i += 7; // "author "
-        let author_result = crate::parser::parse_contributor(&bytes[i..])?;
-        i += author_result.0;
-        let author = author_result.1;
-
-        i += 10; // "committer "
-        let committer_result = crate::parser::parse_contributor(&bytes[i..])?;
-        i += committer_result.0;
-        let committer = committer_result.1;
+        let author = match line.strip_prefix(&b"author "[..]) {
+            Some(author) => crate::parser::parse_contributor(author)?.1,
+            None => return Err(GitError("Missing author in commit".to_owned())),
+        };
+        let line = header_line(&bytes, &mut i).0;
+        let committer = match line.strip_prefix(&b"committer "[..]) {
+            Some(committer) => crate::parser::parse_contributor(committer)?.1,
+            None => return Err(GitError("Missing committer in commit".to_owned())),
+        };
 
-        i += 1; // double newline before the commit message
+        // Further headers run up to the blank line before the commit message,
+        // or the end of a commit without one.
+        let mut extra_headers = Vec::new();
+        let mut blank_line = false;
+        while i < bytes.len() {
+            let line = header_line(&bytes, &mut i).0;
+            if line.is_empty() {
+                blank_line = true;
+                break;
+            }
+            push_header_line(&mut extra_headers, line);
+        }
 
-        let message =
-            std::str::from_utf8(&bytes[i..].iter().copied().collect::<Vec<u8>>())?.to_owned();
+        let message = bytes.slice(i..);
 
         let commit = Self::Commit {
             tree,
@@ -237,18 +546,208 @@ impl Object {
             author,
             committer,
             message,
+            crlf_headers,
+            extra_headers: extra_headers.into_iter().map(Bytes::from).collect(),
+            blank_line,
         };
         Ok(commit)
     }
+
+    pub fn decode_tag(bytes: Bytes) -> GitResult<Self> {
+        let mut headers = Vec::new();
+        let mut i = 0;
+        // Headers run up to the blank line before the message.
+        while i < bytes.len() && bytes[i] != b'\n' {
+            let line = take_until(&bytes[i..], b'\n');
+            i += line.len() + 1;
+            headers.push(line);
+        }
+        let blank_line = i < bytes.len();
+        let message = bytes.slice((i + 1).min(bytes.len())..);
+
+        let header = |name: &str| -> GitResult<String> {
+            headers
+                .iter()
+                .find_map(|h| h.strip_prefix(format!("{} ", name).as_bytes()))
+                .map(|v| String::from_utf8_lossy(v).into_owned())
+                .ok_or_else(|| GitError(format!("Missing {} in tag", name)))
+        };
+        let tagger = match headers.iter().find_map(|h| h.strip_prefix(&b"tagger "[..])) {
+            Some(line) => Some(crate::parser::parse_contributor(line)?.1),
+            None => None,
+        };
+        let mut extra_headers = Vec::new();
+        for line in &headers {
+            let known = ["object ", "type ", "tag ", "tagger "]
+                .iter()
+                .any(|name| line.starts_with(name.as_bytes()));
+            if !known {
+                push_header_line(&mut extra_headers, line);
+            }
+        }
+        Ok(Self::Tag {
+            object: header("object")?,
+            kind: header("type")?,
+            tag: header("tag")?,
+            tagger,
+            extra_headers: extra_headers.into_iter().map(Bytes::from).collect(),
+            blank_line,
+            message,
+        })
+    }
+}
+
+/// Adds a header line to `headers`. A line starting with a space carries on
+/// the value of the header before it.
+fn push_header_line(headers: &mut Vec<Vec<u8>>, line: &[u8]) {
+    match headers.last_mut() {
+        Some(header) if line.starts_with(b" ") => {
+            header.push(b'\n');
+            header.extend_from_slice(line);
+        }
+        _ => headers.push(line.to_vec()),
+    }
+}
+
+/// Writes a header, ending each of its lines with `eol`.
+fn push_header(content: &mut Vec<u8>, header: &[u8], eol: &[u8]) {
+    for line in header.split(|&b| b == b'\n') {
+        content.extend_from_slice(line);
+        content.extend_from_slice(eol);
+    }
+}
+
+/// Whether content is binary rather than text, going by whether a NUL byte
+/// appears in its first 8000 bytes, as git decides.
+pub fn is_binary(content: &[u8]) -> bool {
+    content.iter().take(8000).any(|&b| b == 0)
+}
+
+/// Splits a loose object into the type named in its header and its content,
+/// without checking that the type is one git knows about.
+pub fn split_header(bytes: &Bytes) -> GitResult<(String, Bytes)> {
+    let i = bytes
+        .iter()
+        .position(|&b| b == b'\0')
+        .ok_or("No null character found in object")?;
+    let kind = parse_string_until(&bytes[..i], b' ')?;
+    Ok((kind, bytes.slice(i + 1..)))
+}
+
+pub fn is_known_type(kind: &str) -> bool {
+    matches!(kind, "blob" | "tree" | "commit" | "tag")
+}
+
+/// Computes a SHA-1 a piece at a time, for content streamed from disk or the
+/// network rather than held in memory whole. Writing to it hashes too, so
+/// `io::copy` can feed it.
+#[derive(Default)]
+pub struct ShaHasher(Sha1);
+
+impl ShaHasher {
+    pub fn new() -> ShaHasher {
+        ShaHasher::default()
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    pub fn finalize(self) -> Sha {
+        let mut sha = [0u8; 20];
+        sha.copy_from_slice(&self.0.finalize());
+        sha
+    }
+}
+
+impl Write for ShaHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Streams an object's content, checking as it goes that there's as much
+/// as its header declared and that it hashes to the SHA it was looked up by.
+/// Reading fails rather than stop short on a truncated or corrupt object.
+pub struct VerifiedReader<R> {
+    inner: io::Take<R>,
+    left: u64,
+    hasher: Option<ShaHasher>,
+    sha: Sha,
+}
+
+impl<R: Read> VerifiedReader<R> {
+    pub fn new(inner: R, kind: &str, size: usize, sha: Sha) -> VerifiedReader<R> {
+        let mut hasher = ShaHasher::new();
+        hasher.update(format!("{} {}\0", kind, size).as_bytes());
+        VerifiedReader {
+            inner: inner.take(size as u64),
+            left: size as u64,
+            hasher: Some(hasher),
+            sha,
+        }
+    }
+
+    fn check_sha(&mut self) -> io::Result<()> {
+        match self.hasher.take().map(ShaHasher::finalize) {
+            Some(sha) if sha != self.sha => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Object {} is corrupt: its content has another SHA",
+                    to_hex(&self.sha)
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<R: Read> Read for VerifiedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.left == 0 {
+            self.check_sha()?;
+            return Ok(0);
+        }
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Object {} is truncated: {} bytes missing",
+                    to_hex(&self.sha),
+                    self.left
+                ),
+            ));
+        }
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        self.left -= n as u64;
+        if self.left == 0 {
+            self.check_sha()?;
+        }
+        Ok(n)
+    }
 }
 
 pub fn get_sha(string: &[u8]) -> Sha {
-    let mut sha_one = Sha1::new();
-    sha_one.update(string);
-    let bytes = sha_one.finalize();
-    let mut sha = [0u8; 20];
-    sha[..20].copy_from_slice(&bytes);
-    sha
+    let mut hasher = ShaHasher::new();
+    hasher.update(string);
+    hasher.finalize()
+}
+
+/// The SHA of an object as stored: its `<type> <size>\0` header followed by
+/// the content exactly as it is, whether or not it would encode back the same.
+pub fn hash_content(kind: &str, content: &[u8]) -> Sha {
+    let mut hasher = ShaHasher::new();
+    hasher.update(format!("{} {}\0", kind, content.len()).as_bytes());
+    hasher.update(content);
+    hasher.finalize()
 }
 
 pub fn to_hex(bytes: &Sha) -> String {
@@ -258,3 +757,364 @@ pub fn to_hex(bytes: &Sha) -> String {
     }
     hash
 }
+
+pub fn from_hex(hex: &str) -> GitResult<Sha> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return Err(GitError(format!("Invalid SHA: {}", hex)));
+    }
+    let mut sha = [0u8; 20];
+    for (i, byte) in sha.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| GitError(format!("Invalid SHA: {}", hex)))?;
+    }
+    Ok(sha)
+}
+
+/// Reads the header line starting at `i` and moves `i` past it. The line is
+/// returned without its ending, along with whether that was `\r\n`.
+fn header_line<'a>(bytes: &'a [u8], i: &mut usize) -> (&'a [u8], bool) {
+    let rest = bytes.get(*i..).unwrap_or(&[]);
+    let end = rest.iter().position(|&b| b == b'\n').unwrap_or(rest.len());
+    *i += (end + 1).min(rest.len());
+    match rest[..end].strip_suffix(b"\r") {
+        Some(line) => (line, true),
+        None => (&rest[..end], false),
+    }
+}
+
+fn header_value(line: &[u8], prefix: &str) -> GitResult<String> {
+    match line.strip_prefix(prefix.as_bytes()) {
+        Some(value) => Ok(std::str::from_utf8(value)?.to_owned()),
+        None => Err(GitError(format!(
+            "Expected {}in commit header: {}",
+            prefix,
+            String::from_utf8_lossy(line)
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{MemoryStore, ObjectStore};
+
+    /// Decodes `raw` as an object of type `kind`, checking it encodes back to
+    /// exactly the same bytes and so the same SHA.
+    fn round_trip(kind: &str, raw: &[u8]) -> Object {
+        let obj = Object::decode_as(kind, Bytes::copy_from_slice(raw)).unwrap();
+        let (sha, data) = obj.encode().unwrap();
+        let mut expected = format!("{} {}\0", kind, raw.len()).into_bytes();
+        expected.extend_from_slice(raw);
+        assert_eq!(
+            String::from_utf8_lossy(&data),
+            String::from_utf8_lossy(&expected)
+        );
+        assert_eq!(sha, hash_content(kind, raw));
+        obj
+    }
+
+    #[test]
+    fn blob_has_gits_sha() {
+        let (sha, _) = Object::Blob(Bytes::from_static(b"hello\n"))
+            .encode()
+            .unwrap();
+        assert_eq!(to_hex(&sha), "ce013625030ba8dba906f756967f9e9ca394464a");
+    }
+
+    #[test]
+    fn empty_tree_has_gits_sha() {
+        let (sha, _) = Object::Tree(Vec::new()).encode().unwrap();
+        assert_eq!(to_hex(&sha), "4b825dc642cb6eb9a060e54bf8d69288fbee4904");
+    }
+
+    fn entry(mode: usize, name: &str) -> ObjectReference {
+        ObjectReference {
+            mode,
+            padded_mode: false,
+            name: Bytes::from(name.to_owned()),
+            hash: from_hex("ce013625030ba8dba906f756967f9e9ca394464a").unwrap(),
+        }
+    }
+
+    #[test]
+    fn trees_sort_as_git_does() {
+        // The directory `a` sorts as `a/`: after `a.b`, but before `a0`.
+        let mut refs = vec![
+            entry(100644, "a0"),
+            entry(40000, "a"),
+            entry(100644, "a.b"),
+            entry(100644, "a-"),
+        ];
+        sort_tree_entries(&mut refs);
+        let names: Vec<&[u8]> = refs.iter().map(|r| &r.name[..]).collect();
+        assert_eq!(names, [&b"a-"[..], b"a.b", b"a", b"a0"]);
+        // As `git mktree` makes it.
+        let (sha, _) = Object::Tree(refs).encode().unwrap();
+        assert_eq!(to_hex(&sha), "4b1bf72c256040e530e40077665bb29268766335");
+
+        // A file `a`, unlike a directory, sorts before `a.b`.
+
+        let mut refs = vec![entry(100644, "a.b"), entry(100644, "a")];
+        sort_tree_entries(&mut refs);
+        let names: Vec<&[u8]> = refs.iter().map(|r| &r.name[..]).collect();
+        assert_eq!(names, [&b"a"[..], b"a.b"]);
+    }
+
+    #[test]
+    fn unsafe_entry_names_are_rejected() {
+        for name in &["", ".", "..", "a/b", "a\0", ".git", ".GIT", ".Git"] {
+            assert!(check_entry_name(name.as_bytes()).is_err(), "{:?}", name);
+        }
+        for name in &["a", ".gitignore", "..a", "git"] {
+            assert!(check_entry_name(name.as_bytes()).is_ok(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn tree_order_does_not_depend_on_input_order() {
+        // Every order the entries can come in gives the same tree, stored
+        // and read back in git's order.
+        let store = MemoryStore::default();
+        let names = ["a0", "a", "a.b", "a-"];
+        for first in 0..names.len() {
+            let mut refs: Vec<ObjectReference> = names
+                .iter()
+                .cycle()
+                .skip(first)
+                .take(names.len())
+                .map(|&name| entry(if name == "a" { 40000 } else { 100644 }, name))
+                .collect();
+            if first % 2 == 1 {
+                refs.reverse();
+            }
+            sort_tree_entries(&mut refs);
+            let sha = to_hex(&store.write_object(&Object::Tree(refs)).unwrap());
+            assert_eq!(sha, "4b1bf72c256040e530e40077665bb29268766335");
+            let refs = match store.read_object(&sha).unwrap() {
+                Object::Tree(refs) => refs,
+                _ => panic!("not a tree"),
+            };
+            let names: Vec<&[u8]> = refs.iter().map(|r| &r.name[..]).collect();
+            assert_eq!(names, [&b"a-"[..], b"a.b", b"a", b"a0"]);
+        }
+        assert_eq!(store.shas().len(), 1);
+    }
+
+    #[test]
+    fn duplicate_tree_entries_are_rejected() {
+        let refs = vec![entry(100644, "a"), entry(40000, "a")];
+        assert!(Object::Tree(refs).encode().is_err());
+    }
+
+    #[test]
+    fn tree_round_trips() {
+        let mut raw = Vec::new();
+        for (mode, name) in [
+            ("100644", "a"),
+            ("100755", "b"),
+            ("040000", "c"),
+            ("120000", "d"),
+        ] {
+            raw.extend_from_slice(format!("{} {}\0", mode, name).as_bytes());
+            raw.extend_from_slice(&[7u8; 20]);
+        }
+        match round_trip("tree", &raw) {
+            Object::Tree(refs) => {
+                assert_eq!(refs.len(), 4);
+                assert!(refs[2].padded_mode);
+                assert_eq!(refs[2].kind(), "tree");
+            }
+            obj => panic!("decoded a {}", obj.kind()),
+        }
+    }
+
+    #[test]
+    fn tree_with_non_utf8_name_round_trips() {
+        let mut raw = b"100644 caf\xe9\0".to_vec();
+        raw.extend_from_slice(&[7u8; 20]);
+        match round_trip("tree", &raw) {
+            Object::Tree(refs) => assert_eq!(&refs[0].name[..], b"caf\xe9"),
+            obj => panic!("decoded a {}", obj.kind()),
+        }
+    }
+
+    #[test]
+    fn truncated_tree_is_an_error() {
+        for raw in [&b"100644 a\0\x07\x07\x07"[..], b"100644", b"100644 a"] {
+            assert!(Object::decode_as("tree", Bytes::from_static(raw)).is_err());
+        }
+    }
+
+    #[test]
+    fn commit_round_trips() {
+        let raw = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+parent ce013625030ba8dba906f756967f9e9ca394464a\n\
+parent 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+author A U Thor <author@example.com> 1112911993 -0700\n\
+committer C O Mitter <committer@example.com> -86400 +0130\n\
+encoding ISO-8859-1\n\
+gpgsig -----BEGIN PGP SIGNATURE-----\n \n abcd\n -----END PGP SIGNATURE-----\n\
+\n\
+Subject\n\nBody\n";
+        match round_trip("commit", raw) {
+            Object::Commit {
+                parents,
+                committer,
+                extra_headers,
+                ..
+            } => {
+                assert_eq!(parents.len(), 2);
+                assert_eq!(committer.timestamp, -86400);
+                assert_eq!(extra_headers.len(), 2);
+            }
+            obj => panic!("decoded a {}", obj.kind()),
+        }
+    }
+
+    #[test]
+    fn commit_with_crlf_headers_round_trips() {
+        let raw = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\r\n\
+author A <a@example.com> 0 +0000\r\n\
+committer A <a@example.com> 0 +0000\r\n\
+\r\n\
+message\r\n";
+        round_trip("commit", raw);
+    }
+
+    #[test]
+    fn commits_git_reads_but_never_writes_round_trip() {
+        // A header without a value.
+        round_trip(
+            "commit",
+            b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+author A <a@example.com> 0 +0000\n\
+committer A <a@example.com> 0 +0000\n\
+novalue\n\
+\n\
+message\n",
+        );
+        // No message, and not even the blank line before one.
+        round_trip(
+            "commit",
+            b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+author A <a@example.com> 0 +0000\n\
+committer A <a@example.com> 0 +0000\n",
+        );
+        // An author in Latin-1, as the encoding header says.
+        let raw = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+author Andr\xe9 <a@example.com> 0 +0000\n\
+committer A <a@example.com> 0 +0000\n\
+encoding ISO-8859-1\n\
+\n\
+Caf\xe9\n";
+        match round_trip("commit", raw) {
+            Object::Commit { author, .. } => assert_eq!(author.email, "a@example.com"),
+            _ => panic!("not a commit"),
+        }
+    }
+
+    #[test]
+    fn contributors_with_odd_spacing_round_trip() {
+        let raw = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+author  A   B  <a@example.com> 0 +0000\n\
+committer <c@example.com>  0 +0000\n\
+\n\
+message\n";
+        match round_trip("commit", raw) {
+            Object::Commit {
+                author, committer, ..
+            } => {
+                assert_eq!(author.name, "A   B");
+                assert_eq!(committer.name, "");
+            }
+            _ => panic!("not a commit"),
+        }
+    }
+
+    #[test]
+    fn commits_with_bad_dates_round_trip() {
+        for (date, timestamp) in [
+            ("99999999999999999999 +0000", i64::MAX),
+            ("-99999999999999999999 +0000", i64::MIN),
+            ("yesterday +0000", 0),
+            ("", 0),
+            ("12 +99xx", 12),
+            ("  12   +0100 ", 12),
+        ] {
+            let raw = format!(
+                "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+author A <a@example.com> {}\n\
+committer A <a@example.com> 0 +0000\n\
+\n\
+message\n",
+                date
+            );
+            match round_trip("commit", raw.as_bytes()) {
+                Object::Commit { author, .. } => {
+                    assert_eq!(author.timestamp, timestamp);
+                    assert!(author.format_date().is_ok());
+                }
+                _ => panic!("not a commit"),
+            }
+        }
+    }
+
+    #[test]
+    fn tag_round_trips() {
+        let raw = b"object ce013625030ba8dba906f756967f9e9ca394464a\n\
+type blob\n\
+tag v1\n\
+tagger T <t@example.com> 1112911993 +0000\n\
+\n\
+Release\n";
+        round_trip("tag", raw);
+        // Very old tags have no tagger.
+        round_trip(
+            "tag",
+            b"object ce013625030ba8dba906f756967f9e9ca394464a\ntype blob\ntag v0\n\nOld\n",
+        );
+        // A message that isn't UTF-8, and no message at all.
+        round_trip(
+            "tag",
+            b"object ce013625030ba8dba906f756967f9e9ca394464a\ntype blob\ntag v2\n\n\xff\xfe\n",
+        );
+        round_trip(
+            "tag",
+            b"object ce013625030ba8dba906f756967f9e9ca394464a\ntype blob\ntag v3\n",
+        );
+    }
+
+    #[test]
+    fn hash_content_matches_encode() {
+        let obj = Object::Blob(Bytes::from_static(b"some content"));
+        assert_eq!(
+            obj.encode().unwrap().0,
+            hash_content("blob", b"some content")
+        );
+    }
+
+    #[test]
+    fn verified_reader_checks_size_and_sha() {
+        let sha = hash_content("blob", b"some content");
+        let read = |content: &'static [u8], size: usize| {
+            let mut out = Vec::new();
+            VerifiedReader::new(content, "blob", size, sha)
+                .read_to_end(&mut out)
+                .map(|_| out)
+        };
+        assert_eq!(read(b"some content", 12).unwrap(), b"some content");
+        let truncated = read(b"some", 12).unwrap_err();
+        assert_eq!(truncated.kind(), io::ErrorKind::UnexpectedEof);
+        let corrupt = read(b"some contenT", 12).unwrap_err();
+        assert_eq!(corrupt.kind(), io::ErrorKind::InvalidData);
+
+        let empty = hash_content("blob", b"");
+        let mut out = Vec::new();
+        assert!(VerifiedReader::new(&b""[..], "blob", 0, empty)
+            .read_to_end(&mut out)
+            .is_ok());
+        assert!(VerifiedReader::new(&b""[..], "blob", 0, sha)
+            .read_to_end(&mut out)
+            .is_err());
+    }
+}
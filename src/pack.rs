@@ -1,18 +1,96 @@
 use bytes::Bytes;
 
+use crate::config::Config;
 use crate::git_error::{GitError, GitResult};
-use crate::object::{Object, Sha};
-use crate::{object, zlib};
+use crate::idx::PackIndex;
+use crate::object::{Object, Sha, ShaHasher};
+use crate::store::MemoryStore;
+use crate::{object, trace, zlib};
+use std::cmp::Reverse;
 use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use flate2::bufread::ZlibDecoder;
 
 #[derive(Debug)]
 pub enum PackObjType {
-    ObjCommit(Bytes),
-    ObjTree(Bytes),
-    ObjBlob(Bytes),
-    ObjTag(Bytes),
-    ObjOfsDelta(usize, Bytes),
-    ObjRefDelta(Sha, Bytes),
+    Commit(Bytes),
+    Tree(Bytes),
+    Blob(Bytes),
+    Tag(Bytes),
+    OfsDelta(usize, Bytes),
+    RefDelta(Sha, Bytes),
+}
+
+impl PackObjType {
+    fn content(&self) -> &Bytes {
+        match self {
+            PackObjType::Commit(content)
+            | PackObjType::Tree(content)
+            | PackObjType::Blob(content)
+            | PackObjType::Tag(content)
+            | PackObjType::OfsDelta(_, content)
+            | PackObjType::RefDelta(_, content) => content,
+        }
+    }
+}
+
+/// Upper bounds on how much a pack may inflate to. Packs come from untrusted
+/// remotes, so the sizes they declare can't be taken at face value.
+#[derive(Debug, Clone, Copy)]
+pub struct PackLimits {
+    /// Maximum uncompressed size of a single object (or delta result).
+    pub max_object_size: usize,
+    /// Maximum uncompressed size of all objects in the pack combined.
+    pub max_pack_size: usize,
+}
+
+impl Default for PackLimits {
+    fn default() -> Self {
+        PackLimits {
+            max_object_size: 512 * 1024 * 1024,
+            max_pack_size: 2048 * 1024 * 1024,
+        }
+    }
+}
+
+/// How hard `write_pack` looks for deltas, as git's `pack.window` and
+/// `pack.depth` settings configure it.
+#[derive(Debug, Clone, Copy)]
+pub struct DeltaOptions {
+    /// How many of the objects written before each one are tried as its base.
+    pub window: usize,
+    /// The longest chain of deltas allowed. At 0 every object is stored whole.
+    pub depth: usize,
+}
+
+impl Default for DeltaOptions {
+    fn default() -> Self {
+        DeltaOptions {
+            window: 10,
+            depth: 50,
+        }
+    }
+}
+
+impl DeltaOptions {
+    pub fn from_config(config: &Config) -> GitResult<DeltaOptions> {
+        let mut options = DeltaOptions::default();
+        if let Some(window) = config.get("pack", None, "window") {
+            options.window = window
+                .parse()
+                .map_err(|_| GitError(format!("Invalid pack.window: {}", window)))?;
+        }
+        if let Some(depth) = config.get("pack", None, "depth") {
+            options.depth = depth
+                .parse()
+                .map_err(|_| GitError(format!("Invalid pack.depth: {}", depth)))?;
+        }
+        Ok(options)
+    }
 }
 
 enum Instruction {
@@ -20,82 +98,202 @@ enum Instruction {
     Insert(usize),
 }
 
-pub fn parse_pack(pack: Bytes) -> GitResult<HashMap<String, Object>> {
-    let count = u32::from_be_bytes([pack[8], pack[9], pack[10], pack[11]]) as usize;
-    if pack.slice(..8).as_ref() != b"PACK\0\0\0\x02" {
+/// Summary of what a pack contained, gathered while parsing it.
+#[derive(Debug, Default)]
+pub struct PackStats {
+    pub commits: usize,
+    pub trees: usize,
+    pub blobs: usize,
+    pub tags: usize,
+    pub ofs_deltas: usize,
+    pub ref_deltas: usize,
+    /// Length of the longest chain of deltas applied on top of each other.
+    pub max_delta_depth: usize,
+    /// Bytes inflated from the pack or reconstructed from deltas.
+    pub total_size: usize,
+    /// Every object in the pack, in the order they appear in it.
+    pub entries: Vec<PackEntry>,
+    /// The SHA-1 trailer of the pack, which has been checked against its content.
+    pub checksum: Sha,
+}
+
+/// Where and how a single object is stored in a pack.
+#[derive(Debug)]
+pub struct PackEntry {
+    pub sha: Sha,
+    pub kind: &'static str,
+    /// Size of the data stored in the pack (the delta, for deltified objects).
+    pub size: usize,
+    /// Bytes the object takes up in the pack, including its header.
+    pub packed_size: usize,
+    pub offset: usize,
+    /// Length of the delta chain leading to this object; 0 if it isn't a delta.
+    pub depth: usize,
+    pub base: Option<Sha>,
+}
+
+/// Passes reads through while hashing every byte consumed, so the checksum
+/// at the end of a pack can be verified in the same pass that parses it.
+struct HashingReader<R> {
+    inner: R,
+    hasher: ShaHasher,
+}
+
+impl<R: BufRead> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<R: BufRead> BufRead for HashingReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // The buffer is already filled, so this doesn't read anything new.
+        if let Ok(buf) = self.inner.fill_buf() {
+            self.hasher.update(&buf[..amt]);
+        }
+        self.inner.consume(amt)
+    }
+}
+
+impl fmt::Display for PackStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Objects: {} commits, {} trees, {} blobs, {} tags",
+            self.commits, self.trees, self.blobs, self.tags
+        )?;
+        writeln!(
+            f,
+            "Deltas: {} ofs-delta, {} ref-delta, max depth {}",
+            self.ofs_deltas, self.ref_deltas, self.max_delta_depth
+        )?;
+        write!(f, "Uncompressed size: {} bytes", self.total_size)
+    }
+}
+
+/// Parses a pack by reading it front to back, so it can come straight from a
+/// file or network stream without being loaded into memory first.
+pub fn parse_pack<R: BufRead>(pack: R, limits: &PackLimits) -> GitResult<(MemoryStore, PackStats)> {
+    parse_thin_pack(pack, limits, &|_| None)
+}
+
+/// Like `parse_pack`, but for thin packs, whose deltas may be based on objects
+/// that aren't in the pack. Those are looked up with `external_base`, which
+/// gives them in the loose format.
+pub fn parse_thin_pack<R: BufRead>(
+    pack: R,
+    limits: &PackLimits,
+    external_base: &dyn Fn(&Sha) -> Option<Bytes>,
+) -> GitResult<(MemoryStore, PackStats)> {
+    let mut pack = HashingReader {
+        inner: pack,
+        hasher: ShaHasher::new(),
+    };
+    let mut header = [0u8; 12];
+    pack.read_exact(&mut header)?;
+    if &header[..8] != b"PACK\0\0\0\x02" {
         return Err(GitError(format!(
             "No PACK header in the pack file: {:?}",
-            pack.slice(..8)
+            &header[..8]
         )));
     }
+    let count = u32::from_be_bytes([header[8], header[9], header[10], header[11]]) as usize;
     let mut content_by_sha = HashMap::new();
     let mut sha_by_byte_offset = HashMap::new();
-    let mut i = 12;
-
-    while i < pack.len() - 20 {
-        let (len, obj) = read_pack_object(pack.slice(i..))?;
-        match obj {
-            PackObjType::ObjCommit(content) => {
-                let decoded = Object::decode_commit(content.clone())?;
-                let (sha, _) = decoded.encode();
-                // objects.push(decoded);
-                // let sha = object::get_sha(content.as_ref());
-                content_by_sha.insert(sha, (decoded, content));
-                sha_by_byte_offset.insert(i, sha);
-            }
-            PackObjType::ObjTree(content) => {
-                let decoded = Object::decode_tree(content.clone())?;
-                let (sha, _) = decoded.encode();
-                // objects.push(decoded);
-                // let sha = object::get_sha(content.as_ref());
-                content_by_sha.insert(sha, (decoded, content));
-                sha_by_byte_offset.insert(i, sha);
-            }
-            PackObjType::ObjBlob(content) => {
-                let decoded = Object::decode_blob(content.clone())?;
-                let (sha, _) = decoded.encode();
-                // objects.push(decoded);
-                // let sha = object::get_sha(content.as_ref());
-                content_by_sha.insert(sha, (decoded, content));
-                sha_by_byte_offset.insert(i, sha);
-            }
-            PackObjType::ObjTag(_) => {}
-            PackObjType::ObjOfsDelta(offset, delta) => {
-                let base_sha = *sha_by_byte_offset
-                    .get(&(i - offset))
+    let mut stats = PackStats::default();
+    let mut i: usize = 12;
+    let mut inflater = zlib::Inflater::new();
+
+    for _ in 0..count {
+        let (len, obj) = read_pack_object(&mut pack, &mut inflater, limits.max_object_size)?;
+        let size = obj.content().len();
+        stats.total_size += size;
+        check_pack_size(stats.total_size, limits)?;
+        let mut base = None;
+        let (kind, content, depth) = match obj {
+            PackObjType::Commit(content) => ("commit", content, 0),
+            PackObjType::Tree(content) => ("tree", content, 0),
+            PackObjType::Blob(content) => ("blob", content, 0),
+            PackObjType::Tag(content) => ("tag", content, 0),
+            PackObjType::OfsDelta(offset, delta) => {
+                stats.ofs_deltas += 1;
+                // A corrupt offset may point before the start of the pack.
+                let base_sha = i
+                    .checked_sub(offset)
+                    .and_then(|base_offset| sha_by_byte_offset.get(&base_offset))
+                    .copied()
                     .ok_or(format!("Could not find object with offset {}", offset))?;
-                let (base_object, base) = content_by_sha.get(&base_sha).ok_or(format!(
-                    "Could not find object {}",
-                    object::to_hex(&base_sha)
-                ))?;
-                let content = apply_delta(base, &delta)?;
-                let unpacked_obj = match base_object {
-                    Object::Blob(_) => Object::decode_blob(content.clone())?,
-                    Object::Tree(_) => Object::decode_tree(content.clone())?,
-                    Object::Commit { .. } => Object::decode_commit(content.clone())?,
-                };
-                let (sha, _) = unpacked_obj.encode();
-                content_by_sha.insert(sha, (unpacked_obj, content));
-                sha_by_byte_offset.insert(i, sha);
+                base = Some(base_sha);
+                resolve_delta(&content_by_sha, external_base, &base_sha, &delta, limits)?
             }
-            PackObjType::ObjRefDelta(base_sha, delta) => {
-                let (base_object, base) = content_by_sha.get(&base_sha).ok_or(format!(
-                    "Could not find object {}",
-                    object::to_hex(&base_sha)
-                ))?;
-                let content = apply_delta(base, &delta)?;
-                let unpacked_obj = match base_object {
-                    Object::Blob(_) => Object::decode_blob(content.clone())?,
-                    Object::Tree(_) => Object::decode_tree(content.clone())?,
-                    Object::Commit { .. } => Object::decode_commit(content.clone())?,
-                };
-                let (sha, _) = unpacked_obj.encode();
-                content_by_sha.insert(sha, (unpacked_obj, content));
-                sha_by_byte_offset.insert(i, sha);
+            PackObjType::RefDelta(base_sha, delta) => {
+                stats.ref_deltas += 1;
+                base = Some(base_sha);
+                resolve_delta(&content_by_sha, external_base, &base_sha, &delta, limits)?
             }
+        };
+        if depth > 0 {
+            stats.total_size += content.len();
+            check_pack_size(stats.total_size, limits)?;
+        }
+        stats.max_delta_depth = stats.max_delta_depth.max(depth);
+        // Only decoded to check that it's well formed: it's kept, hashed and
+        // stored as received.
+        Object::decode_as(kind, content.clone())?;
+        match kind {
+            "blob" => stats.blobs += 1,
+            "tree" => stats.trees += 1,
+            "commit" => stats.commits += 1,
+            _ => stats.tags += 1,
         }
-        i += len as usize;
+        let sha = object::hash_content(kind, &content);
+        if trace::enabled("OBJECTS") {
+            let base =
+                base.map_or_else(String::new, |b| format!(" delta on {}", object::to_hex(&b)));
+            trace::print(
+                "OBJECTS",
+                &format!(
+                    "{} {} {} at {}{}",
+                    object::to_hex(&sha),
+                    kind,
+                    size,
+                    i,
+                    base
+                ),
+            );
+        }
+        stats.entries.push(PackEntry {
+            sha,
+            kind,
+            size,
+            packed_size: len,
+            offset: i,
+            depth,
+            base,
+        });
+        content_by_sha.insert(sha, (kind, content, depth));
+        // Any object, tags included, can be the base of a later ofs-delta, so
+        // every one must be findable by its offset.
+        sha_by_byte_offset.insert(i, sha);
+        i += len;
+    }
+
+    let HashingReader { mut inner, hasher } = pack;
+    let mut checksum = [0u8; 20];
+    inner.read_exact(&mut checksum)?;
+    if hasher.finalize()[..] != checksum[..] {
+        return Err(GitError(format!(
+            "Pack checksum mismatch: expected {}",
+            object::to_hex(&checksum)
+        )));
     }
+    stats.checksum = checksum;
     if count != content_by_sha.len() {
         return Err(GitError(format!(
             "Wrong number of objects in a pack: expected {} got {}",
@@ -103,24 +301,379 @@ pub fn parse_pack(pack: Bytes) -> GitResult<HashMap<String, Object>> {
             content_by_sha.len()
         )));
     }
-    Ok(content_by_sha
+    let objects: HashMap<String, Bytes> = content_by_sha
         .into_iter()
-        .map(|(sha, (o, _))| (object::to_hex(&sha), o))
-        .collect())
+        .map(|(sha, (kind, content, _))| {
+            let mut data = format!("{} {}\0", kind, content.len()).into_bytes();
+            data.extend_from_slice(&content);
+            (object::to_hex(&sha), Bytes::from(data))
+        })
+        .collect();
+    Ok((objects.into(), stats))
 }
 
-fn apply_delta(base: &Bytes, delta: &Bytes) -> GitResult<Bytes> {
-    let mut res = Vec::new();
-    let mut i = 0;
+/// Builds a pack holding the given objects. Each is stored as an ofs-delta
+/// against whichever of the `options.window` objects before it makes the
+/// smallest delta, if that's less than half its size, and whole otherwise.
+pub fn write_pack(objects: &[Object], options: &DeltaOptions) -> GitResult<Vec<u8>> {
+    // Packs store the content without the loose object header.
+    let entries = objects
+        .iter()
+        .map(|obj| Ok((type_code(obj), obj.body()?)))
+        .collect::<GitResult<Vec<_>>>()?;
+    write_entries(&entries, options)
+}
 
-    let source_len_last_byte = delta
+/// Like `write_pack`, for objects as they're stored, header included. Their
+/// content goes in unchanged rather than decoded and encoded again.
+pub fn write_raw_pack(objects: &[Bytes], options: &DeltaOptions) -> GitResult<Vec<u8>> {
+    let entries = objects
         .iter()
-        .position(|&b| b < 128)
-        .ok_or("Could not find a byte with a leading 0")?;
-    let source_len_bytes = delta.slice(..=source_len_last_byte);
-    i += source_len_bytes.len();
-    let source_len = read_var_len_integer_le(source_len_bytes);
+        .map(|raw| {
+            let header_end = raw
+                .iter()
+                .position(|&b| b == 0)
+                .ok_or_else(|| GitError("Object has no header".to_owned()))?;
+            let header = std::str::from_utf8(&raw[..header_end])?;
+            let code = type_code_of(header.split(' ').next().unwrap_or(""))?;
+            Ok((code, raw.slice(header_end + 1..)))
+        })
+        .collect::<GitResult<Vec<_>>>()?;
+    write_entries(&entries, options)
+}
+
+/// Builds a pack from each object's type code and content.
+fn write_entries(entries: &[(u8, Bytes)], options: &DeltaOptions) -> GitResult<Vec<u8>> {
+    // Sorting by type, then size from the largest down, puts similar objects
+    // near each other, with deltas mostly removing data.
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    order.sort_by_key(|&i| (entries[i].0, Reverse(entries[i].1.len())));
+
+    let mut pack = b"PACK\0\0\0\x02".to_vec();
+    pack.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    let mut offsets = Vec::with_capacity(order.len());
+    let mut depths = Vec::with_capacity(order.len());
+    for (position, &i) in order.iter().enumerate() {
+        let (code, content) = &entries[i];
+        let mut best: Option<(usize, Vec<u8>)> = None;
+        for base in position.saturating_sub(options.window)..position {
+            let base_object = order[base];
+            if entries[base_object].0 != *code || depths[base] >= options.depth {
+                continue;
+            }
+            let delta = encode_delta(&entries[base_object].1, content);
+            let limit = best.as_ref().map_or(content.len() / 2, |(_, d)| d.len());
+            if delta.len() < limit {
+                best = Some((base, delta));
+            }
+        }
+
+        offsets.push(pack.len());
+        match best {
+            Some((base, delta)) => {
+                depths.push(depths[base] + 1);
+                write_entry_header(&mut pack, 6, delta.len());
+                write_var_len_integer_be_with_increment(
+                    &mut pack,
+                    offsets[position] - offsets[base],
+                );
+                pack.extend(zlib::write(&delta)?);
+            }
+            None => {
+                depths.push(0);
+                write_entry_header(&mut pack, *code, content.len());
+                pack.extend(zlib::write(content)?);
+            }
+        }
+    }
+    let checksum = object::get_sha(&pack);
+    pack.extend_from_slice(&checksum);
+    Ok(pack)
+}
+
+fn type_code(obj: &Object) -> u8 {
+    match obj {
+        Object::Commit { .. } => 1,
+        Object::Tree(_) => 2,
+        Object::Blob(_) => 3,
+        Object::Tag { .. } => 4,
+    }
+}
+
+/// The pack type code for an object type named as in a loose header.
+fn type_code_of(kind: &str) -> GitResult<u8> {
+    match kind {
+        "commit" => Ok(1),
+        "tree" => Ok(2),
+        "blob" => Ok(3),
+        "tag" => Ok(4),
+        _ => Err(GitError(format!("Unknown object type: {}", kind))),
+    }
+}
+
+/// The name of a whole object's pack type code.
+fn kind_name(code: u8) -> &'static str {
+    match code {
+        1 => "commit",
+        2 => "tree",
+        3 => "blob",
+        _ => "tag",
+    }
+}
+
+/// Writes the type and size that start every entry: the type and the low 4
+/// bits of the size, then 7 bits of the size per byte.
+fn write_entry_header(pack: &mut Vec<u8>, type_code: u8, size: usize) {
+    let mut size = size;
+    let mut byte = (type_code << 4) | (size & 0x0f) as u8;
+    size >>= 4;
+    while size > 0 {
+        pack.push(byte | 0x80);
+        byte = (size & 0x7f) as u8;
+        size >>= 7;
+    }
+    pack.push(byte);
+}
+
+/// Matches between a delta's base and target are looked for in blocks of
+/// this many bytes.
+const DELTA_BLOCK: usize = 16;
+
+/// Git splits copies into chunks of at most this many bytes.
+const MAX_COPY: usize = 0x10000;
+
+/// Encodes `target` as a delta against `base`, the inverse of `apply_delta`:
+/// blocks of the target found in the base are copied from it, and the rest
+/// is inserted as it is.
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    write_var_len_integer_le(&mut delta, base.len());
+    write_var_len_integer_le(&mut delta, target.len());
+    let mut blocks = HashMap::new();
+    for start in (0..base.len().saturating_sub(DELTA_BLOCK - 1)).step_by(DELTA_BLOCK) {
+        blocks
+            .entry(&base[start..start + DELTA_BLOCK])
+            .or_insert(start);
+    }
+
+    let mut insert = Vec::new();
+    let mut i = 0;
+    while i < target.len() {
+        let found = target
+            .get(i..i + DELTA_BLOCK)
+            .and_then(|block| blocks.get(block));
+        let mut start = match found {
+            Some(&start) => start,
+            None => {
+                insert.push(target[i]);
+                if insert.len() == 0x7f {
+                    flush_insert(&mut delta, &mut insert);
+                }
+                i += 1;
+                continue;
+            }
+        };
+        // Grow the match back over bytes waiting to be inserted, then forward.
+        while start > 0 && !insert.is_empty() && base[start - 1] == target[i - 1] {
+            start -= 1;
+            i -= 1;
+            insert.pop();
+        }
+        let mut len = 0;
+        while start + len < base.len()
+            && i + len < target.len()
+            && base[start + len] == target[i + len]
+        {
+            len += 1;
+        }
+        flush_insert(&mut delta, &mut insert);
+        i += len;
+        while len > 0 {
+            let chunk = len.min(MAX_COPY);
+            write_copy(&mut delta, start, chunk);
+            start += chunk;
+            len -= chunk;
+        }
+    }
+    flush_insert(&mut delta, &mut insert);
+    delta
+}
+
+fn flush_insert(delta: &mut Vec<u8>, insert: &mut Vec<u8>) {
+    if !insert.is_empty() {
+        delta.push(insert.len() as u8);
+        delta.append(insert);
+    }
+}
+
+/// Writes a copy instruction, leaving out the offset and length bytes that
+/// are zero.
+fn write_copy(delta: &mut Vec<u8>, offset: usize, len: usize) {
+    let mut instruction = 0x80;
+    let mut arguments = Vec::new();
+    for (bit, &byte) in offset.to_le_bytes()[..4].iter().enumerate() {
+        if byte != 0 {
+            instruction |= 1 << bit;
+            arguments.push(byte);
+        }
+    }
+    for (bit, &byte) in len.to_le_bytes()[..3].iter().enumerate() {
+        if byte != 0 {
+            instruction |= 1 << (4 + bit);
+            arguments.push(byte);
+        }
+    }
+    delta.push(instruction);
+    delta.extend(arguments);
+}
+
+/// Reads the object at `offset` in a pack file, applying any deltas it's
+/// stored as, and returns it as a loose object would hold it: with a
+/// `<type> <size>\0` header. The bases of ref-deltas are looked up in the
+/// pack's index.
+pub fn read_packed_object(pack: &Path, index: &PackIndex, offset: u64) -> GitResult<Bytes> {
+    let (kind, content) = unpack_object(pack, index, offset)?;
+    let mut raw = format!("{} {}\0", kind, content.len()).into_bytes();
+    raw.extend_from_slice(&content);
+    Ok(Bytes::from(raw))
+}
+
+/// Opens the object at `offset` in a pack file for reading, returning its
+/// type and size along with a reader over its content. Whole objects are
+/// inflated as they're read, so they never have to fit in memory; deltas are
+/// resolved up front.
+pub fn open_packed_object(
+    pack: &Path,
+    index: &PackIndex,
+    offset: u64,
+) -> GitResult<(&'static str, usize, Box<dyn Read>)> {
+    let mut reader = BufReader::new(File::open(pack)?);
+    reader.seek(SeekFrom::Start(offset))?;
+    let metadata = read_var_len_bytes(&mut reader)?;
+    let kind = match read_pack_metadata(&metadata)? {
+        (1, _) => "commit",
+        (2, _) => "tree",
+        (3, _) => "blob",
+        (4, _) => "tag",
+        _ => {
+            let (kind, content) = unpack_object(pack, index, offset)?;
+            return Ok((kind, content.len(), Box::new(io::Cursor::new(content))));
+        }
+    };
+    let (_, size) = read_pack_metadata(&metadata)?;
+    let content = ZlibDecoder::new(reader).take(size as u64);
+    Ok((kind, size, Box::new(content)))
+}
 
+/// Unpacks the object at `offset`, returning its type and content.
+fn unpack_object(pack: &Path, index: &PackIndex, offset: u64) -> GitResult<(&'static str, Bytes)> {
+    let mut file = File::open(pack)?;
+    let mut inflater = zlib::Inflater::new();
+    Ok(
+        match unpack_at(&mut file, &mut inflater, index, offset, 0)? {
+            PackObjType::Commit(content) => ("commit", content),
+            PackObjType::Tree(content) => ("tree", content),
+            PackObjType::Blob(content) => ("blob", content),
+            PackObjType::Tag(content) => ("tag", content),
+            _ => unreachable!("unpack_at resolves deltas"),
+        },
+    )
+}
+
+/// Git refuses to write delta chains deeper than this.
+const MAX_DELTA_DEPTH: usize = 4095;
+
+fn unpack_at(
+    file: &mut File,
+    inflater: &mut zlib::Inflater,
+    index: &PackIndex,
+    offset: u64,
+    depth: usize,
+) -> GitResult<PackObjType> {
+    if depth > MAX_DELTA_DEPTH {
+        return Err(GitError(format!(
+            "Delta chain too deep at pack offset {}",
+            offset
+        )));
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let limits = PackLimits::default();
+    let (_, obj) = read_pack_object(
+        &mut BufReader::new(&mut *file),
+        inflater,
+        limits.max_object_size,
+    )?;
+    let (base_offset, delta) = match obj {
+        PackObjType::OfsDelta(relative, delta) => (
+            offset
+                .checked_sub(relative as u64)
+                .ok_or(format!("Could not find object with offset {}", relative))?,
+            delta,
+        ),
+        PackObjType::RefDelta(base_sha, delta) => (
+            index
+                .find(&base_sha)
+                .ok_or(format!(
+                    "Could not find object {}",
+                    object::to_hex(&base_sha)
+                ))?
+                .offset,
+            delta,
+        ),
+        obj => return Ok(obj),
+    };
+    let base = unpack_at(file, inflater, index, base_offset, depth + 1)?;
+    let content = apply_delta(base.content(), &delta, limits.max_object_size)?;
+    Ok(match base {
+        PackObjType::Commit(_) => PackObjType::Commit(content),
+        PackObjType::Tree(_) => PackObjType::Tree(content),
+        PackObjType::Blob(_) => PackObjType::Blob(content),
+        PackObjType::Tag(_) => PackObjType::Tag(content),
+        _ => unreachable!("unpack_at resolves deltas"),
+    })
+}
+
+/// Applies a delta on top of an already unpacked base object, returning the
+/// resulting object's type, its content and its depth in the delta chain.
+fn resolve_delta(
+    objects: &HashMap<Sha, (&'static str, Bytes, usize)>,
+    external_base: &dyn Fn(&Sha) -> Option<Bytes>,
+    base_sha: &Sha,
+    delta: &Bytes,
+    limits: &PackLimits,
+) -> GitResult<(&'static str, Bytes, usize)> {
+    let (kind, base, base_depth) = match objects.get(base_sha) {
+        Some((kind, base, base_depth)) => (*kind, base.clone(), *base_depth),
+        None => {
+            let external = external_base(base_sha).ok_or(format!(
+                "Could not find object {}",
+                object::to_hex(base_sha)
+            ))?;
+            let (kind, base) = object::split_header(&external)?;
+            (kind_name(type_code_of(&kind)?), base, 0)
+        }
+    };
+    let content = apply_delta(&base, delta, limits.max_object_size)?;
+    // A delta always has the type of its base, whichever type that is.
+    Ok((kind, content, base_depth + 1))
+}
+
+fn check_pack_size(total_size: usize, limits: &PackLimits) -> GitResult<()> {
+    if total_size > limits.max_pack_size {
+        return Err(GitError(format!(
+            "Pack exceeds the maximum uncompressed size of {} bytes",
+            limits.max_pack_size
+        )));
+    }
+    Ok(())
+}
+
+/// Rebuilds an object from its base and a delta. Deltas come from untrusted
+/// packs, so every instruction is checked against the base, the delta and
+/// the target size the delta declares before it's carried out.
+fn apply_delta(base: &Bytes, delta: &Bytes, max_size: usize) -> GitResult<Bytes> {
+    let mut i = 0;
+    let source_len = read_delta_size(delta, &mut i)?;
     if base.len() != source_len {
         return Err(GitError(format!(
             "Wrong source length: expected {} got {}",
@@ -128,28 +681,46 @@ fn apply_delta(base: &Bytes, delta: &Bytes) -> GitResult<Bytes> {
             base.len()
         )));
     }
+    let target_len = read_delta_size(delta, &mut i)?;
+    if target_len > max_size {
+        return Err(GitError(format!(
+            "Delta target size {} exceeds the maximum object size of {} bytes",
+            target_len, max_size
+        )));
+    }
 
-    let target_len_last_byte = delta
-        .slice(i..)
-        .iter()
-        .position(|&b| b < 128)
-        .ok_or("Could not find a byte with a leading 0")?;
-    let target_len_bytes = delta.slice(i..=target_len_last_byte + i);
-    i += target_len_bytes.len();
-    let target_len = read_var_len_integer_le(target_len_bytes);
-
+    let mut res = Vec::with_capacity(target_len);
     while i < delta.len() {
-        match parse_instruction(delta[i], &delta.slice(i + 1..)) {
-            (skip, Instruction::Copy(len, offset)) => {
-                i += skip + 1;
-                res.extend_from_slice(base.slice(offset..offset + len).as_ref())
-            }
-            (skip, Instruction::Insert(len)) => {
-                i += skip;
-                res.extend_from_slice(delta.slice(i..i + len).as_ref());
+        let (skip, instruction) = parse_instruction(delta[i], &delta[i + 1..])?;
+        i += skip + 1;
+        let data = match instruction {
+            Instruction::Copy(len, offset) => offset
+                .checked_add(len)
+                .filter(|&end| end <= base.len())
+                .map(|end| &base[offset..end])
+                .ok_or_else(|| {
+                    GitError(format!(
+                        "Delta copies {} bytes at offset {} from a base of {} bytes",
+                        len,
+                        offset,
+                        base.len()
+                    ))
+                })?,
+            Instruction::Insert(len) => {
+                let data = delta
+                    .get(i..i + len)
+                    .ok_or("Delta ends in the middle of an insert")?;
                 i += len;
+                data
             }
+        };
+        if res.len() + data.len() > target_len {
+            return Err(GitError(format!(
+                "Delta result exceeds its declared size of {} bytes",
+                target_len
+            )));
         }
+        res.extend_from_slice(data);
     }
 
     if res.len() != target_len {
@@ -163,90 +734,86 @@ fn apply_delta(base: &Bytes, delta: &Bytes) -> GitResult<Bytes> {
     Ok(Bytes::from(res))
 }
 
-fn parse_instruction(instruction: u8, bs: &Bytes) -> (usize, Instruction) {
-    if instruction > 128 {
+/// Reads one of the sizes at the start of a delta, 7 bits per byte with the
+/// least significant first, moving `i` past it.
+fn read_delta_size(delta: &[u8], i: &mut usize) -> GitResult<usize> {
+    let mut size: usize = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *delta.get(*i).ok_or("Delta ends in its header")?;
+        *i += 1;
+        let bits = ((byte & 0x7f) as usize)
+            .checked_shl(shift)
+            .filter(|bits| bits >> shift == (byte & 0x7f) as usize)
+            .ok_or("Delta size is too large")?;
+        size |= bits;
+        if byte & 0x80 == 0 {
+            return Ok(size);
+        }
+        shift += 7;
+    }
+}
+
+/// Decodes a delta instruction from its first byte and the bytes after it,
+/// returning how many of those it took up.
+fn parse_instruction(instruction: u8, args: &[u8]) -> GitResult<(usize, Instruction)> {
+    if instruction & 0x80 != 0 {
         let mut i: usize = 0;
-        let mut len: usize = 0;
-        let mut offset: usize = 0;
-        for o in 0..4 {
-            if (1 << o) & instruction > 0 {
-                offset += (bs[i] as usize) << (8 * o);
-                i += 1;
+        let mut arg = |bit: u8, shift: usize| -> GitResult<usize> {
+            if instruction & (1 << bit) == 0 {
+                return Ok(0);
             }
+            let byte = *args.get(i).ok_or("Delta ends in a copy instruction")?;
+            i += 1;
+            Ok((byte as usize) << shift)
+        };
+        let mut offset: usize = 0;
+        for bit in 0..4 {
+            offset |= arg(bit, 8 * bit as usize)?;
         }
-        for l in 4..6 {
-            if (1 << l) & instruction > 0 {
-                len += (bs[i] as usize) << (8 * (l - 4));
-                i += 1;
-            }
+        let mut len: usize = 0;
+        for bit in 4..7 {
+            len |= arg(bit, 8 * (bit as usize - 4))?;
         }
-        (i, Instruction::Copy(len, offset))
+        // A length of zero stands for the largest copy git makes.
+        if len == 0 {
+            len = MAX_COPY;
+        }
+        Ok((i, Instruction::Copy(len, offset)))
+    } else if instruction != 0 {
+        Ok((0, Instruction::Insert(instruction as usize)))
     } else {
-        (1, Instruction::Insert((instruction & 0b0111_1111) as usize))
+        Err(GitError("Unexpected delta opcode 0".to_owned()))
     }
 }
 
-fn read_pack_object(bytes: Bytes) -> GitResult<(usize, PackObjType)> {
-    let metadata_last_byte = bytes
-        .iter()
-        .position(|&b| b < 128)
-        .ok_or("Could not find a byte with a leading 0")?;
-    let metadata = bytes.slice(..=metadata_last_byte);
+fn read_pack_object<R: BufRead>(
+    reader: &mut R,
+    inflater: &mut zlib::Inflater,
+    max_size: usize,
+) -> GitResult<(usize, PackObjType)> {
+    let metadata = read_var_len_bytes(reader)?;
     let (obj_type_code, len) = read_pack_metadata(&metadata)?;
-    let object_byte_length: usize;
-    let real_content_length: usize;
+    if len > max_size {
+        return Err(GitError(format!(
+            "Object size {} exceeds the maximum of {} bytes",
+            len, max_size
+        )));
+    }
 
-    let obj_type = match obj_type_code {
-        1 => {
-            let obj_bytes = bytes.slice(metadata.len()..);
-            let (compressed_length, content) = zlib::read(obj_bytes)?;
-            real_content_length = content.len();
-            object_byte_length = compressed_length + metadata.len();
-            PackObjType::ObjCommit(content)
-        }
-        2 => {
-            let obj_bytes = bytes.slice(metadata.len()..);
-            let (compressed_length, content) = zlib::read(obj_bytes)?;
-            real_content_length = content.len();
-            object_byte_length = compressed_length + metadata.len();
-            PackObjType::ObjTree(content)
-        }
-        3 => {
-            let obj_bytes = bytes.slice(metadata.len()..);
-            let (compressed_length, content) = zlib::read(obj_bytes)?;
-            real_content_length = content.len();
-            object_byte_length = compressed_length + metadata.len();
-            PackObjType::ObjBlob(content)
-        }
-        4 => {
-            let obj_bytes = bytes.slice(metadata.len()..);
-            let (compressed_length, content) = zlib::read(obj_bytes)?;
-            real_content_length = content.len();
-            object_byte_length = compressed_length + metadata.len();
-            PackObjType::ObjTag(content)
-        }
+    let mut header_length = metadata.len();
+    let mut base_offset = 0;
+    let mut base_sha = [0u8; 20];
+    match obj_type_code {
+        1..=4 => {}
         6 => {
-            let offset_last_byte = bytes
-                .iter()
-                .skip(metadata.len())
-                .position(|&b| b < 128)
-                .ok_or("Could not find a byte with a leading 0")?;
-            let offset_bytes = bytes.slice(metadata.len()..=metadata.len() + offset_last_byte);
-            let obj_bytes = bytes.slice(metadata.len() + offset_last_byte + 1..);
-            let (compressed_length, content) = zlib::read(obj_bytes)?;
-            object_byte_length = compressed_length + offset_last_byte + 1 + metadata.len();
-            let offset = read_var_len_integer_be_with_increment(offset_bytes);
-            real_content_length = content.len();
-            PackObjType::ObjOfsDelta(offset, content)
+            let offset_bytes = read_var_len_bytes(reader)?;
+            header_length += offset_bytes.len();
+            base_offset = read_var_len_integer_be_with_increment(offset_bytes)?;
         }
         7 => {
-            let obj_bytes = bytes.slice(metadata.len() + 20..);
-            let (compressed_length, content) = zlib::read(obj_bytes)?;
-            real_content_length = content.len();
-            let mut sha = [0u8; 20];
-            sha[..20].copy_from_slice(&bytes.slice(metadata.len()..metadata.len() + 20));
-            object_byte_length = compressed_length + 20 + metadata.len();
-            PackObjType::ObjRefDelta(sha, content)
+            reader.read_exact(&mut base_sha)?;
+            header_length += 20;
         }
         _ => {
             return Err(GitError(format!(
@@ -254,46 +821,347 @@ fn read_pack_object(bytes: Bytes) -> GitResult<(usize, PackObjType)> {
                 obj_type_code
             )))
         }
-    };
+    }
 
-    if real_content_length != len {
+    let (compressed_length, content) = inflater.read_limited(&mut *reader, len)?;
+    if content.len() != len {
         return Err(GitError(format!(
             "Wrong object length: expected {} got {}, obj_type {}",
-            len, real_content_length, obj_type_code
+            len,
+            content.len(),
+            obj_type_code
         )));
     }
-    Ok((object_byte_length, obj_type))
+
+    let obj_type = match obj_type_code {
+        1 => PackObjType::Commit(content),
+        2 => PackObjType::Tree(content),
+        3 => PackObjType::Blob(content),
+        4 => PackObjType::Tag(content),
+        6 => PackObjType::OfsDelta(base_offset, content),
+        _ => PackObjType::RefDelta(base_sha, content),
+    };
+    Ok((header_length + compressed_length, obj_type))
+}
+
+/// The most bytes a variable-length integer may take: enough for 64 bits at
+/// 7 bits a byte.
+const MAX_VAR_LEN_BYTES: usize = 10;
+
+/// Reads the bytes of a variable-length integer, up to and including the
+/// first byte without the continuation bit set.
+fn read_var_len_bytes<R: Read>(reader: &mut R) -> GitResult<Bytes> {
+    let mut bytes = Vec::new();
+    loop {
+        if bytes.len() == MAX_VAR_LEN_BYTES {
+            return Err(GitError("Variable-length integer is too long".to_owned()));
+        }
+        let mut byte = [0u8];
+        reader.read_exact(&mut byte)?;
+        bytes.push(byte[0]);
+        if byte[0] < 128 {
+            return Ok(Bytes::from(bytes));
+        }
+    }
 }
 
 fn read_pack_metadata(bytes: &Bytes) -> GitResult<(u8, usize)> {
     let obj_type_code = (bytes[0] & 0b01110000) >> 4;
     let little_end = (bytes[0] & 0b00001111) as usize;
-    let mut res = read_var_len_integer_le(bytes.slice(1..));
-    res <<= 4;
-    res += little_end;
+    let res = read_var_len_integer_le(bytes.slice(1..))?;
+    let res = res.checked_mul(1 << 4).ok_or("Object size is too large")? + little_end;
 
     Ok((obj_type_code, res))
 }
 
-fn read_var_len_integer_le(bytes: Bytes) -> usize {
-    let mut res = 0;
+fn read_var_len_integer_le(bytes: Bytes) -> GitResult<usize> {
+    let mut res: usize = 0;
     let mut shift = 0;
     for byte in bytes {
-        res += ((byte & 0b01111111) as usize) << shift;
+        let bits = ((byte & 0b01111111) as usize)
+            .checked_shl(shift)
+            .filter(|bits| bits >> shift == (byte & 0b01111111) as usize)
+            .ok_or("Variable-length integer is too large")?;
+        res |= bits;
         shift += 7
     }
-    res
+    Ok(res)
 }
 
-fn read_var_len_integer_be_with_increment(bytes: Bytes) -> usize {
-    let mut res = 0;
-    let mut shift = 0;
+fn write_var_len_integer_le(out: &mut Vec<u8>, value: usize) {
+    let mut value = value;
+    while value >= 0x80 {
+        out.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn write_var_len_integer_be_with_increment(out: &mut Vec<u8>, value: usize) {
+    let mut value = value;
+    let mut bytes = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value > 0 {
+        value -= 1;
+        bytes.push(0x80 | (value & 0x7f) as u8);
+        value >>= 7;
+    }
+    bytes.reverse();
+    out.extend(bytes);
+}
+
+fn read_var_len_integer_be_with_increment(bytes: Bytes) -> GitResult<usize> {
+    let mut res: usize = 0;
     for (i, byte) in bytes.iter().enumerate() {
-        res += ((byte & 0b01111111) as usize) << shift;
-        if i != bytes.len() - 1 {
-            res += 1;
+        // Each continuation adds one, so no offset has two encodings.
+        if i != 0 {
+            res = res
+                .checked_add(1)
+                .and_then(|res| res.checked_mul(1 << 7))
+                .ok_or("Variable-length integer is too large")?;
         }
-        shift += 7
+        res += (byte & 0b01111111) as usize;
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::ObjectStore;
+
+    /// A small xorshift generator, so the randomized tests are repeatable.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn bytes(&mut self, len: usize) -> Vec<u8> {
+            // Few distinct bytes, so blocks repeat and copies get made.
+            (0..len).map(|_| b"abcd\n"[self.below(5)]).collect()
+        }
+    }
+
+    /// A target made from pieces of `base` with random bytes in between.
+    fn mutate(rng: &mut Rng, base: &[u8]) -> Vec<u8> {
+        let mut target = Vec::new();
+        for _ in 0..rng.below(8) {
+            if !base.is_empty() && rng.below(2) == 0 {
+                let start = rng.below(base.len());
+                let len = rng.below(base.len() - start + 1);
+                target.extend_from_slice(&base[start..start + len]);
+            } else {
+                let len = rng.below(300);
+                target.extend(rng.bytes(len));
+            }
+        }
+        target
+    }
+
+    fn finish_pack(mut pack: Vec<u8>) -> Vec<u8> {
+        let checksum = object::get_sha(&pack);
+        pack.extend_from_slice(&checksum);
+        pack
+    }
+
+    #[test]
+    fn deltas_round_trip() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for _ in 0..500 {
+            let len = rng.below(2000);
+            let base = rng.bytes(len);
+            let target = mutate(&mut rng, &base);
+            let delta = encode_delta(&base, &target);
+            let applied = apply_delta(&Bytes::from(base), &Bytes::from(delta), usize::MAX).unwrap();
+            assert_eq!(applied[..], target[..]);
+        }
+    }
+
+    #[test]
+    fn malformed_deltas_are_rejected() {
+        let base = Bytes::from_static(b"0123456789");
+        let apply = |delta: &[u8]| apply_delta(&base, &Bytes::copy_from_slice(delta), 1 << 20);
+        // Copies past the end of the base, or at an offset that overflows.
+        assert!(apply(&[10, 5, 0x91, 8, 5]).is_err());
+        assert!(apply(&[10, 5, 0x9f, 0xff, 0xff, 0xff, 0xff, 5]).is_err());
+        // An insert longer than what's left of the delta.
+        assert!(apply(&[10, 5, 5, b'a']).is_err());
+        // A copy instruction missing its arguments.
+        assert!(apply(&[10, 5, 0x91]).is_err());
+        // Growing past the declared size.
+        assert!(apply(&[10, 2, 0x90, 10]).is_err());
+        // A size too large to fit.
+        assert!(apply(&[10, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]).is_err());
+        // The reserved opcode.
+        assert!(apply(&[10, 1, 0]).is_err());
+        assert_eq!(apply(&[10, 3, 0x91, 2, 3]).unwrap()[..], b"234"[..]);
+    }
+
+    #[test]
+    fn packs_round_trip() {
+        let mut rng = Rng(42);
+        let first = rng.bytes(5000);
+        let mut objects: Vec<Object> = (0..20)
+            .map(|i| {
+                // Numbered so that no two are the same object.
+                let mut content = format!("{}\n", i).into_bytes();
+                content.extend(mutate(&mut rng, &first));
+                Object::Blob(Bytes::from(content))
+            })
+            .collect();
+        objects.push(Object::Blob(Bytes::from(first)));
+        objects.push(Object::Tree(Vec::new()));
+        let options = DeltaOptions::default();
+        let pack = write_pack(&objects, &options).unwrap();
+        let (store, stats) = parse_pack(&pack[..], &PackLimits::default()).unwrap();
+
+        assert!(stats.ofs_deltas > 0);
+        assert_eq!(stats.entries.len(), objects.len());
+        for obj in &objects {
+            let (sha, data) = obj.encode().unwrap();
+            let parsed = store.read_object(&object::to_hex(&sha)).unwrap();
+            assert_eq!(parsed.encode().unwrap().1, data);
+        }
+    }
+
+    #[test]
+    fn deltified_raw_packs_round_trip_within_the_depth() {
+        // Each version of a file is the one before with a line added, so
+        // every one of them can be a delta against the last.
+        let mut rng = Rng(7);
+        let mut content = rng.bytes(3000);
+        let mut objects = Vec::new();
+        for i in 0..12 {
+            content.extend(format!("line {}\n", i).into_bytes());
+            let mut raw = format!("blob {}\0", content.len()).into_bytes();
+            raw.extend_from_slice(&content);
+            objects.push(Bytes::from(raw));
+        }
+        for depth in [0, 3, 50] {
+            let options = DeltaOptions { window: 4, depth };
+            let pack = write_raw_pack(&objects, &options).unwrap();
+            let (store, stats) = parse_pack(&pack[..], &PackLimits::default()).unwrap();
+
+            assert_eq!(stats.ofs_deltas > 0, depth > 0);
+            assert!(stats.max_delta_depth <= depth);
+            for raw in &objects {
+                let sha = object::to_hex(&object::get_sha(raw));
+                assert_eq!(&store.read_raw_object(&sha).unwrap(), raw);
+            }
+        }
+    }
+
+    #[test]
+    fn thin_pack_objects_are_hashed_as_received() {
+        let base = Object::Blob(Bytes::from_static(b"line one\nline two\nline three\n"));
+        let (base_sha, base_data) = base.encode().unwrap();
+        let target = b"line one\nline two\nline three\nline four\n";
+        let delta = encode_delta(&base.body().unwrap(), target);
+
+        let mut pack = b"PACK\0\0\0\x02\0\0\0\x01".to_vec();
+        write_entry_header(&mut pack, 7, delta.len());
+        pack.extend_from_slice(&base_sha);
+        pack.extend(zlib::write(&delta).unwrap());
+        let pack = finish_pack(pack);
+
+        let external = |sha: &Sha| Some(base_data.clone()).filter(|_| *sha == base_sha);
+        let (store, stats) = parse_thin_pack(&pack[..], &PackLimits::default(), &external).unwrap();
+        let sha = object::hash_content("blob", target);
+        assert_eq!(stats.ref_deltas, 1);
+        assert_eq!(stats.entries[0].sha, sha);
+        assert!(store.contains(&object::to_hex(&sha)));
+    }
+
+    #[test]
+    fn raw_packs_keep_objects_as_stored() {
+        let content = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+author  Odd   Spacing <a@example.com>  0 +0000\n\
+committer A <a@example.com> 0 +0000\n\
+\n\
+message\n";
+        let mut raw = format!("commit {}\0", content.len()).into_bytes();
+        raw.extend_from_slice(content);
+        let pack = write_raw_pack(&[Bytes::from(raw)], &DeltaOptions::default()).unwrap();
+        let (_, stats) = parse_pack(&pack[..], &PackLimits::default()).unwrap();
+        assert_eq!(
+            stats.entries[0].sha,
+            object::hash_content("commit", content)
+        );
+    }
+
+    #[test]
+    fn parsed_objects_are_kept_as_received() {
+        let objects = [
+            (
+                "commit",
+                &b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+author Andr\xe9 <a@example.com> 0 +0000\n\
+committer A <a@example.com> 0 +0000\n"[..],
+            ),
+            (
+                "tag",
+                b"object ce013625030ba8dba906f756967f9e9ca394464a\ntype blob\ntag v1\n\n\xff\n",
+            ),
+        ]
+        .map(|(kind, content)| {
+            let mut raw = format!("{} {}\0", kind, content.len()).into_bytes();
+            raw.extend_from_slice(content);
+            Bytes::from(raw)
+        });
+        let pack = write_raw_pack(&objects, &DeltaOptions::default()).unwrap();
+        let (store, _) = parse_pack(&pack[..], &PackLimits::default()).unwrap();
+        for raw in objects {
+            let sha = object::to_hex(&object::get_sha(&raw));
+            assert_eq!(store.read_raw_object(&sha).unwrap(), raw);
+            assert!(store.read_object(&sha).is_ok());
+        }
+    }
+
+    #[test]
+    fn ofs_delta_before_the_pack_is_rejected() {
+        let delta = encode_delta(b"", b"x");
+        let mut pack = b"PACK\0\0\0\x02\0\0\0\x01".to_vec();
+        write_entry_header(&mut pack, 6, delta.len());
+        write_var_len_integer_be_with_increment(&mut pack, 1000);
+        pack.extend(zlib::write(&delta).unwrap());
+        let pack = finish_pack(pack);
+        assert!(parse_pack(&pack[..], &PackLimits::default()).is_err());
+    }
+
+    #[test]
+    fn oversized_var_len_integers_are_rejected() {
+        // An object size that runs on past what 64 bits can hold.
+        let mut pack = b"PACK\0\0\0\x02\0\0\0\x01\xb0".to_vec();
+        pack.extend_from_slice(&[0xff; 9]);
+        pack.push(0x01);
+        let pack = finish_pack(pack);
+        assert!(parse_pack(&pack[..], &PackLimits::default()).is_err());
+
+        // An ofs-delta offset that overflows.
+        let delta = encode_delta(b"", b"x");
+        let mut pack = b"PACK\0\0\0\x02\0\0\0\x01".to_vec();
+        write_entry_header(&mut pack, 6, delta.len());
+        pack.extend_from_slice(&[0xff; 9]);
+        pack.push(0x7f);
+        pack.extend(zlib::write(&delta).unwrap());
+        let pack = finish_pack(pack);
+        assert!(parse_pack(&pack[..], &PackLimits::default()).is_err());
+    }
+
+    #[test]
+    fn corrupt_checksum_is_rejected() {
+        let mut pack = write_pack(&[Object::Tree(Vec::new())], &DeltaOptions::default()).unwrap();
+        let last = pack.len() - 1;
+        pack[last] ^= 1;
+        assert!(parse_pack(&pack[..], &PackLimits::default()).is_err());
     }
-    res
 }
@@ -1,30 +1,67 @@
-use crate::git_error::GitResult;
-use crate::object::Contributor;
+use std::num::{IntErrorKind, ParseIntError};
 
+use crate::git_error::{GitError, GitResult};
+use crate::object::{self, Contributor};
+
+/// Parses a `Name <email> timestamp timezone` line. The email is taken from the
+/// last `<...>` pair on the line, so names containing angle brackets survive.
 pub fn parse_contributor(bytes: &[u8]) -> GitResult<(usize, Contributor)> {
-    let mut i = 0;
-    let name = parse_string_until(&bytes[i..], b'<')?.trim_end().to_owned();
-    i += name.len() + 2;
-
-    let email = parse_string_until(&bytes[i..], b'>')?;
-    i += email.len() + 2;
-
-    let timestamp_bytes = parse_string_until(&bytes[i..], b' ')?;
-    let timestamp = timestamp_bytes.parse::<u64>()?;
-    i += timestamp_bytes.len() + 1;
-
-    let timezone = parse_string_until(&bytes[i..], b'\n')?;
-    i += timezone.len() + 1;
-
-    Ok((
-        i,
-        Contributor {
-            name,
-            email,
-            timestamp,
-            timezone,
-        },
-    ))
+    let line = take_until(bytes, b'\n');
+    let line_str = String::from_utf8_lossy(&line);
+
+    let email_end = line
+        .iter()
+        .rposition(|&b| b == b'>')
+        .ok_or_else(|| GitError(format!("Missing email in contributor: {}", line_str)))?;
+    let email_start = line[..email_end]
+        .iter()
+        .rposition(|&b| b == b'<')
+        .ok_or_else(|| GitError(format!("Missing email in contributor: {}", line_str)))?;
+
+    // Names in another encoding, as a commit's `encoding` header allows,
+    // are read lossily here and kept exactly in `raw`.
+    let name = String::from_utf8_lossy(&line[..email_start])
+        .trim()
+        .to_owned();
+    let email = String::from_utf8_lossy(&line[email_start + 1..email_end]).into_owned();
+
+    // Like git, a date that's missing, malformed or out of range doesn't
+    // make the object unreadable: it's taken as the epoch, or the nearest
+    // time that fits.
+    let date = String::from_utf8_lossy(&line[email_end + 1..]);
+    let mut fields = date.split_whitespace();
+    let timestamp = fields.next().map_or(0, parse_timestamp);
+    let timezone = fields
+        .next()
+        .filter(|tz| object::parse_timezone_offset(tz).is_ok())
+        .unwrap_or("+0000")
+        .to_owned();
+
+    let mut contributor = Contributor {
+        name,
+        email,
+        timestamp,
+        timezone,
+        raw: None,
+    };
+    // Anything the fields don't reproduce, such as odd spacing or a bad
+    // date, is kept so that the object is written back unchanged.
+    let len = line.len();
+    if contributor.line() != line {
+        contributor.raw = Some(line.into());
+    }
+    Ok((len + 1, contributor))
+}
+
+/// A timestamp clamped to what fits, or the epoch if it isn't a number.
+fn parse_timestamp(timestamp: &str) -> i64 {
+    timestamp
+        .parse()
+        .unwrap_or_else(|e: ParseIntError| match e.kind() {
+            IntErrorKind::PosOverflow => i64::MAX,
+            IntErrorKind::NegOverflow => i64::MIN,
+            _ => 0,
+        })
 }
 
 pub fn take_until(bytes: &[u8], delimiter: u8) -> Vec<u8> {
@@ -0,0 +1,348 @@
+use crate::git_error::{GitError, GitResult};
+use crate::object;
+
+/// How many lines of context at either end of a hunk may be ignored when it
+/// doesn't apply as it is, as `patch` allows by default.
+const MAX_FUZZ: usize = 2;
+
+/// The changes a patch makes to one file.
+#[derive(Debug, Default)]
+pub struct FilePatch {
+    /// Missing for a file the patch creates.
+    pub old_path: Option<String>,
+    /// Missing for a file the patch deletes.
+    pub new_path: Option<String>,
+    /// Set when the patch creates the file or changes its mode.
+    pub new_mode: Option<u32>,
+    pub hunks: Vec<Hunk>,
+}
+
+/// One `@@` section of a unified diff.
+#[derive(Debug)]
+pub struct Hunk {
+    /// The line the hunk starts at in the old file, counting from 1.
+    pub old_start: usize,
+    /// The hunk as it appeared in the patch, for reporting it.
+    pub text: String,
+    /// Each line with its ` `, `-` or `+` marker, and its newline unless the
+    /// patch said it has none.
+    lines: Vec<(u8, Vec<u8>)>,
+}
+
+impl FilePatch {
+    /// The path the patch is about, for messages.
+    pub fn path(&self) -> &str {
+        self.new_path
+            .as_deref()
+            .or(self.old_path.as_deref())
+            .unwrap_or("")
+    }
+}
+
+/// Parses a patch in unified diff format, with or without git's extended
+/// headers. Anything outside the diffs, such as a commit message, is skipped.
+pub fn parse(patch: &[u8]) -> GitResult<Vec<FilePatch>> {
+    let mut files: Vec<FilePatch> = Vec::new();
+    // Whether the current file's headers are still being read, so that a
+    // `---` line belongs to it rather than starting another file.
+    let mut in_header = false;
+    let mut lines = patch.split_inclusive(|&b| b == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        let text = String::from_utf8_lossy(line);
+        let text = text.trim_end_matches('\n');
+        if let Some(paths) = text.strip_prefix("diff --git ") {
+            let split = paths
+                .rfind(" b/")
+                .ok_or_else(|| GitError(format!("Malformed diff header: {}", text)))?;
+            files.push(FilePatch {
+                old_path: Some(
+                    paths[..split]
+                        .strip_prefix("a/")
+                        .unwrap_or(&paths[..split])
+                        .to_owned(),
+                ),
+                new_path: Some(paths[split + 3..].to_owned()),
+                ..Default::default()
+            });
+            in_header = true;
+        } else if text.starts_with("--- ") && lines.peek().is_some_and(|l| l.starts_with(b"+++ ")) {
+            if !in_header {
+                files.push(FilePatch::default());
+                in_header = true;
+            }
+            let new = String::from_utf8_lossy(lines.next().unwrap_or_default()).into_owned();
+            let file = files.last_mut().ok_or("Patch has no files")?;
+            file.old_path = strip_path(&text[4..]);
+            file.new_path = strip_path(new[4..].trim_end_matches('\n'));
+        } else if text.starts_with("@@ ") {
+            let file = files
+                .last_mut()
+                .ok_or_else(|| GitError(format!("Hunk without a file: {}", text)))?;
+            in_header = false;
+            let hunk = parse_hunk(text, &mut lines)?;
+            file.hunks.push(hunk);
+        } else if in_header {
+            let file = files.last_mut().ok_or("Patch has no files")?;
+            if let Some(mode) = text.strip_prefix("new file mode ") {
+                file.old_path = None;
+                file.new_mode = Some(parse_mode(mode)?);
+            } else if text.starts_with("deleted file mode ") {
+                file.new_path = None;
+            } else if let Some(mode) = text.strip_prefix("new mode ") {
+                file.new_mode = Some(parse_mode(mode)?);
+            } else if let Some(path) = text
+                .strip_prefix("rename from ")
+                .or_else(|| text.strip_prefix("copy from "))
+            {
+                file.old_path = Some(path.to_owned());
+            } else if let Some(path) = text
+                .strip_prefix("rename to ")
+                .or_else(|| text.strip_prefix("copy to "))
+            {
+                file.new_path = Some(path.to_owned());
+            } else if text.starts_with("Binary files ") || text == "GIT binary patch" {
+                return Err(GitError(format!(
+                    "cannot apply binary patch to '{}'",
+                    file.path()
+                )));
+            }
+        }
+    }
+    if files.is_empty() {
+        return Err(GitError("No valid patches in input".to_owned()));
+    }
+    for file in &files {
+        for path in file.old_path.iter().chain(&file.new_path) {
+            check_path(path)?;
+        }
+    }
+    Ok(files)
+}
+
+/// Turns away paths that would reach outside the working tree or into the
+/// repository: absolute ones, and any with a `.`, `..` or `.git` component,
+/// as `git apply` does.
+fn check_path(path: &str) -> GitResult<()> {
+    path.split('/')
+        .try_for_each(|name| object::check_entry_name(name.as_bytes()))
+        .map_err(|_| GitError(format!("invalid path '{}'", path)))
+}
+
+/// Reads a hunk from its `@@ -<start>,<count> +<start>,<count> @@` line
+/// through as many lines as it counts.
+fn parse_hunk<'a>(
+    header: &str,
+    lines: &mut std::iter::Peekable<impl Iterator<Item = &'a [u8]>>,
+) -> GitResult<Hunk> {
+    let malformed = || GitError(format!("Malformed hunk header: {}", header));
+    let mut ranges = header[3..].split(' ');
+    let mut range = |sign: char| -> GitResult<(usize, usize)> {
+        let range = ranges
+            .next()
+            .and_then(|r| r.strip_prefix(sign))
+            .ok_or_else(malformed)?;
+        let (start, count) = range.split_once(',').unwrap_or((range, "1"));
+        Ok((
+            start.parse().map_err(|_| malformed())?,
+            count.parse().map_err(|_| malformed())?,
+        ))
+    };
+    let (old_start, mut old_left) = range('-')?;
+    let (_, mut new_left) = range('+')?;
+
+    let mut hunk = Hunk {
+        old_start,
+        text: format!("{}\n", header),
+        lines: Vec::new(),
+    };
+    while old_left > 0 || new_left > 0 {
+        let line = lines
+            .next()
+            .ok_or_else(|| GitError(format!("Patch ends in the middle of {}", header)))?;
+        // Some tools drop the space from empty context lines.
+        let (marker, content) = match line.split_first() {
+            Some((&b'\n', _)) => (b' ', line),
+            Some((&marker, content)) => (marker, content),
+            None => break,
+        };
+        match marker {
+            b' ' => {
+                old_left = old_left.saturating_sub(1);
+                new_left = new_left.saturating_sub(1);
+            }
+            b'-' => old_left = old_left.saturating_sub(1),
+            b'+' => new_left = new_left.saturating_sub(1),
+            b'\\' => {}
+            _ => return Err(GitError(format!("Corrupt patch in {}", header))),
+        }
+        hunk.text.push_str(&String::from_utf8_lossy(line));
+        if marker == b'\\' {
+            strip_last_newline(&mut hunk);
+        } else {
+            hunk.lines.push((marker, content.to_vec()));
+        }
+    }
+    // The last line may be followed by a note that it has no newline.
+    if lines.peek().is_some_and(|l| l.starts_with(b"\\")) {
+        let line = lines.next().unwrap_or_default();
+        hunk.text.push_str(&String::from_utf8_lossy(line));
+        strip_last_newline(&mut hunk);
+    }
+    Ok(hunk)
+}
+
+/// Applies a `\ No newline at end of file` line to the line before it.
+fn strip_last_newline(hunk: &mut Hunk) {
+    if let Some((_, content)) = hunk.lines.last_mut() {
+        if content.ends_with(b"\n") {
+            content.pop();
+        }
+    }
+}
+
+/// The path in a `---` or `+++` line, without its leading `a/` or `b/`
+/// directory, or nothing for `/dev/null`.
+fn strip_path(path: &str) -> Option<String> {
+    // Some diffs put a timestamp after a tab.
+    let path = path.split('\t').next().unwrap_or(path).trim_end();
+    if path == "/dev/null" {
+        return None;
+    }
+    Some(
+        path.split_once('/')
+            .map_or(path, |(_, rest)| rest)
+            .to_owned(),
+    )
+}
+
+fn parse_mode(mode: &str) -> GitResult<u32> {
+    u32::from_str_radix(mode.trim(), 8).map_err(|_| GitError(format!("Invalid mode: {}", mode)))
+}
+
+/// Applies the hunks of `patch` to `content` in order. Each hunk is looked
+/// for nearest to where it says it goes, after wherever the previous one
+/// went. Failing that, up to `MAX_FUZZ` lines of its context at either end
+/// are ignored. Returns the first hunk that can't be placed.
+pub fn apply<'a>(content: &'a [u8], patch: &'a FilePatch) -> Result<Vec<u8>, &'a Hunk> {
+    let mut lines: Vec<&[u8]> = content.split_inclusive(|&b| b == b'\n').collect();
+    let mut floor = 0;
+    let mut offset = 0isize;
+    for hunk in &patch.hunks {
+        let old: Vec<&[u8]> = side(hunk, b'+');
+        let new: Vec<&[u8]> = side(hunk, b'-');
+        let leading = hunk.lines.iter().take_while(|(m, _)| *m == b' ').count();
+        let trailing = hunk
+            .lines
+            .iter()
+            .rev()
+            .take_while(|(m, _)| *m == b' ')
+            .count();
+
+        let placed = (0..=MAX_FUZZ).find_map(|fuzz| {
+            let (head, tail) = (fuzz.min(leading), fuzz.min(trailing));
+            if head + tail > old.len() {
+                return None;
+            }
+            let old = &old[head..old.len() - tail];
+            let expected = (hunk.old_start.saturating_sub(1) + head) as isize + offset;
+            find(&lines, old, floor, expected).map(|at| (at, head, tail))
+        });
+        let (at, head, tail) = placed.ok_or(hunk)?;
+        let (old_len, new) = (old.len() - head - tail, &new[head..new.len() - tail]);
+        lines.splice(at..at + old_len, new.iter().copied());
+        offset = (at + new.len()) as isize
+            - (hunk.old_start.saturating_sub(1) + head + old_len) as isize;
+        floor = at + new.len();
+    }
+    Ok(lines.concat())
+}
+
+/// The lines of a hunk on one side of it: the old file's without the added
+/// ones, or the new file's without the deleted ones.
+fn side(hunk: &Hunk, without: u8) -> Vec<&[u8]> {
+    hunk.lines
+        .iter()
+        .filter(|(marker, _)| *marker != without)
+        .map(|(_, content)| &content[..])
+        .collect()
+}
+
+/// Finds `wanted` in `lines` at or after `floor`, nearest to `expected`.
+fn find(lines: &[&[u8]], wanted: &[&[u8]], floor: usize, expected: isize) -> Option<usize> {
+    let last = lines.len().checked_sub(wanted.len())?;
+    if floor > last {
+        return None;
+    }
+    let expected = expected.clamp(floor as isize, last as isize) as usize;
+    let matches =
+        |at: &usize| (floor..=last).contains(at) && lines[*at..*at + wanted.len()] == *wanted;
+    (0..=last - floor).find_map(|distance| {
+        Some(expected + distance)
+            .filter(matches)
+            .or_else(|| expected.checked_sub(distance).filter(matches))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply_patch(content: &str, patch: &str) -> Option<String> {
+        let files = parse(patch.as_bytes()).unwrap();
+        apply(content.as_bytes(), &files[0])
+            .ok()
+            .map(|applied| String::from_utf8(applied).unwrap())
+    }
+
+    const PATCH: &str = "--- a/f\n+++ b/f\n\
+@@ -2,3 +2,3 @@\n 2\n-3\n+three\n 4\n\
+@@ -7,2 +7,3 @@\n 7\n 8\n+9\n";
+
+    #[test]
+    fn hunks_apply_where_they_fit() {
+        let content = "1\n2\n3\n4\n5\n6\n7\n8\n";
+        assert_eq!(
+            apply_patch(content, PATCH).unwrap(),
+            "1\n2\nthree\n4\n5\n6\n7\n8\n9\n"
+        );
+        // Lines added above move both hunks down.
+        assert_eq!(
+            apply_patch(&format!("0\n0\n{}", content), PATCH).unwrap(),
+            "0\n0\n1\n2\nthree\n4\n5\n6\n7\n8\n9\n"
+        );
+        // Context that no longer matches is ignored, up to the fuzz allowed.
+        assert_eq!(
+            apply_patch("1\nX\n3\n4\n5\n6\n7\n8\n", PATCH).unwrap(),
+            "1\nX\nthree\n4\n5\n6\n7\n8\n9\n"
+        );
+        // A removed line that isn't there can't be fuzzed away.
+        assert_eq!(apply_patch("1\n2\nX\n4\n5\n6\n7\n8\n", PATCH), None);
+    }
+
+    #[test]
+    fn missing_newlines_at_the_end_are_kept() {
+        let patch = "--- a/f\n+++ b/f\n@@ -1,2 +1,2 @@\n a\n-b\n\\ No newline at end of file\n+c\n";
+        assert_eq!(apply_patch("a\nb", patch).unwrap(), "a\nc\n");
+        assert_eq!(apply_patch("a\nb\n", patch), None);
+
+        let patch = "--- /dev/null\n+++ b/f\n@@ -0,0 +1 @@\n+x\n\\ No newline at end of file\n";
+        assert_eq!(apply_patch("", patch).unwrap(), "x");
+    }
+
+    #[test]
+    fn paths_outside_the_worktree_are_rejected() {
+        for header in [
+            "--- a/../x\n+++ b/../x\n",
+            "--- /dev/null\n+++ b//etc/passwd\n",
+            "--- a/.git/config\n+++ b/.git/config\n",
+            "diff --git a/x b/y\nrename from x\nrename to ../y\n",
+            "diff --git a/x b/y\ncopy from x\ncopy to /tmp/y\n",
+            "diff --git a/x b/.GIT/hooks/post-checkout\n",
+        ] {
+            let patch = format!("{}@@ -0,0 +1 @@\n+x\n", header);
+            assert!(parse(patch.as_bytes()).is_err(), "{}", header);
+        }
+        let patch = b"--- a/dir/x\n+++ b/dir/x\n@@ -0,0 +1 @@\n+x\n";
+        assert_eq!(parse(patch).unwrap()[0].path(), "dir/x");
+    }
+}
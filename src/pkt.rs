@@ -0,0 +1,97 @@
+use std::io::{BufRead, Read};
+
+use bytes::Bytes;
+
+use crate::git_error::{GitError, GitResult};
+use crate::trace;
+
+/// The longest a pkt-line may be, its 4-byte length included.
+pub const MAX_LEN: usize = 65520;
+
+/// A pkt-line as read off the wire. Besides the flush packet, protocol v2
+/// uses special packets to split a message into sections and to end it.
+#[derive(Debug, PartialEq)]
+pub enum Packet {
+    Data(Bytes),
+    /// `0000`
+    Flush,
+    /// `0001`, separating the sections of a v2 request or response.
+    Delimiter,
+    /// `0002`, ending a v2 response over a stateless connection.
+    ResponseEnd,
+}
+
+/// Frames `data` as a pkt-line, prefixing it with its length in hex. Data
+/// too long for one pkt-line is an error.
+pub fn encode(data: &[u8]) -> GitResult<Bytes> {
+    if data.len() + 4 > MAX_LEN {
+        return Err(GitError(format!(
+            "pkt-line too long: {} bytes, at most {} allowed",
+            data.len() + 4,
+            MAX_LEN
+        )));
+    }
+    let mut line = format!("{:04x}", data.len() + 4).into_bytes();
+    line.extend_from_slice(data);
+    Ok(Bytes::from(line))
+}
+
+/// Frames a line of text, adding the newline that ends it.
+pub fn encode_line(line: &str) -> GitResult<Bytes> {
+    encode(format!("{}\n", line).as_bytes())
+}
+
+pub fn encode_flush() -> Bytes {
+    Bytes::from_static(b"0000")
+}
+
+pub fn encode_delim() -> Bytes {
+    Bytes::from_static(b"0001")
+}
+
+/// Reads pkt-lines off a stream, one at a time, so that whatever follows
+/// them (such as a pack) can be read from the same stream.
+pub struct PktReader<R> {
+    reader: R,
+}
+
+impl<R: Read> PktReader<R> {
+    pub fn new(reader: R) -> PktReader<R> {
+        PktReader { reader }
+    }
+
+    pub fn read(&mut self) -> GitResult<Packet> {
+        let mut len_bytes = [0u8; 4];
+        self.reader.read_exact(&mut len_bytes)?;
+        let len = usize::from_str_radix(std::str::from_utf8(&len_bytes)?, 16)?;
+        let packet = match len {
+            0 => Packet::Flush,
+            1 => Packet::Delimiter,
+            2 => Packet::ResponseEnd,
+            3 => return Err(GitError(format!("Invalid pkt-line length: {}", len))),
+            _ if len > MAX_LEN => {
+                return Err(GitError(format!("Invalid pkt-line length: {}", len)))
+            }
+            _ => {
+                let mut line = vec![0u8; len - 4];
+                self.reader.read_exact(&mut line)?;
+                Packet::Data(Bytes::from(line))
+            }
+        };
+        trace::packet('<', &packet);
+        Ok(packet)
+    }
+
+    /// The underlying stream, positioned after the last packet read.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+}
+
+impl<R: BufRead> PktReader<R> {
+    /// Checks whether the stream goes on with `prefix` without consuming it,
+    /// e.g. to spot the `PACK` that a pack sent after the pkt-lines starts with.
+    pub fn peek_is(&mut self, prefix: &[u8]) -> GitResult<bool> {
+        Ok(self.reader.fill_buf()?.starts_with(prefix))
+    }
+}
@@ -0,0 +1,353 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::git_error::{GitError, GitResult};
+use crate::repository::git_path;
+use crate::{lockfile, trace};
+
+/// Reads a ref such as `HEAD` or `refs/heads/master`, following symbolic refs
+/// down to a SHA. Returns `None` if the ref (or the ref it points to) doesn't exist.
+pub fn read_ref(root: &str, name: &str) -> GitResult<Option<String>> {
+    let mut name = name.to_owned();
+    // Bound the number of symbolic hops so a ref cycle can't loop forever.
+    for _ in 0..5 {
+        let content = match fs::read_to_string(git_path(root, &name)) {
+            Ok(content) => content,
+            Err(_) => return read_packed_ref(root, &name),
+        };
+        let content = content.trim_end();
+        if let Some(target) = content.strip_prefix("ref: ") {
+            name = target.to_owned();
+        } else {
+            return Ok(Some(content.to_owned()));
+        }
+    }
+    Err(GitError(format!(
+        "Too many levels of symbolic refs: {}",
+        name
+    )))
+}
+
+/// Returns the ref a symbolic ref such as `HEAD` points at, or `None` if it
+/// isn't symbolic.
+pub fn read_symref(root: &str, name: &str) -> GitResult<Option<String>> {
+    match fs::read_to_string(git_path(root, name)) {
+        Ok(content) => Ok(content.trim_end().strip_prefix("ref: ").map(str::to_owned)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Looks a ref up in `.git/packed-refs`, where `git pack-refs` moves loose refs.
+fn read_packed_ref(root: &str, name: &str) -> GitResult<Option<String>> {
+    let content = match fs::read_to_string(git_path(root, "packed-refs")) {
+        Ok(content) => content,
+        Err(_) => return Ok(None),
+    };
+    for line in content.lines() {
+        // Skip the header comment and `^<sha>` peeled-tag lines.
+        if line.starts_with('#') || line.starts_with('^') {
+            continue;
+        }
+        let mut parts = line.splitn(2, ' ');
+        let sha = parts.next().unwrap_or("");
+        if parts.next() == Some(name) {
+            return Ok(Some(sha.to_owned()));
+        }
+    }
+    Ok(None)
+}
+
+/// Lists the refs under `prefix` (such as `refs/tags/`) by name, from both
+/// loose ref files and `packed-refs`. Loose refs win, as they're newer.
+pub fn list_refs(root: &str, prefix: &str) -> GitResult<BTreeMap<String, String>> {
+    let mut refs = BTreeMap::new();
+    if let Ok(content) = fs::read_to_string(git_path(root, "packed-refs")) {
+        for line in content.lines() {
+            if line.starts_with('#') || line.starts_with('^') {
+                continue;
+            }
+            let mut parts = line.splitn(2, ' ');
+            let sha = parts.next().unwrap_or("");
+            match parts.next() {
+                Some(name) if name.starts_with(prefix) => {
+                    refs.insert(name.to_owned(), sha.to_owned());
+                }
+                _ => {}
+            }
+        }
+    }
+    list_loose_refs(root, prefix.trim_end_matches('/'), &mut refs)?;
+    Ok(refs)
+}
+
+fn list_loose_refs(root: &str, dir: &str, refs: &mut BTreeMap<String, String>) -> GitResult<()> {
+    let entries = match fs::read_dir(git_path(root, dir)) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(()),
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = format!("{}/{}", dir, entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            list_loose_refs(root, &name, refs)?;
+        } else if let Some(sha) = read_ref(root, &name)? {
+            refs.insert(name, sha);
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `name` is a ref name git would accept, as
+/// `git check-ref-format` does, so that it's safe to use as a path in the
+/// git directory. Besides names under `refs/`, only all-caps names like
+/// `HEAD` and `ORIG_HEAD` are allowed.
+pub fn check_ref_format(name: &str) -> GitResult<()> {
+    let invalid = |reason: &str| Err(GitError(format!("Invalid ref name '{}': {}", name, reason)));
+    let pseudoref = !name.is_empty() && name.bytes().all(|b| b.is_ascii_uppercase() || b == b'_');
+    if !name.starts_with("refs/") && !pseudoref {
+        return invalid("not under refs/");
+    }
+    if name.ends_with('/') || name.ends_with('.') {
+        return invalid("ends with '/' or '.'");
+    }
+    if name.contains("..") || name.contains("@{") {
+        return invalid("contains '..' or '@{'");
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|&c| c.is_control() || " ~^:?*[\\".contains(c))
+    {
+        return invalid(&format!("contains {:?}", c));
+    }
+    for component in name.split('/') {
+        if component.is_empty() || component.starts_with('.') || component.ends_with(".lock") {
+            return invalid(&format!("bad component '{}'", component));
+        }
+    }
+    Ok(())
+}
+
+/// Points a ref at a SHA, creating the directories leading up to it.
+pub fn write_ref(root: &str, name: &str, sha: &str) -> GitResult<()> {
+    check_ref_format(name)?;
+    write_ref_content(root, name, &format!("{}\n", sha))
+}
+
+/// Makes `name` a symbolic ref pointing at the ref `target`.
+pub fn write_symref(root: &str, name: &str, target: &str) -> GitResult<()> {
+    check_ref_format(name)?;
+    check_ref_format(target)?;
+    write_ref_content(root, name, &format!("ref: {}\n", target))
+}
+
+/// Writes the file at `name` in the git directory, which callers have made
+/// sure is a valid ref name or the reflog of one.
+fn write_ref_content(root: &str, name: &str, content: &str) -> GitResult<()> {
+    let path = git_path(root, name);
+    let lock = lock_ref(name, &path)?;
+    lockfile::commit(&lock, &path, content.as_bytes())
+}
+
+/// Points a ref at `new`, but only if it currently points at `old`, when
+/// that's given: all zeros (or empty) for a ref that mustn't exist yet.
+/// Symbolic refs are followed, so updating `HEAD` moves its branch. The ref
+/// stays locked from the check until it's rewritten, so two processes can't
+/// both succeed against the same old value.
+pub fn update_ref(root: &str, name: &str, new: &str, old: Option<&str>) -> GitResult<()> {
+    check_ref_format(name)?;
+    let mut name = name.to_owned();
+    for _ in 0..5 {
+        match read_symref(root, &name)? {
+            Some(target) => name = target,
+            None => break,
+        }
+    }
+    check_ref_format(&name)?;
+    let path = git_path(root, &name);
+    let lock = lock_ref(&name, &path)?;
+    if let Err(e) = check_old_value(root, &name, old) {
+        let _ = fs::remove_file(&lock);
+        return Err(e);
+    }
+    lockfile::commit(&lock, &path, format!("{}\n", new).as_bytes())
+}
+
+fn check_old_value(root: &str, name: &str, old: Option<&str>) -> GitResult<()> {
+    let old = match old {
+        Some(old) => old,
+        None => return Ok(()),
+    };
+    let must_not_exist = old.is_empty() || old.bytes().all(|b| b == b'0');
+    match read_ref(root, name)? {
+        Some(_) if must_not_exist => Err(GitError(format!(
+            "cannot lock ref '{}': reference already exists",
+            name
+        ))),
+        Some(current) if current != old => Err(GitError(format!(
+            "cannot lock ref '{}': is at {} but expected {}",
+            name, current, old
+        ))),
+        None if !must_not_exist => Err(GitError(format!(
+            "cannot lock ref '{}': unable to resolve reference '{}'",
+            name, name
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Takes the lock on the ref `name`, stored at `path`, returning the
+/// lock's path.
+fn lock_ref(name: &str, path: &Path) -> GitResult<PathBuf> {
+    lockfile::lock(path, &format!("ref {}", name))
+}
+
+/// Removes a loose ref along with its reflog.
+pub fn delete_ref(root: &str, name: &str) -> GitResult<()> {
+    check_ref_format(name)?;
+    let path = git_path(root, name);
+    let lock = lock_ref(name, &path)?;
+    let mut result = Ok(());
+    for path in &[path, git_path(root, &format!("logs/{}", name))] {
+        if fs::metadata(path).is_ok() {
+            result = result.and(fs::remove_file(path));
+        }
+    }
+    fs::remove_file(lock)?;
+    Ok(result?)
+}
+
+/// One line of a reflog, recording a ref moving from `old` to `new`.
+#[derive(Debug, Clone)]
+pub struct ReflogEntry {
+    pub old: String,
+    pub new: String,
+    /// Who moved the ref and when, as `Name <email> timestamp timezone`.
+    pub identity: String,
+    pub message: String,
+}
+
+/// Reads the reflog of a ref, oldest entry first.
+pub fn read_reflog(root: &str, name: &str) -> GitResult<Vec<ReflogEntry>> {
+    let content = match fs::read_to_string(git_path(root, &format!("logs/{}", name))) {
+        Ok(content) => content,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let mut entries = Vec::new();
+    for line in content.lines() {
+        let (head, message) = line.split_once('\t').unwrap_or((line, ""));
+        let mut parts = head.splitn(3, ' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(old), Some(new), Some(identity)) => entries.push(ReflogEntry {
+                old: old.to_owned(),
+                new: new.to_owned(),
+                identity: identity.to_owned(),
+                message: message.to_owned(),
+            }),
+            _ => {
+                return Err(GitError(format!(
+                    "Invalid reflog entry for {}: {}",
+                    name, line
+                )))
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Replaces the reflog of a ref.
+pub fn write_reflog(root: &str, name: &str, entries: &[ReflogEntry]) -> GitResult<()> {
+    check_ref_format(name)?;
+    let content: String = entries
+        .iter()
+        .map(|e| format!("{} {} {}\t{}\n", e.old, e.new, e.identity, e.message))
+        .collect();
+    write_ref_content(root, &format!("logs/{}", name), &content)
+}
+
+/// Adds an entry to the end of the reflog of a ref.
+pub fn append_reflog(root: &str, name: &str, entry: ReflogEntry) -> GitResult<()> {
+    let mut entries = read_reflog(root, name)?;
+    entries.push(entry);
+    write_reflog(root, name, &entries)
+}
+
+/// Resolves a revision (a full SHA, `HEAD`, or a ref name) to a SHA, looking
+/// the name up in the same places git does.
+pub fn resolve_rev(root: &str, rev: &str) -> GitResult<String> {
+    if is_sha(rev) {
+        return Ok(rev.to_owned());
+    }
+    let sha = match expand_ref(root, rev)? {
+        Some(name) => {
+            let sha = read_ref(root, &name)?;
+            let at = sha.as_deref().unwrap_or("nothing");
+            trace::print("REFS", &format!("{} is {} at {}", rev, name, at));
+            sha
+        }
+        None => None,
+    };
+    sha.ok_or_else(|| GitError(format!("Unknown revision: {}", rev)))
+}
+
+/// Finds the full name of the ref a short name such as `master` refers to.
+pub fn expand_ref(root: &str, name: &str) -> GitResult<Option<String>> {
+    for candidate in &[
+        name.to_owned(),
+        format!("refs/{}", name),
+        format!("refs/tags/{}", name),
+        format!("refs/heads/{}", name),
+        format!("refs/remotes/{}", name),
+        format!("refs/remotes/{}/HEAD", name),
+    ] {
+        if read_ref(root, candidate)?.is_some() {
+            return Ok(Some(candidate.clone()));
+        }
+    }
+    Ok(None)
+}
+
+fn is_sha(s: &str) -> bool {
+    s.len() == 40 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bad_ref_names_are_rejected() {
+        for name in &[
+            "HEAD",
+            "ORIG_HEAD",
+            "refs/heads/master",
+            "refs/tags/v1.0",
+            "refs/heads/a-b/c",
+        ] {
+            assert!(check_ref_format(name).is_ok(), "{}", name);
+        }
+        for name in &[
+            "",
+            "master",
+            "refs/../../../x",
+            "refs/heads/../../..",
+            "refs/heads/.hidden",
+            "refs/heads/x.lock",
+            "refs/heads/a@{1}",
+            "refs/heads/a\x01",
+            "refs/heads/a b",
+            "refs/heads/a~1",
+            "refs/heads/a^",
+            "refs/heads/a:b",
+            "refs/heads/a?",
+            "refs/heads/a*",
+            "refs/heads/a[",
+            "refs/heads/a\\b",
+            "refs/heads/",
+            "refs/heads//x",
+            "refs/heads/x.",
+        ] {
+            assert!(check_ref_format(name).is_err(), "{}", name);
+        }
+    }
+}
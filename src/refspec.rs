@@ -0,0 +1,128 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::git_error::{GitError, GitResult};
+
+/// A fetch refspec such as `+refs/heads/*:refs/remotes/origin/*`, mapping
+/// remote ref names to the local names they are stored under.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Refspec {
+    /// Whether the local ref may be updated even if it isn't a fast-forward.
+    pub force: bool,
+    pub src: String,
+    pub dst: String,
+}
+
+impl Refspec {
+    pub fn parse(spec: &str) -> GitResult<Refspec> {
+        let (force, spec) = match spec.strip_prefix('+') {
+            Some(rest) => (true, rest),
+            None => (false, spec),
+        };
+        let mut parts = spec.splitn(2, ':');
+        let src = parts.next().unwrap_or("").to_owned();
+        let dst = parts.next().unwrap_or("").to_owned();
+
+        let globs = (src.matches('*').count(), dst.matches('*').count());
+        if src.is_empty() || dst.is_empty() || !(globs == (0, 0) || globs == (1, 1)) {
+            return Err(GitError(format!("Invalid refspec: {}", spec)));
+        }
+        Ok(Refspec { force, src, dst })
+    }
+
+    /// Maps a remote ref name to its local name, or `None` if the refspec
+    /// doesn't cover that ref.
+    pub fn map(&self, name: &str) -> Option<String> {
+        match self.src.find('*') {
+            None if name == self.src => Some(self.dst.clone()),
+            None => None,
+            Some(star) => {
+                let (prefix, suffix) = (&self.src[..star], &self.src[star + 1..]);
+                if name.len() >= prefix.len() + suffix.len()
+                    && name.starts_with(prefix)
+                    && name.ends_with(suffix)
+                {
+                    let matched = &name[prefix.len()..name.len() - suffix.len()];
+                    Some(self.dst.replacen('*', matched, 1))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// Maps a local ref name back to the remote ref it's stored for, or
+    /// `None` if the refspec doesn't cover that ref.
+    pub fn map_back(&self, name: &str) -> Option<String> {
+        Refspec {
+            force: self.force,
+            src: self.dst.clone(),
+            dst: self.src.clone(),
+        }
+        .map(name)
+    }
+}
+
+impl Display for Refspec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}:{}",
+            if self.force { "+" } else { "" },
+            self.src,
+            self.dst
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refspecs_map_names_both_ways() {
+        let spec = Refspec::parse("+refs/heads/*:refs/remotes/origin/*").unwrap();
+        assert!(spec.force);
+        assert_eq!(spec.to_string(), "+refs/heads/*:refs/remotes/origin/*");
+        assert_eq!(
+            spec.map("refs/heads/main").as_deref(),
+            Some("refs/remotes/origin/main")
+        );
+        assert_eq!(
+            spec.map("refs/heads/a/b").as_deref(),
+            Some("refs/remotes/origin/a/b")
+        );
+        assert_eq!(spec.map("refs/tags/v1"), None);
+        assert_eq!(
+            spec.map_back("refs/remotes/origin/main").as_deref(),
+            Some("refs/heads/main")
+        );
+        assert_eq!(spec.map_back("refs/heads/main"), None);
+
+        let spec = Refspec::parse("refs/heads/main:refs/heads/upstream").unwrap();
+        assert!(!spec.force);
+        assert_eq!(
+            spec.map("refs/heads/main").as_deref(),
+            Some("refs/heads/upstream")
+        );
+        assert_eq!(spec.map("refs/heads/main2"), None);
+
+        // The prefix and suffix around the `*` may not overlap.
+        let spec = Refspec::parse("refs/*/x:refs/y/*").unwrap();
+        assert_eq!(spec.map("refs/a/x").as_deref(), Some("refs/y/a"));
+        assert_eq!(spec.map("refs/x"), None);
+    }
+
+    #[test]
+    fn invalid_refspecs_are_rejected() {
+        for spec in &[
+            "",
+            "refs/heads/main",
+            ":refs/x",
+            "+refs/x:",
+            "refs/*:refs/x",
+            "a*b*:c*",
+        ] {
+            assert!(Refspec::parse(spec).is_err(), "{}", spec);
+        }
+    }
+}
@@ -1,66 +1,652 @@
-use reqwest::blocking::{get, Client};
+use bytes::Bytes;
+use reqwest::blocking::Client;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 
+use crate::config::Config;
 use crate::git_error::{GitError, GitResult};
-use crate::object::Object;
-use crate::pack;
-use std::collections::HashMap;
+use crate::idx::PackIndex;
+use crate::object;
+use crate::pack::{self, PackStats};
+use crate::pkt::{self, Packet, PktReader};
+use crate::store::MemoryStore;
+use crate::{trace, zlib};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Cursor, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, process};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Ref {
     pub sha: String,
     pub name: String,
 }
 
-pub fn get_refs(url: &str) -> GitResult<Vec<Ref>> {
-    let body = get(format!("{}/info/refs?service=git-upload-pack", url).as_str())?.text()?;
-    let mut refs = <Vec<Ref>>::new();
+/// How requests to a remote are made.
+#[derive(Debug, Clone)]
+pub struct HttpOptions {
+    pub user_agent: String,
+    /// Sent with every request, e.g. a token that an authenticating proxy wants.
+    pub extra_headers: Vec<(String, String)>,
+    /// Whether to show how much of a pack has been received.
+    pub progress: bool,
+    /// Whether to leave out the `remote:` messages the server sends.
+    pub quiet: bool,
+    /// Whether to speak HTTP/2 from the start, which the server must support.
+    pub http2: bool,
+}
 
-    for line in body.lines().skip(1) {
-        if line == "0000" {
-            break;
+impl Default for HttpOptions {
+    fn default() -> Self {
+        HttpOptions {
+            user_agent: format!("git-client-rust/{}", env!("CARGO_PKG_VERSION")),
+            extra_headers: Vec::new(),
+            progress: false,
+            quiet: false,
+            http2: false,
+        }
+    }
+}
+
+impl HttpOptions {
+    /// Reads the options from git's `http.userAgent`, `http.extraHeader`
+    /// (`Name: value`, may be repeated) and `http.version` (`HTTP/1.1` or
+    /// `HTTP/2`) settings. `GIT_HTTP_USER_AGENT` takes precedence over the
+    /// configured user agent.
+    pub fn from_config(config: &Config) -> GitResult<HttpOptions> {
+        let mut options = HttpOptions::default();
+        if let Some(user_agent) = env::var("GIT_HTTP_USER_AGENT")
+            .ok()
+            .filter(|user_agent| !user_agent.is_empty())
+            .or_else(|| config.get("http", None, "useragent").map(str::to_owned))
+        {
+            options.user_agent = user_agent;
+        }
+        for header in config.get_all("http", None, "extraheader") {
+            let mut parts = header.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if !name.trim().is_empty() => options
+                    .extra_headers
+                    .push((name.trim().to_owned(), value.trim().to_owned())),
+                _ => return Err(GitError(format!("Invalid http.extraHeader: {}", header))),
+            }
+        }
+        options.http2 = match config.get("http", None, "version") {
+            None | Some("HTTP/1.1") => false,
+            Some("HTTP/2") => true,
+            Some(version) => {
+                return Err(GitError(format!("Unsupported http.version: {}", version)))
+            }
+        };
+        Ok(options)
+    }
+
+    /// Builds the client for every request to a remote, so that they can
+    /// reuse its connections rather than each opening another.
+    pub fn connect(self) -> GitResult<HttpClient> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.extra_headers {
+            headers.append(
+                HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|e| GitError(format!("Invalid header name {}: {}", name, e)))?,
+                HeaderValue::from_str(value)
+                    .map_err(|e| GitError(format!("Invalid header value for {}: {}", name, e)))?,
+            );
+        }
+        let mut builder = Client::builder()
+            .user_agent(self.user_agent.as_str())
+            .default_headers(headers)
+            .tcp_keepalive(Duration::from_secs(60));
+        if self.http2 {
+            builder = builder.http2_prior_knowledge();
+        }
+        Ok(HttpClient {
+            client: builder.build()?,
+            options: self,
+        })
+    }
+}
+
+/// A connection to remotes over HTTP, shared by the requests of a command:
+/// the refs advertisement and each round of negotiation.
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    client: Client,
+    options: HttpOptions,
+}
+
+/// Lists the refs a server advertises, along with the capabilities it sent
+/// with the first of them.
+pub fn get_refs(url: &str, http: &HttpClient) -> GitResult<(Vec<Ref>, Vec<String>)> {
+    let mut body = PktReader::new(get_advertisement(url, http, false)?);
+    expect_service_header(&mut body)?;
+
+    let mut refs = <Vec<Ref>>::new();
+    let mut capabilities = Vec::new();
+    loop {
+        let line = match body.read()? {
+            Packet::Data(line) => line,
+            Packet::Flush => break,
+            packet => {
+                return Err(GitError(format!(
+                    "Unexpected packet in refs advertisement: {:?}",
+                    packet
+                )))
+            }
+        };
+        let line = line.strip_suffix(b"\n").unwrap_or(&line);
+        // Capabilities follow the first ref after a NUL byte.
+        let mut parts = line.splitn(2, |&b| b == 0);
+        let ref_data = parts.next().unwrap_or(line);
+        if let Some(list) = parts.next() {
+            capabilities = text(list)?
+                .split(' ')
+                .filter(|c| !c.is_empty())
+                .map(str::to_owned)
+                .collect();
+        }
+        let space = ref_data
+            .iter()
+            .position(|&b| b == b' ')
+            .ok_or("ref name not found")?;
+        let name = std::str::from_utf8(&ref_data[space + 1..])?;
+        // A repository without refs sends its capabilities on this
+        // placeholder instead, which isn't a ref.
+        if name == "capabilities^{}" {
+            continue;
         }
-        let ref_data: Vec<&str> = line
-            .split('\0')
-            .next()
-            .ok_or("Empty line in refs")?
-            .split(' ')
-            .collect();
         refs.push(Ref {
-            sha: ref_data
-                .get(0)
-                .ok_or("ref id not found")?
-                .trim_start_matches("0000")[4..]
-                .to_string(),
-            name: ref_data.get(1).ok_or("ref name not found")?.to_string(),
+            sha: std::str::from_utf8(&ref_data[..space])?.to_owned(),
+            name: name.to_owned(),
         })
     }
-    Ok(refs)
+    Ok((refs, capabilities))
 }
 
-pub fn fetch_ref(url: &str, ref_id: &str) -> GitResult<HashMap<String, Object>> {
-    let mut response = Client::builder()
-        .build()?
+/// Lists the server's protocol v2 capabilities, such as `fetch=shallow
+/// ref-in-want`. Returns `None` if the server only speaks protocol v0.
+pub fn get_capabilities_v2(url: &str, http: &HttpClient) -> GitResult<Option<Vec<String>>> {
+    let mut body = PktReader::new(get_advertisement(url, http, true)?);
+    let mut packet = body.read()?;
+    // Some servers precede the v2 capabilities with the v0 service header.
+    if let Packet::Data(ref line) = packet {
+        if line.starts_with(b"# service=") {
+            if body.read()? != Packet::Flush {
+                return Err(GitError(
+                    "No flush after the service header in refs advertisement".to_owned(),
+                ));
+            }
+            packet = body.read()?;
+        }
+    }
+    match packet {
+        Packet::Data(ref line) if &line[..] == b"version 2\n" => {}
+        _ => return Ok(None),
+    }
+    let mut capabilities = Vec::new();
+    loop {
+        match body.read()? {
+            Packet::Data(line) => capabilities.push(text(&line)?),
+            Packet::Flush => return Ok(Some(capabilities)),
+            packet => {
+                return Err(GitError(format!(
+                    "Unexpected packet in capability advertisement: {:?}",
+                    packet
+                )))
+            }
+        }
+    }
+}
+
+/// What to ask for in a protocol v2 fetch.
+#[derive(Debug, Default)]
+pub struct FetchRequest {
+    /// Refs to fetch by name with `want-ref`, so the server resolves them at
+    /// the moment it builds the pack rather than when they were listed. The
+    /// server must advertise `ref-in-want`.
+    pub want_refs: Vec<String>,
+    pub wants: Vec<String>,
+    /// Commits we have, so the server can leave out what they reference.
+    pub haves: Vec<String>,
+    /// The commits our history is cut off at, from `.git/shallow`.
+    pub shallow: Vec<String>,
+    /// Where to cut off the history sent.
+    pub deepen: Option<Deepen>,
+}
+
+/// How far back a shallow fetch goes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Deepen {
+    /// This many commits from the wants.
+    Depth(u32),
+    /// This many commits further back than the current shallow boundary.
+    Relative(u32),
+    /// Back to the commits committed at or after this time.
+    Since(i64),
+    /// Up to, but not including, what these refs reach.
+    Not(Vec<String>),
+}
+
+impl Deepen {
+    fn lines(&self) -> Vec<String> {
+        match self {
+            Deepen::Depth(depth) => vec![format!("deepen {}", depth)],
+            Deepen::Relative(depth) => {
+                vec![format!("deepen {}", depth), "deepen-relative".to_owned()]
+            }
+            Deepen::Since(time) => vec![format!("deepen-since {}", time)],
+            Deepen::Not(refs) => refs.iter().map(|r| format!("deepen-not {}", r)).collect(),
+        }
+    }
+}
+
+/// What the server reported besides the pack.
+#[derive(Debug, Default)]
+pub struct FetchResponse {
+    /// The SHAs that the `want_refs` resolved to.
+    pub wanted_refs: Vec<Ref>,
+    /// Commits that are now cut off from their parents.
+    pub shallow: Vec<String>,
+    /// Previously shallow commits whose parents have now been sent.
+    pub unshallow: Vec<String>,
+}
+
+/// Fetches objects with a protocol v2 `fetch` command.
+pub fn fetch_v2(
+    url: &str,
+    request: &FetchRequest,
+    pack_dir: &Path,
+    keep: bool,
+    http: &HttpClient,
+) -> GitResult<(FetchResponse, MemoryStore, PackStats)> {
+    let mut message = pkt::encode_line("command=fetch")?.to_vec();
+    message.extend_from_slice(&pkt::encode_line(&format!(
+        "agent={}",
+        http.options.user_agent
+    ))?);
+    message.extend_from_slice(&pkt::encode_delim());
+    message.extend_from_slice(&pkt::encode_line("ofs-delta")?);
+    message.extend_from_slice(&pkt::encode_line("no-progress")?);
+    let lines = request
+        .want_refs
+        .iter()
+        .map(|name| format!("want-ref {}", name))
+        .chain(request.wants.iter().map(|sha| format!("want {}", sha)))
+        .chain(request.haves.iter().map(|sha| format!("have {}", sha)))
+        .chain(request.shallow.iter().map(|sha| format!("shallow {}", sha)))
+        .chain(request.deepen.iter().flat_map(Deepen::lines));
+    for line in lines {
+        message.extend_from_slice(&pkt::encode_line(&line)?);
+    }
+    message.extend_from_slice(&pkt::encode_line("done")?);
+    message.extend_from_slice(&pkt::encode_flush());
+
+    trace::request(&message);
+    let response = http
+        .client
         .post(format!("{}/git-upload-pack", url).as_str())
-        .body(pkt_message(vec![format!("want {}", ref_id)]))
+        .body(message)
         .header("Content-Type", "application/x-git-upload-pack-request")
-        .send()?
-        .bytes()?;
-    let nak = response.split_to(8);
-    if nak.as_ref() != b"0008NAK\n" {
-        return Err(GitError(format!("No NAK header in response: {:?}", nak)));
+        .header("Git-Protocol", "version=2")
+        .send()?;
+
+    // Sections other than the pack, such as `shallow-info`, end with a delimiter.
+    let mut response = PktReader::new(response);
+    let mut result = FetchResponse::default();
+    let mut section = String::new();
+    loop {
+        match response.read()? {
+            Packet::Data(line) => {
+                let line = text(&line)?;
+                if section.is_empty() {
+                    section = line;
+                    if section == "packfile" {
+                        break;
+                    }
+                    continue;
+                }
+                let mut parts = line.splitn(2, ' ');
+                match (section.as_str(), parts.next(), parts.next()) {
+                    ("wanted-refs", Some(sha), Some(name)) => result.wanted_refs.push(Ref {
+                        sha: sha.to_owned(),
+                        name: name.to_owned(),
+                    }),
+                    ("shallow-info", Some("shallow"), Some(sha)) => {
+                        result.shallow.push(sha.to_owned())
+                    }
+                    ("shallow-info", Some("unshallow"), Some(sha)) => {
+                        result.unshallow.push(sha.to_owned())
+                    }
+                    ("wanted-refs", _, _) | ("shallow-info", _, _) => {
+                        return Err(GitError(format!("Invalid {} line: {}", section, line)))
+                    }
+                    _ => {}
+                }
+            }
+            Packet::Delimiter => section.clear(),
+            packet => {
+                return Err(GitError(format!(
+                    "Unexpected packet in fetch response: {:?}",
+                    packet
+                )))
+            }
+        }
+    }
+
+    let path = download_pack(
+        &mut Sideband::new(&mut response, http.options.quiet),
+        pack_dir,
+        http.options.progress,
+    )?;
+    let (objects, stats) = parse_downloaded_pack(&path, pack_dir, keep)?;
+    Ok((result, objects, stats))
+}
+
+/// Number of `have` lines offered to the server per negotiation round.
+const HAVES_PER_ROUND: usize = 32;
+
+/// Fetches the objects needed for the `wants` SHAs over stateless smart HTTP.
+/// Commits in `haves` are offered to the server over as many requests as it
+/// takes; `done` is only sent once the server reports it's ready to send the
+/// pack or there is nothing left to offer. The pack is downloaded into
+/// `pack_dir`; with `keep`, it stays there along with its index instead of
+/// being thrown away once parsed.
+pub fn fetch_refs(
+    url: &str,
+    wants: &[String],
+    haves: &[String],
+    pack_dir: &Path,
+    keep: bool,
+    http: &HttpClient,
+) -> GitResult<(MemoryStore, PackStats)> {
+    // Capabilities ride along on the first want line.
+    let wants: Vec<String> = wants
+        .iter()
+        .enumerate()
+        .map(|(i, sha)| match i {
+            0 => format!("want {} multi_ack_detailed", sha),
+            _ => format!("want {}", sha),
+        })
+        .collect();
+    let mut common: Vec<String> = Vec::new();
+    let mut remaining = haves.iter();
+    let mut ready = false;
+
+    loop {
+        let round: Vec<String> = if ready {
+            Vec::new()
+        } else {
+            remaining.by_ref().take(HAVES_PER_ROUND).cloned().collect()
+        };
+        let done = round.is_empty();
+        let have_lines: Vec<String> = common
+            .iter()
+            .chain(round.iter())
+            .map(|sha| format!("have {}", sha))
+            .collect();
+
+        let message = pkt_message(&wants, &have_lines, done)?;
+        trace::request(&message);
+        let response = http
+            .client
+            .post(format!("{}/git-upload-pack", url).as_str())
+            .body(message)
+            .header("Content-Type", "application/x-git-upload-pack-request")
+            .send()?;
+        let mut response = PktReader::new(BufReader::new(response));
+        let acks = read_acknowledgments(&mut response)?;
+        if done {
+            let path = download_pack(response.get_mut(), pack_dir, http.options.progress)?;
+            return parse_downloaded_pack(&path, pack_dir, keep);
+        }
+
+        for ack in acks {
+            let words: Vec<&str> = ack.split(' ').collect();
+            match words.as_slice() {
+                ["ACK", sha, "common"] | ["ACK", sha, "ready"] => {
+                    ready |= words[2] == "ready";
+                    if !common.iter().any(|c| c == sha) {
+                        common.push(sha.to_string());
+                    }
+                }
+                ["NAK"] => {}
+                _ => return Err(GitError(format!("Unexpected acknowledgment: {}", ack))),
+            }
+        }
     }
-    Ok(pack::parse_pack(response)?)
 }
 
-fn pkt_message(lines: Vec<String>) -> String {
-    lines
-        .into_iter()
-        .map(encode_pkt)
-        .collect::<Vec<String>>()
-        .join("")
-        + "00000009done\n"
+/// Requests the refs advertisement, in protocol v2 if `v2` is set. Proxies
+/// may compress it, and servers disagree on whether "deflate" means
+/// zlib-wrapped or raw DEFLATE.
+fn get_advertisement(url: &str, http: &HttpClient, v2: bool) -> GitResult<Box<dyn Read>> {
+    let mut request = http
+        .client
+        .get(format!("{}/info/refs?service=git-upload-pack", url).as_str());
+    if v2 {
+        request = request.header("Git-Protocol", "version=2");
+    }
+    let mut response = request.send()?;
+    let encoding = response
+        .headers()
+        .get("content-encoding")
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_lowercase);
+    Ok(match encoding.as_deref() {
+        Some("gzip") | Some("deflate") => {
+            let mut raw = Vec::new();
+            response.read_to_end(&mut raw)?;
+            Box::new(Cursor::new(zlib::read_any(Bytes::from(raw))?))
+        }
+        _ => Box::new(response),
+    })
+}
+
+fn expect_service_header<R: Read>(body: &mut PktReader<R>) -> GitResult<()> {
+    match body.read()? {
+        Packet::Data(ref line) if line.starts_with(b"# service=") => {}
+        packet => {
+            return Err(GitError(format!(
+                "No service header in refs advertisement: {:?}",
+                packet
+            )))
+        }
+    }
+    if body.read()? != Packet::Flush {
+        return Err(GitError(
+            "No flush after the service header in refs advertisement".to_owned(),
+        ));
+    }
+    Ok(())
 }
 
-fn encode_pkt(msg: String) -> String {
-    format!("{:04x}{}\n", msg.len() + 5, msg)
+/// Consumes the acknowledgment lines at the front of an upload-pack response,
+/// up to and including the `NAK` or final `ACK <sha>` that ends them, or up to
+/// the pack if that comes first. Shallow updates are skipped over, since we
+/// don't ask for them.
+fn read_acknowledgments<R: BufRead>(response: &mut PktReader<R>) -> GitResult<Vec<String>> {
+    let mut acks = Vec::new();
+    loop {
+        if response.peek_is(b"PACK")? {
+            return Ok(acks);
+        }
+        let line = match response.read()? {
+            Packet::Data(line) => text(&line)?,
+            Packet::Flush => continue,
+            packet => {
+                return Err(GitError(format!(
+                    "Unexpected packet in acknowledgments: {:?}",
+                    packet
+                )))
+            }
+        };
+        if line.starts_with("shallow ") || line.starts_with("unshallow ") {
+            continue;
+        }
+        let is_last = line == "NAK" || (line.starts_with("ACK ") && line.split(' ').count() == 2);
+        acks.push(line);
+        if is_last {
+            return Ok(acks);
+        }
+    }
+}
+
+/// Reads a data packet as text, without the newline ending it.
+fn text(line: &[u8]) -> GitResult<String> {
+    Ok(std::str::from_utf8(line)?.trim_end_matches('\n').to_owned())
+}
+
+/// Streams the pack at the rest of `response` into a new temporary file in
+/// `dir`, so it is never held in memory in one piece. The file is removed
+/// again if the download fails. With `progress`, how much has been received
+/// is reported on stderr.
+fn download_pack<R: Read>(response: &mut R, dir: &Path, progress: bool) -> GitResult<PathBuf> {
+    let (path, mut file) = create_temp_pack(dir)?;
+    match copy_pack(response, &mut file, progress) {
+        Ok(()) => Ok(path),
+        Err(e) => {
+            let _ = fs::remove_file(&path);
+            Err(e)
+        }
+    }
+}
+
+/// Creates a `tmp_pack_*` file in `dir` that didn't exist before, so that a
+/// file or link someone else put there is never written through.
+fn create_temp_pack(dir: &Path) -> GitResult<(PathBuf, File)> {
+    let mut attempt = 0;
+    loop {
+        let path = dir.join(format!("tmp_pack_{}_{}", process::id(), attempt));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => attempt += 1,
+            Err(e) => return Err(GitError::from(e)),
+        }
+    }
+}
+
+/// Copies the pack data from `response` into `file`.
+fn copy_pack<R: Read>(response: &mut R, file: &mut File, progress: bool) -> GitResult<()> {
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut received = 0;
+    loop {
+        let read = response.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        file.write_all(&buffer[..read])?;
+        received += read;
+        if progress {
+            eprint!("\rReceiving pack: {} KiB", received / 1024);
+        }
+    }
+    if progress {
+        eprintln!();
+    }
+    Ok(())
+}
+
+/// Reads the pack data out of a v2 `packfile` section, where each pkt-line
+/// starts with a band number: 1 for data, 2 for progress and 3 for errors.
+struct Sideband<'a, R: Read> {
+    packets: &'a mut PktReader<R>,
+    /// The data of the current line, and how much of it has been read.
+    line: Bytes,
+    read: usize,
+    /// Whether progress messages are left out rather than shown.
+    quiet: bool,
+}
+
+impl<'a, R: Read> Sideband<'a, R> {
+    fn new(packets: &'a mut PktReader<R>, quiet: bool) -> Self {
+        Sideband {
+            packets,
+            line: Bytes::new(),
+            read: 0,
+            quiet,
+        }
+    }
+}
+
+impl<'a, R: Read> Read for Sideband<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.read == self.line.len() {
+            let line = match self.packets.read() {
+                Ok(Packet::Data(line)) => line,
+                Ok(_) => return Ok(0),
+                Err(e) => return Err(std::io::Error::other(e.0)),
+            };
+            match line.first() {
+                Some(1) => {
+                    self.line = line;
+                    self.read = 1;
+                }
+                Some(2) if self.quiet => {}
+                Some(2) => eprint!("remote: {}", String::from_utf8_lossy(&line[1..])),
+                None => {}
+                _ => {
+                    return Err(std::io::Error::other(format!(
+                        "remote error: {}",
+                        String::from_utf8_lossy(&line[1..])
+                    )))
+                }
+            }
+        }
+        let len = (self.line.len() - self.read).min(buf.len());
+        buf[..len].copy_from_slice(&self.line[self.read..self.read + len]);
+        self.read += len;
+        Ok(len)
+    }
+}
+
+/// Parses a downloaded pack, keeping it in `pack_dir` with `keep` and
+/// deleting it otherwise. A pack with no objects in it, as a server sends
+/// when there's nothing new, is never kept.
+fn parse_downloaded_pack(
+    path: &Path,
+    pack_dir: &Path,
+    keep: bool,
+) -> GitResult<(MemoryStore, PackStats)> {
+    let parsed = File::open(path)
+        .map_err(GitError::from)
+        .and_then(|file| pack::parse_pack(BufReader::new(file), &pack::PackLimits::default()));
+    match parsed {
+        Ok((objects, stats)) if keep && !stats.entries.is_empty() => {
+            if let Err(e) = keep_pack(path, pack_dir, &stats) {
+                let _ = fs::remove_file(path);
+                return Err(e);
+            }
+            Ok((objects, stats))
+        }
+        parsed => {
+            fs::remove_file(path)?;
+            parsed
+        }
+    }
+}
+
+/// Moves a downloaded pack to its final `pack-<checksum>.pack` name and writes
+/// the index that lets objects be read from it.
+fn keep_pack(path: &Path, dir: &Path, stats: &PackStats) -> GitResult<()> {
+    let name = format!("pack-{}", object::to_hex(&stats.checksum));
+    let pack = dir.join(format!("{}.pack", name));
+    fs::rename(path, &pack)?;
+    PackIndex::for_pack(&pack, stats)?.write(&dir.join(format!("{}.idx", name)))
+}
+
+fn pkt_message(wants: &[String], haves: &[String], done: bool) -> GitResult<Vec<u8>> {
+    let mut message = Vec::new();
+    for want in wants {
+        message.extend_from_slice(&pkt::encode_line(want)?);
+    }
+    message.extend_from_slice(&pkt::encode_flush());
+    for have in haves {
+        message.extend_from_slice(&pkt::encode_line(have)?);
+    }
+    if done {
+        message.extend_from_slice(&pkt::encode_line("done")?);
+    } else {
+        message.extend_from_slice(&pkt::encode_flush());
+    }
+    Ok(message)
 }
@@ -0,0 +1,482 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use bytes::Bytes;
+use flate2::read::ZlibDecoder;
+
+use crate::config::Config;
+use crate::git_error::{GitError, GitResult};
+use crate::idx::PackIndex;
+use crate::object::{self, Object, ObjectReference, Sha, VerifiedReader};
+use crate::store::ObjectStore;
+use crate::{pack, refs, zlib};
+
+/// Where HEAD points.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Head {
+    /// A branch, by its full ref name. A new repository's branch is unborn:
+    /// it doesn't exist until its first commit.
+    Branch(String),
+    /// A commit, by its SHA.
+    Detached(String),
+}
+
+impl Head {
+    /// The branch's short name, if HEAD is on one.
+    pub fn branch_name(&self) -> Option<&str> {
+        match self {
+            Head::Branch(branch) => Some(branch.strip_prefix("refs/heads/").unwrap_or(branch)),
+            Head::Detached(_) => None,
+        }
+    }
+}
+
+/// The path of `name` in the git directory of the working tree at `root`,
+/// usually `<root>/.git/<name>`. A linked working tree's `.git` is instead a
+/// file naming its own git directory, which keeps its HEAD and index, and
+/// whose `commondir` names the main one, where everything else is shared.
+pub fn git_path(root: &str, name: &str) -> PathBuf {
+    let dot_git = Path::new(root).join(".git");
+    let git_dir = match fs::read_to_string(&dot_git) {
+        Ok(content) => match content.trim_end().strip_prefix("gitdir: ") {
+            Some(dir) => Path::new(root).join(dir),
+            None => dot_git,
+        },
+        Err(_) => return dot_git.join(name),
+    };
+    if is_per_worktree(name) {
+        return git_dir.join(name);
+    }
+    match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common) => git_dir.join(common.trim_end()).join(name),
+        Err(_) => git_dir.join(name),
+    }
+}
+
+/// Whether a file in the git directory belongs to one working tree, rather
+/// than being shared by all of a repository's working trees.
+fn is_per_worktree(name: &str) -> bool {
+    matches!(
+        name,
+        "HEAD" | "index" | "logs/HEAD" | "COMMIT_EDITMSG" | "ORIG_HEAD" | "info/sparse-checkout"
+    )
+}
+
+/// A repository, identified by the root of its working tree.
+pub struct Repository {
+    root: String,
+    /// Where objects are read from and written to, `.git/objects` by default.
+    pub objects_dir: PathBuf,
+    /// When set, objects are hashed but never written.
+    pub dry_run: bool,
+    /// `core.symlinks`: whether symlinks are checked out as such, rather than
+    /// as files holding their target.
+    pub symlinks: bool,
+    /// `core.ignorecase`: whether the filesystem ignores case in file names.
+    pub ignore_case: bool,
+    /// When set, status messages are left out.
+    pub quiet: bool,
+    /// Whether to show progress while downloading.
+    pub progress: bool,
+    /// The pack indexes read so far, by path. Packs are named after their
+    /// content, so an index at a given path never changes.
+    pack_indexes: RefCell<HashMap<PathBuf, Rc<PackIndex>>>,
+    /// What `refs/replace/` maps each replaced object to, read on first use.
+    replacements: RefCell<Option<HashMap<String, String>>>,
+}
+
+impl Repository {
+    pub fn new(root: &str) -> Repository {
+        Repository {
+            root: root.to_owned(),
+            objects_dir: git_path(root, "objects"),
+            dry_run: false,
+            symlinks: true,
+            ignore_case: false,
+            quiet: false,
+            progress: false,
+            pack_indexes: RefCell::default(),
+            replacements: RefCell::default(),
+        }
+    }
+
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    /// Where the index is kept: `.git/index`, unless `GIT_INDEX_FILE` names
+    /// another file, as it does for git.
+    pub fn index_path(&self) -> PathBuf {
+        match env::var_os("GIT_INDEX_FILE") {
+            Some(path) if !path.is_empty() => PathBuf::from(path),
+            _ => git_path(&self.root, "index"),
+        }
+    }
+
+    /// Opens an existing repository, refusing ones that need features we don't
+    /// have, as given by `core.repositoryformatversion` and `extensions.*`.
+    pub fn open(root: &str) -> GitResult<Repository> {
+        let config = Config::load(root)?;
+        let version = match config.get("core", None, "repositoryformatversion") {
+            Some(version) => version
+                .parse::<u32>()
+                .map_err(|_| GitError(format!("Invalid repository format version: {}", version)))?,
+            None => 0,
+        };
+        if version > 1 {
+            return Err(GitError(format!(
+                "Expected git repo version <= 1, found {}",
+                version
+            )));
+        }
+        // Extensions only became binding with version 1.
+        if version == 1 {
+            for (key, value) in config.section("extensions", None) {
+                let supported = match key {
+                    "noop" | "preciousobjects" => true,
+                    "objectformat" => value.eq_ignore_ascii_case("sha1"),
+                    _ => false,
+                };
+                if !supported {
+                    return Err(GitError(format!(
+                        "Unknown repository extension: {} = {}",
+                        key, value
+                    )));
+                }
+            }
+        }
+        let mut repo = Repository::new(root);
+        repo.symlinks = config.get_bool("core", None, "symlinks")?.unwrap_or(true);
+        repo.ignore_case = config
+            .get_bool("core", None, "ignorecase")?
+            .unwrap_or(false);
+        Ok(repo)
+    }
+
+    /// Where HEAD points, failing if it's missing.
+    pub fn head(&self) -> GitResult<Head> {
+        if let Some(branch) = refs::read_symref(&self.root, "HEAD")? {
+            return Ok(Head::Branch(branch));
+        }
+        refs::read_ref(&self.root, "HEAD")?
+            .map(Head::Detached)
+            .ok_or_else(|| GitError("HEAD is missing".to_owned()))
+    }
+
+    /// The commit HEAD points at, or `None` on an unborn branch.
+    pub fn head_commit(&self) -> GitResult<Option<String>> {
+        match self.head()? {
+            Head::Branch(branch) => refs::read_ref(&self.root, &branch),
+            Head::Detached(sha) => Ok(Some(sha)),
+        }
+    }
+
+    /// Points HEAD at a branch, as a symbolic ref, or detaches it.
+    pub fn set_head(&self, head: &Head) -> GitResult<()> {
+        match head {
+            Head::Branch(branch) => refs::write_symref(&self.root, "HEAD", branch),
+            Head::Detached(sha) => refs::write_ref(&self.root, "HEAD", sha),
+        }
+    }
+
+    /// Reads an object in the loose object format, header included, without
+    /// decoding it.
+    pub fn read_raw_object(&self, sha: &str) -> GitResult<Bytes> {
+        let replaced = self.replacement_object(sha)?;
+        self.read_stored_raw_object(replaced.as_deref().unwrap_or(sha))
+    }
+
+    /// Reads an object as it's stored, without substituting replacements.
+    pub fn read_stored_object(&self, sha: &str) -> GitResult<Object> {
+        Object::decode(self.read_stored_raw_object(sha)?)
+    }
+
+    /// Reads an object as it's stored, loose if it is, and packed otherwise.
+    pub fn read_stored_raw_object(&self, sha: &str) -> GitResult<Bytes> {
+        match self.read_loose_object(sha)? {
+            Some(content) => Ok(content),
+            None => self
+                .read_packed_object(sha)?
+                .ok_or_else(|| GitError(format!("Object not found: {}", sha))),
+        }
+    }
+
+    /// Reads the loose copy of an object, if there is one.
+    pub fn read_loose_object(&self, sha: &str) -> GitResult<Option<Bytes>> {
+        for dir in object_dirs(&self.objects_dir) {
+            if let Ok(bytes) = fs::read(dir.join(&sha[0..2]).join(&sha[2..])) {
+                return Ok(Some(zlib::read(Bytes::from(bytes))?.1));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the packed copy of an object, if there is one, in the loose
+    /// object format.
+    pub fn read_packed_object(&self, sha: &str) -> GitResult<Option<Bytes>> {
+        let sha = object::from_hex(sha)?;
+        for dir in object_dirs(&self.objects_dir) {
+            if let Some((pack, index)) = self.find_pack(&dir, &sha)? {
+                let offset = pack_offset(&index, &sha)?;
+                return Ok(Some(pack::read_packed_object(&pack, &index, offset)?));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether the store, alternates included, holds `sha`, loose or packed.
+    /// Nothing is inflated: loose objects are checked for by path and packs
+    /// by their indexes.
+    pub fn has_object(&self, sha: &Sha) -> bool {
+        let hex = object::to_hex(sha);
+        object_dirs(&self.objects_dir).iter().any(|dir| {
+            dir.join(&hex[0..2]).join(&hex[2..]).exists()
+                || matches!(self.find_pack(dir, sha), Ok(Some(_)))
+        })
+    }
+
+    /// Opens an object for reading its content as a stream, returning its
+    /// type and size alongside. Loose objects and whole packed objects are
+    /// inflated as they're read, so large blobs never have to fit in memory.
+    pub fn open_object(&self, sha: &str) -> GitResult<(String, usize, Box<dyn Read>)> {
+        let replaced = self.replacement_object(sha)?;
+        self.open_stored_object(replaced.as_deref().unwrap_or(sha))
+    }
+
+    /// Opens an object as it's stored, without substituting replacements.
+    pub fn open_stored_object(&self, sha: &str) -> GitResult<(String, usize, Box<dyn Read>)> {
+        let dirs = object_dirs(&self.objects_dir);
+        for dir in &dirs {
+            if let Ok(file) = fs::File::open(dir.join(&sha[0..2]).join(&sha[2..])) {
+                let mut file = BufReader::new(file);
+                zlib::check_preset_dictionary(file.fill_buf()?)?;
+                let mut decoder = flate2::bufread::ZlibDecoder::new(file);
+                let mut header = Vec::new();
+                let mut byte = [0u8];
+                while decoder.read(&mut byte)? == 1 && byte[0] != 0 {
+                    header.push(byte[0]);
+                }
+                let header = String::from_utf8(header)?;
+                let (kind, size) = header
+                    .split_once(' ')
+                    .ok_or_else(|| GitError(format!("Corrupt object header: {}", sha)))?;
+                let size = size.parse()?;
+                let content = VerifiedReader::new(decoder, kind, size, object::from_hex(sha)?);
+                return Ok((kind.to_owned(), size, Box::new(content)));
+            }
+        }
+        let sha_bytes = object::from_hex(sha)?;
+        for dir in &dirs {
+            if let Some((pack, index)) = self.find_pack(dir, &sha_bytes)? {
+                let offset = pack_offset(&index, &sha_bytes)?;
+                let (kind, size, content) = pack::open_packed_object(&pack, &index, offset)?;
+                let content = VerifiedReader::new(content, kind, size, sha_bytes);
+                return Ok((kind.to_owned(), size, Box::new(content)));
+            }
+        }
+        Err(GitError(format!("Object not found: {}", sha)))
+    }
+
+    /// Looks up the object `git replace` substitutes for `sha`, if any. Setting
+    /// `GIT_NO_REPLACE_OBJECTS` turns replacement off, as it does for git.
+    fn replacement_object(&self, sha: &str) -> GitResult<Option<String>> {
+        if env::var_os("GIT_NO_REPLACE_OBJECTS").is_some() {
+            return Ok(None);
+        }
+        let mut replacements = self.replacements.borrow_mut();
+        if replacements.is_none() {
+            let refs = refs::list_refs(&self.root, "refs/replace/")?;
+            *replacements = Some(
+                refs.into_iter()
+                    .map(|(name, sha)| (name["refs/replace/".len()..].to_owned(), sha))
+                    .collect(),
+            );
+        }
+        let replacements = match &*replacements {
+            Some(replacements) if !replacements.is_empty() => replacements,
+            _ => return Ok(None),
+        };
+        let mut replacement = None;
+        // Replacements can chain; bound the depth like git does.
+        for _ in 0..5 {
+            let current = replacement.as_deref().unwrap_or(sha);
+            match replacements.get(current) {
+                Some(next) => replacement = Some(next.clone()),
+                None => return Ok(replacement),
+            }
+        }
+        Err(GitError(format!(
+            "Replace depth too high for object {}",
+            sha
+        )))
+    }
+
+    /// Lists the commits among the loose objects. Only their headers are
+    /// inflated, so this stays cheap for a large store.
+    pub fn loose_commits(&self) -> GitResult<Vec<String>> {
+        let mut commits = Vec::new();
+        for dir in fs::read_dir(&self.objects_dir)? {
+            let dir = dir?;
+            let prefix = dir.file_name().to_string_lossy().into_owned();
+            if prefix.len() != 2 || !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(dir.path())? {
+                let file = file?;
+                let mut header = [0u8; 7];
+                let mut decoder = ZlibDecoder::new(fs::File::open(file.path())?);
+                if decoder.read_exact(&mut header).is_ok() && &header == b"commit " {
+                    commits.push(format!("{}{}", prefix, file.file_name().to_string_lossy()));
+                }
+            }
+        }
+        Ok(commits)
+    }
+
+    /// Lists every object in the store, loose or packed, in SHA order.
+    /// Alternates aren't included.
+    pub fn all_objects(&self) -> GitResult<Vec<String>> {
+        let mut shas = Vec::new();
+        for dir in fs::read_dir(&self.objects_dir)? {
+            let dir = dir?;
+            let prefix = dir.file_name().to_string_lossy().into_owned();
+            if prefix.len() != 2 || !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(dir.path())? {
+                let name = file?.file_name().to_string_lossy().into_owned();
+                if name.len() == 38 {
+                    shas.push(format!("{}{}", prefix, name));
+                }
+            }
+        }
+        if let Ok(entries) = fs::read_dir(self.objects_dir.join("pack")) {
+            for entry in entries {
+                let path = entry?.path();
+                if path.extension() == Some("idx".as_ref()) {
+                    let index = self.pack_index(&path)?;
+                    shas.extend(index.entries.iter().map(|e| object::to_hex(&e.sha)));
+                }
+            }
+        }
+        shas.sort();
+        shas.dedup();
+        Ok(shas)
+    }
+
+    /// Writes a tree made of `entries`, which are checked and put into git's
+    /// canonical order first. Nothing but the object store is touched.
+    pub fn write_tree_entries(&self, mut entries: Vec<ObjectReference>) -> GitResult<Sha> {
+        for entry in &entries {
+            object::check_entry_name(&entry.name)?;
+            if !matches!(entry.mode, 40000 | 100644 | 100755 | 120000 | 160000) {
+                return Err(GitError(format!(
+                    "Invalid mode {} for tree entry {}",
+                    entry.mode,
+                    String::from_utf8_lossy(&entry.name)
+                )));
+            }
+        }
+        // Encoding the tree rejects duplicate names.
+        object::sort_tree_entries(&mut entries);
+        self.write_object(&Object::Tree(entries))
+    }
+
+    /// Finds the pack in an object directory holding `sha`, returning its
+    /// path and index.
+    fn find_pack(
+        &self,
+        objects_dir: &Path,
+        sha: &Sha,
+    ) -> GitResult<Option<(PathBuf, Rc<PackIndex>)>> {
+        let entries = match fs::read_dir(objects_dir.join("pack")) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(None),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension() != Some("idx".as_ref()) {
+                continue;
+            }
+            let index = self.pack_index(&path)?;
+            if index.find(sha).is_some() {
+                return Ok(Some((path.with_extension("pack"), index)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Reads the pack index at `path`, or takes it from those already read.
+    fn pack_index(&self, path: &Path) -> GitResult<Rc<PackIndex>> {
+        if let Some(index) = self.pack_indexes.borrow().get(path) {
+            return Ok(Rc::clone(index));
+        }
+        let index = Rc::new(PackIndex::read(path)?);
+        self.pack_indexes
+            .borrow_mut()
+            .insert(path.to_path_buf(), Rc::clone(&index));
+        Ok(index)
+    }
+}
+
+/// Where `sha` is in the pack `index` belongs to.
+fn pack_offset(index: &PackIndex, sha: &Sha) -> GitResult<u64> {
+    index
+        .find(sha)
+        .map(|entry| entry.offset)
+        .ok_or_else(|| GitError(format!("Object not found in pack: {}", object::to_hex(sha))))
+}
+
+/// Lists the object directories to search: the repository's own, followed by
+/// any shared stores named in `info/alternates` (which may list more in turn).
+fn object_dirs(objects_dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![objects_dir.to_path_buf()];
+    let mut i = 0;
+    // Bound the search like git does, which also guards against cycles.
+    while i < dirs.len() && dirs.len() < 6 {
+        if let Ok(alternates) = fs::read_to_string(dirs[i].join("info/alternates")) {
+            for line in alternates.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                // Relative entries are relative to the directory listing them.
+                let dir = dirs[i].join(line);
+                if !dirs.contains(&dir) {
+                    dirs.push(dir);
+                }
+            }
+        }
+        i += 1;
+    }
+    dirs
+}
+
+impl ObjectStore for Repository {
+    fn write_raw_object(&self, data: Bytes) -> GitResult<Sha> {
+        let hash = object::get_sha(&data);
+        if self.dry_run || self.has_object(&hash) {
+            return Ok(hash);
+        }
+        let hex = object::to_hex(&hash);
+        let dir = self.objects_dir.join(&hex[0..2]);
+        fs::create_dir_all(&dir)?;
+        // Written whole under a temporary name and renamed into place, so a
+        // crash or a concurrent reader never sees a partial object.
+        let temp = dir.join(format!("tmp_obj_{}_{}", std::process::id(), &hex[2..]));
+        fs::write(&temp, zlib::write(&data)?)?;
+        if let Err(e) = fs::rename(&temp, dir.join(&hex[2..])) {
+            let _ = fs::remove_file(&temp);
+            return Err(e.into());
+        }
+        Ok(hash)
+    }
+
+    fn read_object(&self, sha: &str) -> GitResult<Object> {
+        Object::decode(self.read_raw_object(sha)?)
+    }
+}
@@ -0,0 +1,35 @@
+use std::fs;
+
+use crate::git_error::GitResult;
+use crate::repository::git_path;
+
+/// Reads `.git/shallow`, which lists the commits whose parents were left out
+/// of a shallow clone. A repository with complete history has no such file.
+pub fn read(root: &str) -> GitResult<Vec<String>> {
+    match fs::read_to_string(git_path(root, "shallow")) {
+        Ok(content) => Ok(content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(str::to_owned)
+            .collect()),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+/// Replaces the list of shallow commits, removing the file once the history
+/// is complete.
+pub fn write(root: &str, shallow: &[String]) -> GitResult<()> {
+    let path = git_path(root, "shallow");
+    if shallow.is_empty() {
+        if fs::metadata(&path).is_ok() {
+            fs::remove_file(path)?;
+        }
+        return Ok(());
+    }
+    let mut shallow = shallow.to_vec();
+    shallow.sort();
+    shallow.dedup();
+    fs::write(path, shallow.join("\n") + "\n")?;
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use std::collections::BTreeSet;
+use std::fs;
+
+use crate::config::Config;
+use crate::git_error::GitResult;
+use crate::repository::git_path;
+
+/// The directories a cone-mode sparse checkout keeps in the working tree.
+/// Besides everything under them, the files directly in the directories
+/// leading up to them are kept, down from the files at the top level.
+#[derive(Debug)]
+pub struct Cone {
+    dirs: BTreeSet<String>,
+    parents: BTreeSet<String>,
+}
+
+impl Cone {
+    pub fn new(dirs: &[String]) -> Cone {
+        let dirs: BTreeSet<String> = dirs
+            .iter()
+            .map(|dir| dir.trim_matches('/').to_owned())
+            .filter(|dir| !dir.is_empty())
+            .collect();
+        // A directory inside another one adds nothing.
+        let dirs: BTreeSet<String> = dirs
+            .iter()
+            .filter(|dir| {
+                !dirs
+                    .iter()
+                    .any(|other| dir.starts_with(&format!("{}/", other)))
+            })
+            .cloned()
+            .collect();
+        let mut parents = BTreeSet::new();
+        for dir in &dirs {
+            let mut parent = dir.as_str();
+            while let Some(slash) = parent.rfind('/') {
+                parent = &parent[..slash];
+                parents.insert(parent.to_owned());
+            }
+        }
+        Cone { dirs, parents }
+    }
+
+    /// Whether the file at `path` belongs in the working tree.
+    pub fn contains(&self, path: &str) -> bool {
+        let dir = match path.rfind('/') {
+            Some(slash) => &path[..slash],
+            None => return true,
+        };
+        self.parents.contains(dir)
+            || self
+                .dirs
+                .iter()
+                .any(|d| dir == d || dir.starts_with(&format!("{}/", d)))
+    }
+
+    /// The patterns git writes for the cone, one per line.
+    fn patterns(&self) -> String {
+        let mut patterns = "/*\n!/*/\n".to_owned();
+        let mut lines = BTreeSet::new();
+        for parent in &self.parents {
+            lines.insert(format!("/{}/\n!/{}/*/\n", parent, parent));
+        }
+        for dir in &self.dirs {
+            if !self.parents.contains(dir) {
+                lines.insert(format!("/{}/\n", dir));
+            }
+        }
+        for line in lines {
+            patterns.push_str(&line);
+        }
+        patterns
+    }
+}
+
+/// Reads the cone from `.git/info/sparse-checkout`, if sparse checkout is
+/// turned on in cone mode.
+pub fn read(root: &str) -> GitResult<Option<Cone>> {
+    let config = Config::load(root)?;
+    let enabled = |key| config.get("core", None, key) == Some("true");
+    if !enabled("sparsecheckout") || !enabled("sparsecheckoutcone") {
+        return Ok(None);
+    }
+    let content = match fs::read_to_string(git_path(root, "info/sparse-checkout")) {
+        Ok(content) => content,
+        Err(_) => return Ok(None),
+    };
+    // A directory followed by `!/<dir>/*/` only has its own files kept.
+    let mut dirs = Vec::new();
+    for line in content.lines().map(str::trim) {
+        match line.strip_prefix("!/").and_then(|l| l.strip_suffix("/*/")) {
+            Some(parent) => dirs.retain(|dir| dir != parent),
+            None if line.starts_with('/') && line.ends_with('/') && line != "/" => {
+                dirs.push(line.trim_matches('/').to_owned())
+            }
+            None => {}
+        }
+    }
+    Ok(Some(Cone::new(&dirs)))
+}
+
+/// Writes the cone's patterns and turns sparse checkout on in cone mode.
+pub fn write(root: &str, cone: &Cone) -> GitResult<()> {
+    let path = git_path(root, "info/sparse-checkout");
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, cone.patterns())?;
+    let mut config = Config::read(root)?;
+    config.set("core", None, "sparseCheckout", "true");
+    config.set("core", None, "sparseCheckoutCone", "true");
+    config.write(root)
+}
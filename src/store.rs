@@ -0,0 +1,117 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+use crate::git_error::{GitError, GitResult};
+use crate::object::{self, Object, Sha};
+
+/// Somewhere objects are kept by their SHA. A `Repository` keeps them in its
+/// `.git/objects`, and a `MemoryStore` in memory.
+pub trait ObjectStore {
+    fn read_object(&self, sha: &str) -> GitResult<Object>;
+
+    /// Stores an object in the loose format, header included, exactly as it
+    /// is, unless it's already there. Returns the SHA of those bytes.
+    fn write_raw_object(&self, data: Bytes) -> GitResult<Sha>;
+
+    /// Stores an object unless it's already there, returning its SHA.
+    fn write_object(&self, obj: &Object) -> GitResult<Sha> {
+        self.write_raw_object(obj.encode()?.1)
+    }
+}
+
+/// Objects held in memory, such as those parsed from a pack before they're
+/// written out. They're kept in the loose format, as they were received.
+#[derive(Default)]
+pub struct MemoryStore {
+    objects: RefCell<HashMap<String, Bytes>>,
+}
+
+impl MemoryStore {
+    /// The SHAs of all the objects held.
+    pub fn shas(&self) -> Vec<String> {
+        self.objects.borrow().keys().cloned().collect()
+    }
+
+    pub fn contains(&self, sha: &str) -> bool {
+        self.objects.borrow().contains_key(sha)
+    }
+
+    /// The object in the loose format, as it was stored.
+    pub fn read_raw_object(&self, sha: &str) -> GitResult<Bytes> {
+        self.objects
+            .borrow()
+            .get(sha)
+            .cloned()
+            .ok_or_else(|| GitError(format!("Object not found: {}", sha)))
+    }
+}
+
+impl From<HashMap<String, Bytes>> for MemoryStore {
+    fn from(objects: HashMap<String, Bytes>) -> MemoryStore {
+        MemoryStore {
+            objects: RefCell::new(objects),
+        }
+    }
+}
+
+impl ObjectStore for MemoryStore {
+    fn read_object(&self, sha: &str) -> GitResult<Object> {
+        Object::decode(self.read_raw_object(sha)?)
+    }
+
+    fn write_raw_object(&self, data: Bytes) -> GitResult<Sha> {
+        let sha = object::get_sha(&data);
+        self.objects
+            .borrow_mut()
+            .entry(object::to_hex(&sha))
+            .or_insert(data);
+        Ok(sha)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_store_round_trips_objects() {
+        let store = MemoryStore::default();
+        let blob = Object::Blob(Bytes::from_static(b"hello\n"));
+        let sha = object::to_hex(&store.write_object(&blob).unwrap());
+        // As `git hash-object` gives it.
+        assert_eq!(sha, "ce013625030ba8dba906f756967f9e9ca394464a");
+        assert!(store.contains(&sha));
+        assert_eq!(
+            store.read_raw_object(&sha).unwrap()[..],
+            b"blob 6\0hello\n"[..]
+        );
+        match store.read_object(&sha).unwrap() {
+            Object::Blob(content) => assert_eq!(content[..], b"hello\n"[..]),
+            _ => panic!("not a blob"),
+        }
+        // Writing it again changes nothing.
+        store.write_object(&blob).unwrap();
+        assert_eq!(store.shas(), [sha]);
+        assert!(store
+            .read_object("4b825dc642cb6eb9a060e54bf8d69288fbee4904")
+            .is_err());
+    }
+
+    #[test]
+    fn memory_store_keeps_raw_objects_as_written() {
+        // A commit with a header `Object::encode` would never write.
+        let content = "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+author  A <a@b> 0 +0000\ncommitter A <a@b> 0 +0000\n";
+        let raw = Bytes::from(format!("commit {}\0{}", content.len(), content));
+        let store = MemoryStore::default();
+        let sha = object::to_hex(&store.write_raw_object(raw.clone()).unwrap());
+        assert_eq!(sha, object::to_hex(&object::get_sha(&raw)));
+        assert_eq!(store.read_raw_object(&sha).unwrap(), raw);
+        assert!(matches!(
+            store.read_object(&sha).unwrap(),
+            Object::Commit { .. }
+        ));
+    }
+}
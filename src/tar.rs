@@ -0,0 +1,183 @@
+use std::io::Write;
+
+use crate::git_error::GitResult;
+
+const BLOCK: usize = 512;
+/// Archives are padded to a whole record, as tar itself writes them.
+const RECORD: u64 = 10240;
+/// Permission bits cleared from what's archived, like git's `tar.umask`.
+const UMASK: u32 = 0o002;
+
+/// What an archived path holds.
+pub enum Entry<'a> {
+    Directory,
+    File { executable: bool, content: &'a [u8] },
+    Symlink(&'a [u8]),
+}
+
+/// Writes a tar stream in the same ustar layout as `git archive`: owned by
+/// root, with every entry stamped with one time, and pax headers for what
+/// doesn't fit the fixed-size fields.
+pub struct TarWriter<W: Write> {
+    out: W,
+    mtime: i64,
+    written: u64,
+}
+
+impl<W: Write> TarWriter<W> {
+    pub fn new(out: W, mtime: i64) -> TarWriter<W> {
+        TarWriter {
+            out,
+            mtime,
+            written: 0,
+        }
+    }
+
+    /// Records a comment for the whole archive, such as the commit it was
+    /// made from, which `git get-tar-commit-id` reads back.
+    pub fn comment(&mut self, comment: &str) -> GitResult<()> {
+        let records = pax_record("comment", comment.as_bytes());
+        let mut header = self.header(0o666, records.len());
+        header[..17].copy_from_slice(b"pax_global_header");
+        header[156] = b'g';
+        self.write_header(header)?;
+        self.write_data(&records)
+    }
+
+    /// Adds an entry at `path`, a directory's with a trailing slash. `sha` is
+    /// the object it came from, used to name the pax headers of paths too
+    /// long for the header itself.
+    pub fn add(&mut self, path: &str, sha: &str, entry: Entry) -> GitResult<()> {
+        let (mode, typeflag, content) = match entry {
+            Entry::Directory => (0o777 & !UMASK, b'5', &[][..]),
+            Entry::File {
+                executable,
+                content,
+            } => {
+                let mode = if executable { 0o777 } else { 0o666 };
+                (mode & !UMASK, b'0', content)
+            }
+            Entry::Symlink(_) => (0o777, b'2', &[][..]),
+        };
+        let mut header = self.header(mode, content.len());
+        header[156] = typeflag;
+
+        let mut records = Vec::new();
+        let path = path.as_bytes();
+        if path.len() <= 100 {
+            header[..path.len()].copy_from_slice(path);
+        } else {
+            // Split at a slash into the prefix field if that's enough.
+            let split = path_prefix(path);
+            let rest = &path[(split + 1).min(path.len())..];
+            if split > 0 && rest.len() <= 100 {
+                header[345..345 + split].copy_from_slice(&path[..split]);
+                header[..rest.len()].copy_from_slice(rest);
+            } else {
+                let name = format!("{}.data", sha);
+                header[..name.len()].copy_from_slice(name.as_bytes());
+                records.extend(pax_record("path", path));
+            }
+        }
+        if let Entry::Symlink(target) = entry {
+            if target.len() <= 100 {
+                header[157..157 + target.len()].copy_from_slice(target);
+            } else {
+                let linkname = format!("see {}.paxheader", sha);
+                header[157..157 + linkname.len()].copy_from_slice(linkname.as_bytes());
+                records.extend(pax_record("linkpath", target));
+            }
+        }
+
+        if !records.is_empty() {
+            let mut pax = self.header(0o666, records.len());
+            let name = format!("{}.paxheader", sha);
+            pax[..name.len()].copy_from_slice(name.as_bytes());
+            pax[156] = b'x';
+            self.write_header(pax)?;
+            self.write_data(&records)?;
+        }
+        self.write_header(header)?;
+        self.write_data(content)
+    }
+
+    /// Ends the archive with at least two empty blocks, padded to a record.
+    pub fn finish(mut self) -> GitResult<()> {
+        let end = (self.written + 2 * BLOCK as u64).div_ceil(RECORD) * RECORD;
+        let padding = vec![0; (end - self.written) as usize];
+        self.out.write_all(&padding)?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    /// A header with everything but the name, type and checksum filled in.
+    fn header(&self, mode: u32, size: usize) -> [u8; BLOCK] {
+        let mut header = [0; BLOCK];
+        let mut field = |start: usize, value: &str| {
+            header[start..start + value.len()].copy_from_slice(value.as_bytes());
+        };
+        field(100, &format!("{:07o}", mode));
+        field(108, "0000000");
+        field(116, "0000000");
+        field(124, &format!("{:011o}", size));
+        field(136, &format!("{:011o}", self.mtime.max(0)));
+        field(257, "ustar");
+        field(263, "00");
+        field(265, "root");
+        field(297, "root");
+        field(329, "0000000");
+        field(337, "0000000");
+        header
+    }
+
+    /// Writes a header, with its checksum taken as if the checksum field
+    /// were spaces.
+    fn write_header(&mut self, mut header: [u8; BLOCK]) -> GitResult<()> {
+        header[148..156].copy_from_slice(b"        ");
+        let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+        header[148..156].copy_from_slice(format!("{:07o}\0", checksum).as_bytes());
+        self.out.write_all(&header)?;
+        self.written += BLOCK as u64;
+        Ok(())
+    }
+
+    /// Writes an entry's content, padded to a whole block.
+    fn write_data(&mut self, data: &[u8]) -> GitResult<()> {
+        self.out.write_all(data)?;
+        let padding = (BLOCK - data.len() % BLOCK) % BLOCK;
+        self.out.write_all(&vec![0; padding])?;
+        self.written += (data.len() + padding) as u64;
+        Ok(())
+    }
+}
+
+/// How much of a long path goes in the 155-byte prefix field: up to the last
+/// slash that fits, not counting a directory's trailing one.
+fn path_prefix(path: &[u8]) -> usize {
+    let mut i = path.len();
+    if i > 1 && path[i - 1] == b'/' {
+        i -= 1;
+    }
+    i = i.min(155);
+    loop {
+        i -= 1;
+        if i == 0 || path[i] == b'/' {
+            return i;
+        }
+    }
+}
+
+/// A pax header record, `<length> <keyword>=<value>\n`, where the length
+/// counts its own digits.
+fn pax_record(keyword: &str, value: &[u8]) -> Vec<u8> {
+    let mut len = keyword.len() + value.len() + 4;
+    let mut power = 1;
+    while len / 10 >= power {
+        len += 1;
+        power *= 10;
+    }
+    let mut record = format!("{} {}=", len, keyword).into_bytes();
+    record.extend_from_slice(value);
+    record.push(b'\n');
+    record
+}
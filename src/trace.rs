@@ -0,0 +1,69 @@
+use std::env;
+
+use bytes::Bytes;
+
+use crate::pkt::Packet;
+
+/// Whether tracing of `category` is on: `GIT_TRACE_<CATEGORY>`, or
+/// `GIT_TRACE` for every category, set to anything but empty, `0` or `false`.
+pub fn enabled(category: &str) -> bool {
+    let on =
+        |var: String| matches!(env::var(var), Ok(v) if !matches!(v.as_str(), "" | "0" | "false"));
+    on(format!("GIT_TRACE_{}", category)) || on("GIT_TRACE".to_owned())
+}
+
+/// Writes a line of debug output to stderr, if `category` is traced.
+pub fn print(category: &str, message: &str) {
+    if enabled(category) {
+        eprintln!("trace {}: {}", category.to_lowercase(), message);
+    }
+}
+
+/// Traces a pkt-line going out (`>`) or coming in (`<`). Binary data, such
+/// as a pack coming over a sideband, is only traced by its length.
+pub fn packet(direction: char, packet: &Packet) {
+    if !enabled("PACKET") {
+        return;
+    }
+    let shown = match packet {
+        Packet::Data(data) => match std::str::from_utf8(data) {
+            // Control characters, like the NUL before capabilities, are escaped.
+            Ok(line) => line
+                .trim_end_matches('\n')
+                .chars()
+                .flat_map(|c| match c.is_control() {
+                    true => c.escape_default().collect::<Vec<_>>(),
+                    false => vec![c],
+                })
+                .collect(),
+            Err(_) => format!("<{} bytes of binary data>", data.len()),
+        },
+        Packet::Flush => "0000".to_owned(),
+        Packet::Delimiter => "0001".to_owned(),
+        Packet::ResponseEnd => "0002".to_owned(),
+    };
+    print("PACKET", &format!("{} {}", direction, shown));
+}
+
+/// Traces each pkt-line of a request about to be sent.
+pub fn request(mut message: &[u8]) {
+    if !enabled("PACKET") {
+        return;
+    }
+    while message.len() >= 4 {
+        let len = std::str::from_utf8(&message[..4])
+            .ok()
+            .and_then(|len| usize::from_str_radix(len, 16).ok());
+        let (p, len) = match len {
+            Some(0) => (Packet::Flush, 4),
+            Some(1) => (Packet::Delimiter, 4),
+            Some(2) => (Packet::ResponseEnd, 4),
+            Some(len) if len > 4 && len <= message.len() => {
+                (Packet::Data(Bytes::copy_from_slice(&message[4..len])), len)
+            }
+            _ => return,
+        };
+        packet('>', &p);
+        message = &message[len..];
+    }
+}
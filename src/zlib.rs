@@ -1,21 +1,128 @@
-use std::io::{Read, Write};
+use std::io::{BufRead, Read, Write};
 
 use bytes::Bytes;
-use flate2::bufread::ZlibDecoder;
+use flate2::bufread::{DeflateDecoder, GzDecoder, ZlibDecoder};
 use flate2::write::ZlibEncoder;
-use flate2::Compression;
+use flate2::{Compression, Decompress, FlushDecompress, Status};
 
-use crate::git_error::GitResult;
+use crate::git_error::{GitError, GitResult};
 
 pub fn read(bytes: Bytes) -> GitResult<(usize, Bytes)> {
+    check_preset_dictionary(&bytes)?;
     let mut decoder = ZlibDecoder::new(bytes.as_ref());
     let mut content = Vec::new();
     decoder.read_to_end(&mut content)?;
     Ok((decoder.total_in() as usize, Bytes::from(content)))
 }
 
+/// Like `read`, but also takes gzip and raw DEFLATE streams, telling them
+/// apart by their headers. Meant for data from outside the object store;
+/// loose objects are always zlib-wrapped and should fail loudly otherwise.
+pub fn read_any(bytes: Bytes) -> GitResult<Bytes> {
+    let mut content = Vec::new();
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        GzDecoder::new(bytes.as_ref()).read_to_end(&mut content)?;
+        return Ok(Bytes::from(content));
+    }
+    // A raw stream can pass for a zlib header by chance, so fall back on error.
+    if has_zlib_header(&bytes) {
+        if let Ok((_, content)) = read(bytes.clone()) {
+            return Ok(content);
+        }
+    }
+    DeflateDecoder::new(bytes.as_ref()).read_to_end(&mut content)?;
+    Ok(Bytes::from(content))
+}
+
+/// A zlib header names DEFLATE as the method and, read as a big-endian
+/// number, is a multiple of 31.
+fn has_zlib_header(bytes: &[u8]) -> bool {
+    bytes.len() >= 2
+        && bytes[0] & 0x0f == 8
+        && u16::from_be_bytes([bytes[0], bytes[1]]).is_multiple_of(31)
+}
+
+/// Git never compresses with a preset dictionary, and without one such a
+/// stream can't be inflated, so a header asking for one (the FDICT bit) is
+/// turned away up front.
+pub fn check_preset_dictionary(header: &[u8]) -> GitResult<()> {
+    if has_zlib_header(header) && header[1] & 0x20 != 0 {
+        return Err(GitError(
+            "object uses unsupported zlib preset dictionary".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// How much room is made for inflated data at first. It grows as the data
+/// comes in, so a size claimed up front costs nothing until it's borne out.
+const INITIAL_CAPACITY: usize = 8192;
+
+/// Inflates a run of zlib streams, such as the objects in a pack, reusing
+/// the decompressor state between them.
+pub struct Inflater {
+    decompress: Decompress,
+}
+
+impl Inflater {
+    pub fn new() -> Inflater {
+        Inflater {
+            decompress: Decompress::new(true),
+        }
+    }
+
+    /// Inflates the stream at the front of `reader`, leaving the reader just
+    /// past its end, and fails if it holds more than `limit` bytes. Returns
+    /// the compressed length and the inflated data.
+    pub fn read_limited<R: BufRead>(
+        &mut self,
+        mut reader: R,
+        limit: usize,
+    ) -> GitResult<(usize, Bytes)> {
+        self.decompress.reset(true);
+        // One byte of slack so a stream that's too long can be detected.
+        let mut buffer = Vec::with_capacity((limit + 1).min(INITIAL_CAPACITY));
+        loop {
+            if buffer.len() == buffer.capacity() {
+                buffer.reserve_exact(buffer.capacity().min(limit + 1 - buffer.len()));
+            }
+            let (in_before, out_before) = (self.decompress.total_in(), self.decompress.total_out());
+            let input = reader.fill_buf()?;
+            if in_before == 0 {
+                check_preset_dictionary(input)?;
+            }
+            let flush = if input.is_empty() {
+                FlushDecompress::Finish
+            } else {
+                FlushDecompress::None
+            };
+            let status = self
+                .decompress
+                .decompress_vec(input, &mut buffer, flush)
+                .map_err(|e| GitError(format!("Corrupt zlib stream: {}", e)))?;
+            let consumed = (self.decompress.total_in() - in_before) as usize;
+            let produced = (self.decompress.total_out() - out_before) as usize;
+            reader.consume(consumed);
+
+            if buffer.len() > limit {
+                return Err(GitError(format!(
+                    "Inflated data exceeds the expected size of {} bytes",
+                    limit
+                )));
+            }
+            if status == Status::StreamEnd {
+                break;
+            }
+            if consumed == 0 && produced == 0 {
+                return Err(GitError("Truncated zlib stream".to_owned()));
+            }
+        }
+        Ok((self.decompress.total_in() as usize, Bytes::from(buffer)))
+    }
+}
+
 pub fn write(data: &[u8]) -> GitResult<Vec<u8>> {
     let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
     encoder.write_all(data)?;
     Ok(encoder.finish()?)
-}
\ No newline at end of file
+}